@@ -1,14 +1,21 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use once_cell::sync::Lazy;
-use wiz_rs::ConstantTokenBias;
+use wiz_rs::{ConstantTokenBias, SnapshotCompression};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Where to load the model path from
-    #[arg(long, short = 'm')]
+    #[arg(long, short = 'm', default_value_t = default_model_path())]
     pub model_path: String,
 
+    /// The `wiz-server` used by the shell integration, see `shell-init`.
+    #[arg(long, default_value = "http://localhost:8085")]
+    pub server: String,
+
     /// The prompt to feed the generator
     #[arg(long, short = 'p', default_value = None)]
     pub prompt: Option<String>,
@@ -21,6 +28,40 @@ pub struct Args {
     #[arg(long, short = 'R', default_value_t = false)]
     pub repl: bool,
 
+    /// Generate an answer instead of caching the prompt, then offer to run
+    /// the command in your shell and record its exit code and output in the
+    /// journal, see `journal`.
+    #[arg(long, short = 'x', default_value_t = false)]
+    pub execute: bool,
+
+    /// Name of the prompt template. Wraps the prompt in it, and each line in
+    /// REPL mode. REPL mode defaults to the template named like the model
+    /// file if there is one, else `chat`. `eval`, `fix` and `history adapt`
+    /// pick the template as `wiz-server` does: the one named like the model
+    /// file if there is one, else `wiz`.
+    #[arg(long, default_value = None)]
+    pub template: Option<String>,
+
+    /// Directory with additional prompt templates as `<name>.toml` files.
+    /// Defaults to `~/.wiz/templates`.
+    #[arg(long, default_value = None)]
+    pub template_dir: Option<String>,
+
+    /// Do not tell the model about this machine (OS, shell, installed tools,
+    /// working directory) when wrapping the prompt in a template.
+    #[arg(long, default_value_t = false)]
+    pub no_context: bool,
+
+    /// File with few-shot examples in the `examples.txt` format, used when
+    /// wrapping the prompt in a template. Defaults to `~/.wiz/examples.txt`
+    /// if it exists.
+    #[arg(long, default_value = None)]
+    pub examples: Option<String>,
+
+    /// How many few-shot examples to put into the prompt, 0 disables them.
+    #[arg(long, default_value_t = 3)]
+    pub num_examples: usize,
+
     /// Sets the number of threads to use
     #[arg(long, short = 't', default_value_t = 4)]
     pub num_threads: usize,
@@ -46,8 +87,8 @@ pub struct Args {
 
     /// The penalty for repeating tokens. Higher values make the generation less
     /// likely to get into a loop, but may harm results when repetitive outputs
-    /// are desired.
-    #[arg(long, default_value_t = 0.000000000001)]
+    /// are desired. 1.0 disables it.
+    #[arg(long, default_value_t = 1.0)]
     pub repeat_penalty: f32,
 
     /// Temperature
@@ -64,7 +105,8 @@ pub struct Args {
     pub top_p: f32,
 
     /// Stores a cached prompt at the given path. The same prompt can then be
-    /// loaded from disk using --restore-prompt
+    /// loaded from disk using --restore-prompt. Only the prompt is fed, no
+    /// answer is generated unless --execute is given.
     #[arg(long, default_value = "./convert_to_command.bin")]
     pub cache_prompt: Option<String>,
 
@@ -73,6 +115,11 @@ pub struct Args {
     #[arg(long, default_value = None)]
     pub restore_prompt: Option<String>,
 
+    /// How to compress the prompt cache written with --cache-prompt: none,
+    /// zstd or lz4
+    #[arg(long, default_value_t = SnapshotCompression::None)]
+    pub snapshot_compression: SnapshotCompression,
+
     /// Specifies the seed to use during sampling. Note that, depending on
     /// hardware, the same seed may lead to different results on two separate
     /// machines.
@@ -100,6 +147,193 @@ pub struct Args {
     pub ignore_eos: bool,
 }
 
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Runs the queries of an example file through the model like
+    /// `wiz-server` does, and scores the answers against the expected
+    /// commands.
+    Eval(EvalArgs),
+    /// Asks the model to correct a command that failed and explain what was
+    /// wrong with it. Without a command, fixes the last one recorded by the
+    /// prompt hook of `wiz-cli shell-init`, or else the last one in the
+    /// shell's history.
+    Fix(FixArgs),
+    /// Explains a shell command piece by piece: every command of it, and
+    /// every flag and redirect of those. Without a command, explains the last
+    /// one recorded by the prompt hook of `wiz-cli shell-init`, or else the
+    /// last one in the shell's history. Uses the `explain` prompt template
+    /// unless `--template` is given.
+    Explain {
+        /// The command to explain.
+        command: Option<String>,
+    },
+    /// Keeps a copy of the shell history in `~/.wiz/history.jsonl`, to find
+    /// commands in it by describing them and to explain or adapt them.
+    History {
+        #[command(subcommand)]
+        command: HistoryCommand,
+    },
+    /// Shows the journal of the queries `wiz-server` answered, their
+    /// candidates, the chosen commands and how running them went, kept in
+    /// `~/.wiz/journal.jsonl`. Commands run with `--execute` are recorded
+    /// there as well.
+    Journal {
+        #[command(subcommand)]
+        command: JournalCommand,
+    },
+    /// Prints the code integrating wiz into a shell. Add
+    /// `eval "$(wiz-cli shell-init bash)"` to `~/.bashrc`,
+    /// `eval "$(wiz-cli shell-init zsh)"` to `~/.zshrc` or
+    /// `wiz-cli shell-init fish | source` to `~/.config/fish/config.fish`.
+    ///
+    /// Alt-w then completes the command line at the cursor, or replaces a
+    /// line starting with `#` by a command doing what it describes. The exit
+    /// code of every command is recorded for `wiz-cli fix`, and `explain`
+    /// runs `wiz-cli explain`. The key binding uses a running `wiz-server`.
+    ShellInit {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Rewrites a command line with `wiz-server`, as the key binding of
+    /// `shell-init` does. Prints the new cursor position, then the new line.
+    Line(LineArgs),
+    /// Asks `wiz-server` and prints its answer. With `--execute`, offers to
+    /// run the command and reports how it went to the server's journal.
+    Ask {
+        /// What the command should do.
+        query: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum HistoryCommand {
+    /// Imports the history of a shell, or of every shell that has one.
+    /// Importing again adds the commands run since.
+    Import {
+        /// The shell whose history to import.
+        #[arg(long, value_enum)]
+        shell: Option<Shell>,
+
+        /// The history file, if it is not the shell's default one.
+        #[arg(long, requires = "shell")]
+        file: Option<String>,
+    },
+    /// Finds the commands matching a description, e.g.
+    /// `wiz-cli history search "ffmpeg command to crop a video"`.
+    Search {
+        query: String,
+
+        /// How many commands to show.
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Explains a command from the history, see `explain`.
+    Explain {
+        /// The id of the command as shown by `search`, or a description of
+        /// it, in which case the best match is explained.
+        entry: String,
+    },
+    /// Asks the model to change a command from the history, e.g.
+    /// `wiz-cli history adapt 42 "for every .mkv file in the directory"`.
+    Adapt {
+        /// The id of the command as shown by `search`, or a description of
+        /// it, in which case the best match is adapted.
+        entry: String,
+
+        /// What the command should do differently.
+        change: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum JournalCommand {
+    /// Lists the latest queries, newest first.
+    List {
+        /// How many queries to show.
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Finds the queries whose text or commands match a description.
+    Search {
+        query: String,
+
+        /// How many queries to show.
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Writes every query with its outcome as JSON lines, oldest first.
+    Export {
+        /// The file to write to instead of stdout.
+        #[arg(long, short)]
+        output: Option<String>,
+    },
+    /// Removes queries from the journal, all of them unless
+    /// `--older-than-days` is given.
+    Purge {
+        #[arg(long)]
+        older_than_days: Option<u64>,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LineArgs {
+    /// The command line.
+    #[arg(default_value = "")]
+    pub line: String,
+
+    /// Position of the cursor in the line, in characters. Defaults to the end.
+    #[arg(long)]
+    pub cursor: Option<usize>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct EvalArgs {
+    /// Example file in the `examples.txt` format. The examples of the other
+    /// sections serve as few-shot examples.
+    #[arg(default_value = "examples.txt")]
+    pub file: String,
+
+    /// The `# section` of the file to evaluate.
+    #[arg(long, default_value = "eval")]
+    pub section: String,
+
+    /// Also write the results as JSON to this file, `-` for stdout instead
+    /// of the table.
+    #[arg(long, default_value = None)]
+    pub json: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct FixArgs {
+    /// The command that failed.
+    pub command: Option<String>,
+
+    /// The exit code of the command.
+    #[arg(long, short = 'e', allow_negative_numbers = true)]
+    pub exit_code: Option<i32>,
+
+    /// What the command printed to stderr, `-` to read it from stdin. Read
+    /// from stdin as well if that is not a terminal, e.g.
+    /// `make 2>&1 | wiz-cli -m model.bin fix make`.
+    #[arg(long, default_value = None)]
+    pub stderr: Option<String>,
+}
+
+fn default_model_path() -> String {
+    dirs::home_dir()
+        .map(|home| home.join(".wiz").join("model.bin"))
+        .unwrap_or_else(|| "model.bin".into())
+        .to_string_lossy()
+        .into_owned()
+}
+
 fn parse_bias(s: &str) -> Result<ConstantTokenBias, String> {
     s.parse()
 }
@@ -0,0 +1,169 @@
+use std::{
+    error::Error,
+    io::{BufRead, BufReader},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use wiz_protocol::{PartKind, StreamEvent};
+use wiz_rs::{Outcome, ShellContext};
+
+use crate::CLI_ARGS;
+
+#[derive(Serialize)]
+struct QueryRequest<'a> {
+    query: &'a str,
+    context: ShellContext,
+}
+
+#[derive(Serialize)]
+struct CompleteRequest<'a> {
+    input: &'a str,
+    cursor: usize,
+    n: usize,
+}
+
+#[derive(Deserialize)]
+struct Completion {
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct CompleteResponse {
+    completions: Vec<Completion>,
+}
+
+/// A command suggested by the server.
+pub struct Suggestion {
+    /// The id of the answer, to report the outcome of the command with.
+    pub id: String,
+    pub command: String,
+}
+
+/// Talks to a running `wiz-server`, for the shell integration which should not
+/// load the model itself.
+pub struct Client {
+    server: String,
+    http: reqwest::blocking::Client,
+}
+
+impl Client {
+    /// `server` is the base URL, e.g. `http://localhost:8085`.
+    pub fn new(server: &str) -> Self {
+        Self {
+            server: server.trim_end_matches('/').to_string(),
+            // Answers stream for a while, so only connecting has a time limit
+            http: reqwest::blocking::Client::builder()
+                .connect_timeout(Duration::from_secs(2))
+                .timeout(None)
+                .build()
+                .expect("the client is valid"),
+        }
+    }
+
+    /// Posts `body` as JSON, turning error responses into errors.
+    fn post(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<reqwest::blocking::Response, Box<dyn Error>> {
+        let response = self
+            .http
+            .post(format!("{}{path}", self.server))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(body)?)
+            .send()?;
+        if !response.status().is_success() {
+            return Err(response.text()?.into());
+        }
+        Ok(response)
+    }
+
+    /// Completions of `input` at the byte offset `cursor`, most likely first.
+    pub fn complete(&self, input: &str, cursor: usize) -> Result<Vec<String>, Box<dyn Error>> {
+        let response = self.post(
+            "/api/complete",
+            &CompleteRequest {
+                input,
+                cursor,
+                n: 1,
+            },
+        )?;
+        let response: CompleteResponse = serde_json::from_str(&response.text()?)?;
+        Ok(response
+            .completions
+            .into_iter()
+            .map(|completion| completion.text)
+            .collect())
+    }
+
+    /// Asks the server to answer `query` in the environment of this process,
+    /// calling `on_event` for every event of the answer as it streams in. With
+    /// `--no-context`, nothing about this process is sent.
+    pub fn query(
+        &self,
+        query: &str,
+        mut on_event: impl FnMut(StreamEvent),
+    ) -> Result<(), Box<dyn Error>> {
+        let response = self.post(
+            "/api/v1/completions",
+            &QueryRequest {
+                query,
+                context: if CLI_ARGS.no_context {
+                    ShellContext::default()
+                } else {
+                    ShellContext::gather()
+                },
+            },
+        )?;
+        for line in BufReader::new(response).lines() {
+            // Events added in newer versions of the protocol are skipped
+            let line = line?;
+            let event = line
+                .strip_prefix("data:")
+                .and_then(|data| serde_json::from_str(data.trim_start()).ok());
+            if let Some(event) = event {
+                on_event(event);
+            }
+        }
+        Ok(())
+    }
+
+    /// The first command of the answer to `query`, from the last candidate.
+    pub fn command(&self, query: &str) -> Result<Option<Suggestion>, Box<dyn Error>> {
+        let mut id = String::new();
+        let mut commands: Vec<Option<String>> = vec![];
+        let mut error = None;
+        self.query(query, |event| match event {
+            StreamEvent::Start { id: start, .. } => id = start,
+            StreamEvent::Candidate { .. } => {
+                commands.clear();
+            }
+            StreamEvent::PartStart { kind, .. } => {
+                commands.push((kind == PartKind::Command).then(String::new));
+            }
+            StreamEvent::PartText { index, text } => {
+                if let Some(Some(command)) = commands.get_mut(index) {
+                    *command += &text;
+                }
+            }
+            StreamEvent::Error { message, .. } => error = Some(message),
+            _ => {}
+        })?;
+        if let Some(message) = error {
+            return Err(message.into());
+        }
+        Ok(commands
+            .into_iter()
+            .flatten()
+            .next()
+            .map(|command| Suggestion { id, command }))
+    }
+
+    /// Tells the server which command of an answer the user chose, and how
+    /// running it went if it was run.
+    pub fn outcome(&self, outcome: &Outcome) -> Result<(), Box<dyn Error>> {
+        self.post("/api/v1/outcome", outcome)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+use std::{cell::RefCell, collections::HashMap, convert::Infallible, rc::Rc};
+
+use rand::SeedableRng;
+use serde::Serialize;
+use wiz_rs::{
+    Example, ExampleIndex, InferenceError, InferenceParameters, InferenceSessionParameters,
+    InferenceStats, OutputToken, ResponseParser,
+};
+
+use crate::{cli_args::EvalArgs, few_shot, load_model, server_template, CLI_ARGS};
+
+/// Answers are cut off after this many tokens unless `--num-predict` is given.
+const DEFAULT_MAX_TOKENS: usize = 256;
+
+#[derive(Serialize)]
+struct ExampleResult {
+    query: String,
+    expected: Vec<String>,
+    predicted: String,
+    /// The command equals one of the expected ones.
+    exact_match: bool,
+    /// The command equals one of the expected ones after `normalise`.
+    normalised_match: bool,
+    /// The best token-level F1 score against the expected commands.
+    similarity: f64,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    prompt_ms: u64,
+    completion_ms: u64,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Summary {
+    examples: usize,
+    exact_match: f64,
+    normalised_match: f64,
+    similarity: f64,
+    mean_prompt_ms: f64,
+    mean_completion_ms: f64,
+    ms_per_token: f64,
+}
+
+#[derive(Serialize)]
+struct Report {
+    file: String,
+    section: String,
+    summary: Summary,
+    results: Vec<ExampleResult>,
+}
+
+/// Makes commands comparable that only differ in formatting: whitespace,
+/// quote style, a trailing `;` and `--flag=value` versus `--flag value`.
+fn normalise(command: &str) -> Vec<String> {
+    command
+        .trim()
+        .trim_end_matches(';')
+        .replace('\'', "\"")
+        .split_whitespace()
+        .flat_map(|word| match word.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => vec![flag, value],
+            _ => vec![word],
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// F1 score of the normalised words of two commands, counting repeated words.
+fn token_f1(predicted: &[String], expected: &[String]) -> f64 {
+    if predicted.is_empty() || expected.is_empty() {
+        return (predicted.is_empty() && expected.is_empty()) as u8 as f64;
+    }
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for word in expected {
+        *counts.entry(word).or_default() += 1;
+    }
+    let mut overlap = 0;
+    for word in predicted {
+        if let Some(count) = counts.get_mut(word.as_str()).filter(|count| **count > 0) {
+            *count -= 1;
+            overlap += 1;
+        }
+    }
+
+    let precision = overlap as f64 / predicted.len() as f64;
+    let recall = overlap as f64 / expected.len() as f64;
+    if overlap == 0 {
+        0.0
+    } else {
+        2.0 * precision * recall / (precision + recall)
+    }
+}
+
+fn score(
+    example: &Example,
+    predicted: String,
+    outcome: Result<InferenceStats, String>,
+) -> ExampleResult {
+    let normalised = normalise(&predicted);
+    let exact_match = example
+        .commands
+        .iter()
+        .any(|command| command.trim() == predicted);
+    let normalised_match = example
+        .commands
+        .iter()
+        .any(|command| normalise(command) == normalised);
+    let similarity = example
+        .commands
+        .iter()
+        .map(|command| token_f1(&normalised, &normalise(command)))
+        .fold(0.0, f64::max);
+
+    let (stats, error) = match outcome {
+        Ok(stats) => (stats, None),
+        Err(err) => (InferenceStats::default(), Some(err)),
+    };
+    ExampleResult {
+        query: example.query.clone(),
+        expected: example.commands.clone(),
+        predicted,
+        exact_match,
+        normalised_match,
+        similarity,
+        prompt_tokens: stats.prompt_tokens,
+        completion_tokens: stats.predict_tokens.saturating_sub(stats.prompt_tokens),
+        prompt_ms: stats.feed_prompt_duration.as_millis() as u64,
+        completion_ms: stats
+            .predict_duration
+            .saturating_sub(stats.feed_prompt_duration)
+            .as_millis() as u64,
+        error,
+    }
+}
+
+fn summarise(results: &[ExampleResult]) -> Summary {
+    let n = results.len().max(1) as f64;
+    let mean = |value: fn(&ExampleResult) -> f64| results.iter().map(value).sum::<f64>() / n;
+    let completion_tokens: usize = results.iter().map(|r| r.completion_tokens).sum();
+    let completion_ms: u64 = results.iter().map(|r| r.completion_ms).sum();
+
+    Summary {
+        examples: results.len(),
+        exact_match: mean(|r| r.exact_match as u8 as f64),
+        normalised_match: mean(|r| r.normalised_match as u8 as f64),
+        similarity: mean(|r| r.similarity),
+        mean_prompt_ms: mean(|r| r.prompt_ms as f64),
+        mean_completion_ms: mean(|r| r.completion_ms as f64),
+        ms_per_token: completion_ms as f64 / completion_tokens.max(1) as f64,
+    }
+}
+
+fn print_table(report: &Report) {
+    println!(
+        "{:>3}  {:>5}  {:>4}  {:>5}  {:>7}  query / predicted",
+        "#", "exact", "norm", "sim", "ms"
+    );
+    for (i, result) in report.results.iter().enumerate() {
+        let mark = |matched: bool| if matched { "yes" } else { "no" };
+        println!(
+            "{:>3}  {:>5}  {:>4}  {:>5.2}  {:>7}  {}",
+            i + 1,
+            mark(result.exact_match),
+            mark(result.normalised_match),
+            result.similarity,
+            result.prompt_ms + result.completion_ms,
+            result.query
+        );
+        match &result.error {
+            Some(err) => println!("{:>32}error: {err}", ""),
+            None => println!("{:>32}{}", "", result.predicted),
+        }
+    }
+
+    let summary = &report.summary;
+    println!();
+    println!("examples:         {}", summary.examples);
+    println!("exact match:      {:.1}%", summary.exact_match * 100.0);
+    println!("normalised match: {:.1}%", summary.normalised_match * 100.0);
+    println!("token similarity: {:.3}", summary.similarity);
+    println!("prompt latency:   {:.0}ms", summary.mean_prompt_ms);
+    println!("answer latency:   {:.0}ms", summary.mean_completion_ms);
+    println!("per token:        {:.1}ms", summary.ms_per_token);
+}
+
+pub fn run(args: &EvalArgs, session_params: &InferenceSessionParameters) {
+    let examples = match wiz_rs::load_examples(&args.file) {
+        Ok(examples) => examples,
+        Err(err) => {
+            log::error!("Could not read {}: {err}", args.file);
+            std::process::exit(1);
+        }
+    };
+    let (evaluated, others): (Vec<Example>, Vec<Example>) = examples
+        .into_iter()
+        .partition(|example| example.section.as_deref() == Some(args.section.as_str()));
+    if evaluated.is_empty() {
+        log::error!("{} has no examples under `# {}`", args.file, args.section);
+        std::process::exit(1);
+    }
+    let index =
+        (CLI_ARGS.num_examples > 0 && !others.is_empty()).then(|| ExampleIndex::new(others));
+
+    // The same template as `wiz-server`, but without the context of this
+    // machine so that results are comparable
+    let template = server_template();
+    let (model, vocab) = load_model();
+    let mut rng = match CLI_ARGS.seed {
+        Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+        None => rand::rngs::StdRng::from_entropy(),
+    };
+
+    let mut results = vec![];
+    for (i, example) in evaluated.iter().enumerate() {
+        log::info!("[{}/{}] {}", i + 1, evaluated.len(), example.query);
+
+        let parser = Rc::new(RefCell::new(ResponseParser::with_prefix(
+            &template.response_prefix,
+        )));
+        let params = InferenceParameters {
+            n_threads: CLI_ARGS.num_threads as i32,
+            ..wiz_rs::answer_parameters(parser.clone())
+        };
+        let examples = few_shot(index.as_ref(), &example.query);
+        // Tokenized in two parts like `wiz-server` does, which feeds the
+        // system prompt from a snapshot
+        let prompt_tokens =
+            vocab_tokens(&model, &vocab, &template.system, true).and_then(|system| {
+                let first_turn = template.first_turn(&example.query, None, &examples);
+                let turn = vocab_tokens(&model, &vocab, &first_turn, system.is_empty())?;
+                Ok([system, turn].concat())
+            });
+
+        let mut session = model.start_session(*session_params);
+        let outcome = prompt_tokens.and_then(|prompt_tokens| {
+            session.inference_with_tokens::<Infallible>(
+                &model,
+                &vocab,
+                &params,
+                &prompt_tokens,
+                Some(CLI_ARGS.num_predict.unwrap_or(DEFAULT_MAX_TOKENS)),
+                &mut rng,
+                |t| {
+                    if let OutputToken::Token(text, true, _) = t {
+                        parser.borrow_mut().push(&text);
+                    }
+                    Ok(())
+                },
+            )
+        });
+
+        let mut parser = parser.borrow_mut();
+        parser.finish();
+        let predicted = parser.command().unwrap_or_default().trim().to_string();
+        results.push(score(
+            example,
+            predicted,
+            outcome.map_err(|err| err.to_string()),
+        ));
+    }
+
+    let report = Report {
+        file: args.file.clone(),
+        section: args.section.clone(),
+        summary: summarise(&results),
+        results,
+    };
+
+    let json = serde_json::to_string_pretty(&report).unwrap();
+    match args.json.as_deref() {
+        Some("-") => println!("{json}"),
+        Some(path) => {
+            print_table(&report);
+            if let Err(err) = std::fs::write(path, json) {
+                log::error!("Could not write {path}: {err}");
+                std::process::exit(1);
+            }
+        }
+        None => print_table(&report),
+    }
+}
+
+/// Tokenizes `text`, nothing for an empty one.
+fn vocab_tokens(
+    model: &wiz_rs::Model,
+    vocab: &tokenizers::Tokenizer,
+    text: &str,
+    bos: bool,
+) -> Result<Vec<wiz_rs::TokenId>, InferenceError> {
+    if text.is_empty() {
+        return Ok(vec![]);
+    }
+    model.tokenize(vocab, text, bos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalisation() {
+        let cases = [
+            ("ls -la", "ls -la"),
+            ("  ls   -la ;", "ls -la"),
+            ("echo 'hi there'", "echo \"hi there\""),
+            ("git log --format=%h", "git log --format %h"),
+            (
+                "kubectl get pods --namespace=prod",
+                "kubectl get pods --namespace prod",
+            ),
+        ];
+        for (command, equivalent) in cases {
+            assert_eq!(normalise(command), normalise(equivalent), "{command}");
+        }
+        // Short options and assignments keep their `=`
+        assert_eq!(normalise("dd if=a of=b"), ["dd", "if=a", "of=b"]);
+        assert_ne!(normalise("ls -la"), normalise("ls -al"));
+    }
+
+    #[test]
+    fn token_f1_scores() {
+        let f1 =
+            |predicted: &str, expected: &str| token_f1(&normalise(predicted), &normalise(expected));
+        let cases = [
+            ("ls -la", "ls -la", 1.0),
+            ("ls", "cd", 0.0),
+            ("ls -la", "ls -la /tmp", 0.8),
+            ("rm rm rm", "rm -r", 0.4),
+            ("", "", 1.0),
+            ("", "ls", 0.0),
+        ];
+        for (predicted, expected, score) in cases {
+            let actual = f1(predicted, expected);
+            assert!((actual - score).abs() < 1e-9, "{predicted:?}: {actual}");
+        }
+    }
+
+    #[test]
+    fn scoring_against_any_expected_command() {
+        let example = Example {
+            query: "list pods in production".to_string(),
+            commands: vec![
+                "kubectl get pods -n production".to_string(),
+                "kubectl get pods --namespace=production".to_string(),
+            ],
+            section: Some("eval".to_string()),
+        };
+        let cases = [
+            ("kubectl get pods -n production", true, true, 1.0),
+            ("kubectl get pods --namespace production", false, true, 1.0),
+            ("kubectl  get pods -n production;", false, true, 1.0),
+            ("kubectl get pods", false, false, 0.75),
+            ("ls", false, false, 0.0),
+        ];
+        for (predicted, exact, normalised, similarity) in cases {
+            let result = score(
+                &example,
+                predicted.to_string(),
+                Ok(InferenceStats::default()),
+            );
+            assert_eq!(result.exact_match, exact, "{predicted}");
+            assert_eq!(result.normalised_match, normalised, "{predicted}");
+            assert!(
+                (result.similarity - similarity).abs() < 1e-9,
+                "{predicted}: {}",
+                result.similarity
+            );
+            assert_eq!(result.error, None);
+        }
+    }
+
+    #[test]
+    fn failed_inference_is_reported() {
+        let example = Example {
+            query: "q".to_string(),
+            commands: vec!["ls".to_string()],
+            section: None,
+        };
+        let result = score(&example, String::new(), Err("context full".to_string()));
+        assert_eq!(result.error.as_deref(), Some("context full"));
+        assert!(!result.exact_match);
+        assert_eq!(result.completion_tokens, 0);
+    }
+
+    #[test]
+    fn summary_means() {
+        let example = Example {
+            query: "q".to_string(),
+            commands: vec!["ls -la".to_string()],
+            section: None,
+        };
+        let results = [
+            score(
+                &example,
+                "ls -la".to_string(),
+                Ok(InferenceStats::default()),
+            ),
+            score(&example, "ls".to_string(), Ok(InferenceStats::default())),
+        ];
+        let summary = summarise(&results);
+        assert_eq!(summary.examples, 2);
+        assert_eq!(summary.exact_match, 0.5);
+        assert!((summary.similarity - (1.0 + 2.0 / 3.0) / 2.0).abs() < 1e-9);
+        assert_eq!(summarise(&[]).examples, 0);
+    }
+}
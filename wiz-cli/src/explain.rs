@@ -0,0 +1,154 @@
+use std::{cell::RefCell, error::Error, fmt::Display};
+
+use colored::Colorize;
+use rand::SeedableRng;
+use wiz_rs::{
+    CommandBreakdown, InferenceError, InferenceParameters, InferenceSessionParameters, OutputToken,
+    SegmentKind,
+};
+
+use crate::{
+    fix::{last_history_command, read_hook},
+    load_model, load_templates, CLI_ARGS,
+};
+
+/// Tokens allowed for the summary, and for every segment of the command,
+/// unless `--num-predict` is given.
+const SUMMARY_TOKENS: usize = 64;
+const SEGMENT_TOKENS: usize = 48;
+
+/// Returned from the inference callback once the model starts writing the
+/// next turn of the template.
+#[derive(Debug)]
+struct EndOfAnswer;
+
+impl Display for EndOfAnswer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "end of answer")
+    }
+}
+
+impl Error for EndOfAnswer {}
+
+/// Prints the summary, then every explained segment underlined in the
+/// command. Commands spanning several lines are listed segment by segment.
+fn print_breakdown(breakdown: &CommandBreakdown) {
+    if let Some(summary) = &breakdown.summary {
+        println!("{summary}\n");
+    }
+
+    let command = &breakdown.command;
+    let single_line = !command.trim_end().contains('\n');
+    if single_line {
+        println!("{}", command.trim_end().bold());
+    }
+    for segment in &breakdown.segments {
+        let Some(explanation) = &segment.explanation else {
+            continue;
+        };
+        if !single_line {
+            let indent = if segment.kind == SegmentKind::Command {
+                ""
+            } else {
+                "  "
+            };
+            println!("{indent}{} {explanation}", segment.text.bold());
+            continue;
+        }
+        let padding = command[..segment.start].chars().count();
+        let marks = "^".repeat(segment.text.chars().count().max(1));
+        let marks = match segment.kind {
+            SegmentKind::Command => marks.yellow(),
+            SegmentKind::Flag => marks.cyan(),
+            SegmentKind::Redirect => marks.magenta(),
+        };
+        println!("{}{marks} {explanation}", " ".repeat(padding));
+    }
+}
+
+pub fn run(
+    command: Option<&str>,
+    params: &InferenceParameters,
+    session_params: &InferenceSessionParameters,
+) {
+    let command = match command {
+        Some(command) => command.to_string(),
+        None => match read_hook()
+            .map(|(command, _)| command)
+            .or_else(last_history_command)
+        {
+            Some(command) => command,
+            None => {
+                log::error!(
+                    "No command to explain. Pass it as an argument, or set up the prompt hook \
+                     with `wiz-cli shell-init`"
+                );
+                std::process::exit(1);
+            }
+        },
+    };
+    let mut breakdown = match CommandBreakdown::new(&command) {
+        Ok(breakdown) => breakdown,
+        Err(err) => {
+            log::error!("Could not parse the command: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let templates = load_templates();
+    let name = CLI_ARGS.template.as_deref().unwrap_or("explain");
+    let template = match templates.get(name) {
+        Ok(template) => template,
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(1);
+        }
+    };
+    let prompt = template.render(&[], &breakdown.query(), None, &[]);
+    let turn_start = template.turn_start();
+    let max_tokens = CLI_ARGS
+        .num_predict
+        .unwrap_or(SUMMARY_TOKENS + SEGMENT_TOKENS * breakdown.segments.len());
+
+    let (model, vocab) = load_model();
+    let mut rng = match CLI_ARGS.seed {
+        Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+        None => rand::rngs::StdRng::from_entropy(),
+    };
+    println!("{} {}", "Explaining".dimmed(), command.trim().bold());
+
+    let answer = RefCell::new(String::new());
+    let mut session = model.start_session(*session_params);
+    let res = session.inference_with_prompt(
+        &model,
+        &vocab,
+        params,
+        &prompt,
+        Some(max_tokens),
+        &mut rng,
+        |t| {
+            if let OutputToken::Token(text, true, _) = t {
+                let mut answer = answer.borrow_mut();
+                *answer += &text;
+                if let Some(end) = turn_start.and_then(|start| answer.find(start)) {
+                    answer.truncate(end);
+                    return Err(EndOfAnswer);
+                }
+            }
+            Ok(())
+        },
+    );
+
+    match res {
+        Ok(_) | Err(InferenceError::UserCallback(_)) => {}
+        Err(InferenceError::ContextFull) => log::warn!("Context window full, stopping inference."),
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(1);
+        }
+    }
+
+    breakdown.apply_answer(&answer.borrow());
+    println!();
+    print_breakdown(&breakdown);
+}
@@ -0,0 +1,98 @@
+use std::{
+    io::{IsTerminal, Read},
+    path::PathBuf,
+};
+
+use colored::Colorize;
+use wiz_rs::{FailedCommand, HistoryFormat, InferenceSessionParameters, ResponseParser};
+
+use crate::{cli_args::FixArgs, generate_answer, offer_to_run, print_risks, CLI_ARGS};
+
+/// Written by the prompt hook of the shell after every command: the exit code
+/// on the first line, the command on the following ones.
+fn last_command_path() -> Option<PathBuf> {
+    Some(dirs::home_dir()?.join(".wiz").join("last_command"))
+}
+
+/// The command and exit code recorded by the prompt hook, if any.
+pub fn read_hook() -> Option<(String, Option<i32>)> {
+    let contents = std::fs::read_to_string(last_command_path()?).ok()?;
+    let (code, command) = contents.split_once('\n')?;
+    let command = command.trim_end();
+    (!command.is_empty()).then(|| (command.to_string(), code.trim().parse().ok()))
+}
+
+/// The last command in the history of the user's shell, leaving out calls of
+/// wiz itself.
+pub fn last_history_command() -> Option<String> {
+    let format = HistoryFormat::for_shell(&std::env::var("SHELL").ok()?)?;
+    let entries = format.read(format.default_file(&dirs::home_dir()?)).ok()?;
+    entries
+        .into_iter()
+        .rev()
+        .map(|entry| entry.command)
+        .find(|command| {
+            let program = command.split_whitespace().next().unwrap_or_default();
+            !program.ends_with("wiz") && !program.ends_with("wiz-cli")
+        })
+}
+
+/// Puts together the failed command from the arguments, the prompt hook and
+/// the shell history, in that order.
+fn failed_command(args: &FixArgs) -> FailedCommand {
+    let (command, exit_code) = match &args.command {
+        Some(command) => (command.clone(), args.exit_code),
+        None => match read_hook().or_else(|| Some((last_history_command()?, None))) {
+            Some((command, exit_code)) => (command, args.exit_code.or(exit_code)),
+            None => {
+                log::error!(
+                    "No command to fix. Pass it as an argument, or set up the prompt hook \
+                     with `wiz-cli shell-init`"
+                );
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let stderr = match args.stderr.as_deref() {
+        Some("-") => read_stdin(),
+        Some(stderr) => stderr.to_string(),
+        None if !std::io::stdin().is_terminal() => read_stdin(),
+        None => String::new(),
+    };
+
+    FailedCommand {
+        command,
+        exit_code,
+        stderr,
+    }
+}
+
+fn read_stdin() -> String {
+    let mut input = vec![];
+    if let Err(err) = std::io::stdin().read_to_end(&mut input) {
+        log::error!("Could not read stderr from stdin: {err}");
+        std::process::exit(1);
+    }
+    String::from_utf8_lossy(&input).into_owned()
+}
+
+pub fn run(args: &FixArgs, session_params: &InferenceSessionParameters) {
+    let failed = failed_command(args);
+    println!("{} {}", "Fixing".dimmed(), failed.command.bold());
+
+    let query = failed.correction_query();
+    let (response_prefix, answer) = generate_answer(&query, &failed.command, session_params);
+
+    let mut parser = ResponseParser::with_prefix(&response_prefix);
+    parser.push(&answer);
+    parser.finish();
+    if parser.command().is_none() {
+        log::warn!("The answer contains no corrected command");
+        return;
+    }
+    print_risks(&response_prefix, &answer);
+    if CLI_ARGS.execute {
+        offer_to_run(&query, &response_prefix, &answer);
+    }
+}
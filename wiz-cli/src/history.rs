@@ -0,0 +1,187 @@
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use colored::Colorize;
+use wiz_rs::{
+    adaptation_query, HistoryFormat, HistoryStore, InferenceParameters, InferenceSessionParameters,
+    StoredCommand,
+};
+
+use crate::{
+    cli_args::{HistoryCommand, Shell},
+    explain, generate_answer, offer_to_run, print_risks, CLI_ARGS,
+};
+
+fn store_path() -> PathBuf {
+    dirs::home_dir()
+        .unwrap_or_default()
+        .join(".wiz")
+        .join("history.jsonl")
+}
+
+fn open_store() -> HistoryStore {
+    match HistoryStore::open(store_path()) {
+        Ok(store) => store,
+        Err(err) => {
+            log::error!("Could not read the history store: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn shell_name(shell: Shell) -> &'static str {
+    match shell {
+        Shell::Bash => "bash",
+        Shell::Zsh => "zsh",
+        Shell::Fish => "fish",
+    }
+}
+
+fn history_format(shell: Shell) -> HistoryFormat {
+    match shell {
+        Shell::Bash => HistoryFormat::Bash,
+        Shell::Zsh => HistoryFormat::Zsh,
+        Shell::Fish => HistoryFormat::Fish,
+    }
+}
+
+/// Imports the history file of `shell`, or of every shell if not given. Only
+/// an explicitly named file has to exist.
+fn import(shell: Option<Shell>, file: Option<&str>) {
+    let home = dirs::home_dir().unwrap_or_default();
+    let shells = match shell {
+        Some(shell) => vec![shell],
+        None => vec![Shell::Bash, Shell::Zsh, Shell::Fish],
+    };
+
+    let mut store = open_store();
+    let mut imported: Vec<PathBuf> = vec![];
+    for shell in shells {
+        let path = match file {
+            Some(file) => PathBuf::from(file),
+            None => history_format(shell).default_file(&home),
+        };
+        // `HISTFILE` is the default file of both bash and zsh
+        if imported.contains(&path) || (file.is_none() && !path.exists()) {
+            continue;
+        }
+        let entries = match history_format(shell).read(&path) {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::error!("Could not read {}: {err}", path.display());
+                std::process::exit(1);
+            }
+        };
+        let total = entries.len();
+        let added = store.import(shell_name(shell), entries);
+        println!(
+            "{} {added} new of {total} commands from {}",
+            "Imported".green(),
+            path.display()
+        );
+        imported.push(path);
+    }
+
+    if imported.is_empty() {
+        log::warn!("Found no shell history to import, pass it with `--shell` and `--file`");
+        return;
+    }
+    if let Err(err) = store.save() {
+        log::error!("Could not write {}: {err}", store_path().display());
+        std::process::exit(1);
+    }
+}
+
+/// How long ago `timestamp` was, e.g. `3d ago`.
+pub fn ago(timestamp: Option<u64>) -> String {
+    let Some(timestamp) = timestamp else {
+        return String::new();
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    match now.saturating_sub(timestamp) {
+        seconds if seconds < 60 => "just now".to_string(),
+        seconds if seconds < 60 * 60 => format!("{}m ago", seconds / 60),
+        seconds if seconds < 24 * 60 * 60 => format!("{}h ago", seconds / (60 * 60)),
+        seconds if seconds < 365 * 24 * 60 * 60 => format!("{}d ago", seconds / (24 * 60 * 60)),
+        seconds => format!("{}y ago", seconds / (365 * 24 * 60 * 60)),
+    }
+}
+
+/// Prints a command with its id, the first line only if it has several.
+fn print_entry(id: usize, command: &StoredCommand) {
+    let mut lines = command.command.lines();
+    let first = lines.next().unwrap_or_default();
+    let more = if lines.next().is_some() { " …" } else { "" };
+    println!(
+        "{:>6}  {:>8}  {}{}",
+        id.to_string().dimmed(),
+        ago(command.last_run).dimmed(),
+        first.bold(),
+        more.dimmed()
+    );
+}
+
+fn search(query: &str, limit: usize) {
+    let store = open_store();
+    if store.commands().is_empty() {
+        log::warn!("The history is empty, import it with `wiz-cli history import`");
+        return;
+    }
+    let matches = store.search(query, limit);
+    if matches.is_empty() {
+        println!("{}", "No command matches".dimmed());
+    }
+    for (position, _) in matches {
+        print_entry(position + 1, &store.commands()[position]);
+    }
+}
+
+/// The command `entry` refers to: its id, or else the best match of it as a
+/// description.
+fn find(entry: &str) -> String {
+    let store = open_store();
+    let position = match entry.trim().parse::<usize>() {
+        Ok(id) => id
+            .checked_sub(1)
+            .filter(|&position| position < store.commands().len()),
+        Err(_) => store
+            .search(entry, 1)
+            .first()
+            .map(|&(position, _)| position),
+    };
+    match position {
+        Some(position) => store.commands()[position].command.clone(),
+        None => {
+            log::error!("No command in {} matches `{entry}`", store_path().display());
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn run(
+    command: &HistoryCommand,
+    params: &InferenceParameters,
+    session_params: &InferenceSessionParameters,
+) {
+    match command {
+        HistoryCommand::Import { shell, file } => import(*shell, file.as_deref()),
+        HistoryCommand::Search { query, limit } => search(query, *limit),
+        HistoryCommand::Explain { entry } => {
+            explain::run(Some(&find(entry)), params, session_params)
+        }
+        HistoryCommand::Adapt { entry, change } => {
+            let command = find(entry);
+            println!("{} {}", "Adapting".dimmed(), command.bold());
+            let query = adaptation_query(&command, change);
+            let (response_prefix, answer) = generate_answer(&query, change, session_params);
+            print_risks(&response_prefix, &answer);
+            if CLI_ARGS.execute {
+                offer_to_run(&query, &response_prefix, &answer);
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use colored::Colorize;
+use rand::Rng;
+use wiz_rs::{Candidate, Journal, JournalEntry, JournalEvent, QueryRecord};
+
+#[cfg(unix)]
+use crate::run::RunRecord;
+use crate::{cli_args::JournalCommand, history::ago};
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+fn open_journal() -> Journal {
+    Journal::in_dir(dirs::home_dir().unwrap_or_default().join(".wiz"))
+}
+
+/// Adds an answer of the local model with `--execute` to the journal that
+/// `wiz-server` keeps as well, with the outcome of running `commands[0]` if
+/// it was run.
+#[cfg(unix)]
+pub fn record_local(query: &str, answer: &str, commands: Vec<String>, run: Option<&RunRecord>) {
+    let id = format!("cli-{:016x}", rand::thread_rng().gen::<u64>());
+    let mut events = vec![JournalEvent::Query(QueryRecord {
+        id: id.clone(),
+        time: now(),
+        kind: "cli".to_string(),
+        query: query.to_string(),
+        candidates: vec![Candidate {
+            answer: answer.to_string(),
+            commands,
+            ..Default::default()
+        }],
+        ..Default::default()
+    })];
+    events.extend(run.map(|run| JournalEvent::Outcome(run.outcome(&id))));
+
+    let journal = open_journal();
+    for event in &events {
+        if let Err(err) = journal.append(event) {
+            log::warn!("Could not write to {}: {err}", journal.path().display());
+            return;
+        }
+    }
+}
+
+fn read_entries(journal: &Journal) -> Vec<JournalEntry> {
+    match journal.entries() {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::error!("Could not read {}: {err}", journal.path().display());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints a query with the command the user chose, or else the first one
+/// suggested, and how it went.
+fn print_entry(entry: &JournalEntry) {
+    let record = &entry.record;
+    println!(
+        "{:>8}  {:<5}  {}",
+        ago(Some(record.time)).dimmed(),
+        record.kind.dimmed(),
+        record.query.lines().next().unwrap_or_default().bold()
+    );
+
+    let suggested = record
+        .candidates
+        .last()
+        .and_then(|candidate| candidate.commands.first());
+    let command = match &entry.outcome {
+        Some(outcome) => Some(&outcome.command),
+        None => suggested,
+    };
+    let mut details = vec![format!("{}ms", record.ms)];
+    if record.candidates.len() > 1 {
+        details.push(format!("{} candidates", record.candidates.len()));
+    }
+    if let Some(outcome) = &entry.outcome {
+        match (outcome.exit_code, outcome.signal) {
+            (Some(0), _) => details.push("ran".to_string()),
+            (Some(code), _) => details.push(format!("exit code {code}")),
+            (None, Some(signal)) => details.push(format!("killed by signal {signal}")),
+            (None, None) => details.push("chosen".to_string()),
+        }
+    }
+    if let Some(command) = command {
+        let mut lines = command.trim().lines();
+        let first = lines.next().unwrap_or_default();
+        let more = if lines.next().is_some() { " …" } else { "" };
+        println!("{:>17}{}{}", "", first, more.dimmed());
+    }
+    if let Some(error) = &record.error {
+        println!("{:>17}{}", "", error.red());
+    }
+    println!("{:>17}{}", "", details.join(", ").dimmed());
+}
+
+fn list(limit: usize) {
+    let entries = read_entries(&open_journal());
+    if entries.is_empty() {
+        println!("{}", "The journal is empty".dimmed());
+    }
+    for entry in entries.iter().rev().take(limit) {
+        print_entry(entry);
+    }
+}
+
+fn search(query: &str, limit: usize) {
+    let journal = open_journal();
+    let matches = match journal.search(query, limit) {
+        Ok(matches) => matches,
+        Err(err) => {
+            log::error!("Could not read {}: {err}", journal.path().display());
+            std::process::exit(1);
+        }
+    };
+    if matches.is_empty() {
+        println!("{}", "No query matches".dimmed());
+    }
+    for entry in &matches {
+        print_entry(entry);
+    }
+}
+
+fn export(output: Option<&str>) {
+    let entries = read_entries(&open_journal());
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => match std::fs::File::create(path) {
+            Ok(file) => Box::new(io::BufWriter::new(file)),
+            Err(err) => {
+                log::error!("Could not create {path}: {err}");
+                std::process::exit(1);
+            }
+        },
+        None => Box::new(io::stdout().lock()),
+    };
+    let written = entries.iter().try_for_each(|entry| {
+        serde_json::to_writer(&mut writer, entry)?;
+        writer.write_all(b"\n")
+    });
+    if let Err(err) = written.and_then(|_| writer.flush()) {
+        log::error!("Could not export the journal: {err}");
+        std::process::exit(1);
+    }
+}
+
+fn purge(older_than_days: Option<u64>) {
+    let journal = open_journal();
+    let before =
+        older_than_days.map(|days| now().saturating_sub(days.saturating_mul(24 * 60 * 60)));
+    match journal.purge(before) {
+        Ok(removed) => println!("{} {removed} queries", "Removed".green()),
+        Err(err) => {
+            log::error!("Could not purge {}: {err}", journal.path().display());
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn run(command: &JournalCommand) {
+    match command {
+        JournalCommand::List { limit } => list(*limit),
+        JournalCommand::Search { query, limit } => search(query, *limit),
+        JournalCommand::Export { output } => export(output.as_deref()),
+        JournalCommand::Purge { older_than_days } => purge(*older_than_days),
+    }
+}
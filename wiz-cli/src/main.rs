@@ -2,33 +2,52 @@ use std::cell::RefCell;
 use std::rc::Rc;
 use std::{convert::Infallible, io::Write};
 
-use cli_args::CLI_ARGS;
+use cli_args::{Command, CLI_ARGS};
 use colored::Colorize;
 use rand::thread_rng;
 use rand::SeedableRng;
 use rustyline::error::ReadlineError;
 use tokenizers::Tokenizer;
 use wiz_rs::{
-    ConstantTokenBias, InferenceError, InferenceParameters, InferenceSessionParameters,
-    InferenceSnapshot, ModelKVMemoryType, TokenBias, EOD_TOKEN_ID,
+    ConstantTokenBias, ExampleIndex, InferenceError, InferenceParameters,
+    InferenceSessionParameters, InferenceSnapshot, ModelKVMemoryType, PartKind, PromptTemplate,
+    PromptTemplates, ResponseParser, RiskLevel, ShellContext, TokenBias, EOD_TOKEN_ID,
 };
 
 mod cli_args;
+mod client;
+mod eval;
+mod explain;
+mod fix;
+mod history;
+mod journal;
+#[cfg(unix)]
+mod run;
+mod shell_init;
 
 fn repl_mode(
     model: &wiz_rs::Model,
     vocab: &Tokenizer,
     params: &InferenceParameters,
     session_params: &InferenceSessionParameters,
+    template: &PromptTemplate,
+    context: Option<&str>,
+    examples: Option<&ExampleIndex>,
 ) {
     let mut rl = rustyline::DefaultEditor::new().unwrap();
     let mut session = model.start_session(*session_params);
+    let mut first_turn = true;
 
     loop {
         let readline = rl.readline(">> ");
         match readline {
             Ok(line) => {
-                let prompt = format!("<|USER|>{line}<|ASSISTANT|>");
+                let prompt = if first_turn {
+                    first_turn = false;
+                    template.render(&[], &line, context, &few_shot(examples, &line))
+                } else {
+                    template.follow_up(&line)
+                };
                 let mut rng = thread_rng();
 
                 let mut sp = spinners::Spinner::new(spinners::Spinners::Dots2, "".to_string());
@@ -66,9 +85,11 @@ fn repl_mode(
                     log::error!("Reply exceeds context window l gth");
                 }
 
-                let text = text.into_inner().trim().to_string();
-
-                println!("");
+                println!();
+                print_risks(&prompt, &text.borrow());
+                if CLI_ARGS.execute {
+                    offer_to_run(&line, &prompt, &text.borrow());
+                }
             }
             Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => {
                 break;
@@ -116,6 +137,280 @@ impl TokenBias for CustomTokenBias {
     }
 }
 
+/// Prints how risky the commands in `answer` are, if they are at all. `prompt`
+/// is the text the answer continues.
+fn print_risks(prompt: &str, answer: &str) {
+    let mut parser = ResponseParser::with_prefix(prompt);
+    parser.push(answer);
+    parser.finish();
+    let cwd = std::env::current_dir().ok();
+
+    let commands = parser
+        .parts()
+        .iter()
+        .filter(|part| part.kind == PartKind::Command);
+    for command in commands {
+        let risk = wiz_rs::assess_risk(&command.text, cwd.as_deref());
+        let label = format!("{} risk", risk.level());
+        match risk.level() {
+            RiskLevel::None => continue,
+            RiskLevel::Low => println!("{}", label.bold()),
+            RiskLevel::Medium => println!("{}", label.yellow().bold()),
+            RiskLevel::High | RiskLevel::Critical => println!("{}", label.red().bold()),
+        }
+        for reason in &risk.reasons {
+            println!(
+                "  {} {}: {}",
+                "-".dimmed(),
+                &command.text[reason.span.start..reason.span.end],
+                reason.message
+            );
+        }
+    }
+}
+
+/// Answers are cut off after this many tokens unless `--num-predict` is given.
+const DEFAULT_MAX_TOKENS: usize = 256;
+
+/// Answers `query` with the `wiz` template, the context of this machine and
+/// the few-shot examples most similar to `similar_to`, printing the answer as
+/// it is generated. Returns the response prefix of the template and the
+/// answer.
+fn generate_answer(
+    query: &str,
+    similar_to: &str,
+    session_params: &InferenceSessionParameters,
+) -> (String, String) {
+    let template = server_template();
+    let context = if CLI_ARGS.no_context {
+        None
+    } else {
+        ShellContext::gather().render()
+    };
+    let examples = load_example_index();
+    let prompt = template.render(
+        &[],
+        query,
+        context.as_deref(),
+        &few_shot(examples.as_ref(), similar_to),
+    );
+
+    let (model, vocab) = load_model();
+    let mut rng = match CLI_ARGS.seed {
+        Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+        None => rand::rngs::StdRng::from_entropy(),
+    };
+
+    let parser = Rc::new(RefCell::new(ResponseParser::with_prefix(
+        &template.response_prefix,
+    )));
+    let params = InferenceParameters {
+        n_threads: CLI_ARGS.num_threads as i32,
+        ..wiz_rs::answer_parameters(parser.clone())
+    };
+    let answer = RefCell::new(String::new());
+    let mut session = model.start_session(*session_params);
+    let res = session.inference_with_prompt::<Infallible>(
+        &model,
+        &vocab,
+        &params,
+        &prompt,
+        Some(CLI_ARGS.num_predict.unwrap_or(DEFAULT_MAX_TOKENS)),
+        &mut rng,
+        |t| {
+            if let wiz_rs::OutputToken::Token(text, true, _) = t {
+                print!("{}", text.yellow().bold());
+                std::io::stdout().flush().unwrap();
+                parser.borrow_mut().push(&text);
+                *answer.borrow_mut() += &text;
+            }
+            Ok(())
+        },
+    );
+    println!();
+
+    match res {
+        Ok(_) => {}
+        Err(InferenceError::ContextFull) => log::warn!("Context window full, stopping inference."),
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(1);
+        }
+    }
+    (template.response_prefix, answer.into_inner())
+}
+
+/// Offers to run the first command in `answer`, see `run::execute`.
+#[cfg(unix)]
+fn offer_to_run(query: &str, prompt: &str, answer: &str) {
+    let mut parser = ResponseParser::with_prefix(prompt);
+    parser.push(answer);
+    parser.finish();
+    let Some(command) = parser.command() else {
+        return;
+    };
+    let cwd = std::env::current_dir().ok();
+    let risk = wiz_rs::assess_risk(command, cwd.as_deref()).level();
+    let run = run::execute(command, risk);
+
+    let commands = parser
+        .parts()
+        .iter()
+        .filter(|part| part.kind == PartKind::Command)
+        .map(|part| part.text.clone())
+        .collect();
+    journal::record_local(query, answer, commands, run.as_ref());
+}
+
+/// `--execute` is rejected up front on other systems, see `main`.
+#[cfg(not(unix))]
+fn offer_to_run(_query: &str, _prompt: &str, _answer: &str) {}
+
+/// Loads the built-in templates and the ones in `--template-dir`.
+fn load_templates() -> PromptTemplates {
+    let args = &*CLI_ARGS;
+    let mut templates = PromptTemplates::default();
+    let dir = match &args.template_dir {
+        Some(dir) => Some(dir.into()),
+        None => dirs::home_dir().map(|home| home.join(".wiz").join("templates")),
+    };
+    if let Some(dir) = dir {
+        if let Err(err) = templates.load_dir(dir) {
+            log::error!("{err}");
+            std::process::exit(1);
+        }
+    }
+    templates
+}
+
+/// Looks up the template to use: `--template`, or else the one named like
+/// the model file, or else `default` if given.
+fn load_template(default: Option<&str>) -> Option<PromptTemplate> {
+    let args = &*CLI_ARGS;
+    let templates = load_templates();
+    let name = match (&args.template, default) {
+        (Some(name), _) => name.as_str(),
+        (None, Some(default)) => {
+            templates.name_for_model(std::path::Path::new(&args.model_path), default)
+        }
+        (None, None) => return None,
+    };
+    match templates.get(name) {
+        Ok(template) => Some(template.clone()),
+        Err(err) => {
+            log::error!(
+                "{err}. Available templates: {}",
+                templates.names().join(", ")
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The template `wiz-server` answers queries with, picked the same way:
+/// `--template`, or else the one named like the model file, or else `wiz`.
+fn server_template() -> PromptTemplate {
+    load_template(Some("wiz")).expect("there is a default template")
+}
+
+/// Indexes the few-shot examples, leaving out the ones kept for evaluation.
+fn load_example_index() -> Option<ExampleIndex> {
+    let args = &*CLI_ARGS;
+    if args.num_examples == 0 {
+        return None;
+    }
+    let path = match &args.examples {
+        Some(path) => path.into(),
+        None => dirs::home_dir()?.join(".wiz").join("examples.txt"),
+    };
+    let examples = match wiz_rs::load_examples(&path) {
+        Ok(examples) => examples,
+        Err(err) if args.examples.is_some() => {
+            log::error!("Could not read examples from {}: {err}", path.display());
+            std::process::exit(1);
+        }
+        Err(_) => return None,
+    };
+
+    let index = ExampleIndex::new(
+        examples
+            .into_iter()
+            .filter(|example| example.section.as_deref() != Some("eval"))
+            .collect(),
+    );
+    (!index.is_empty()).then_some(index)
+}
+
+/// The `(query, command)` pairs of the examples most similar to `query`.
+fn few_shot<'a>(index: Option<&'a ExampleIndex>, query: &str) -> Vec<(&'a str, &'a str)> {
+    index.map_or(vec![], |index| {
+        index
+            .top_k(query, CLI_ARGS.num_examples)
+            .into_iter()
+            .map(|(example, _)| (example.query.as_str(), example.commands[0].as_str()))
+            .collect()
+    })
+}
+
+fn load_model() -> (wiz_rs::Model, Tokenizer) {
+    let args = &*CLI_ARGS;
+    let loaded = wiz_rs::Model::load(&args.model_path, args.num_ctx_tokens as i32, |progress| {
+        use wiz_rs::LoadProgress;
+        match progress {
+            LoadProgress::HyperparametersLoaded(hparams) => {
+                log::debug!("Loaded HyperParams {hparams:#?}")
+            }
+            LoadProgress::BadToken { index } => {
+                log::info!("Warning: Bad token in vocab at index {index}")
+            }
+            LoadProgress::ContextSize { bytes } => log::info!(
+                "ggml ctx size = {:.2} MB\n",
+                bytes as f64 / (1024.0 * 1024.0)
+            ),
+            LoadProgress::MemorySize { bytes, n_mem } => log::info!(
+                "Memory size: {} MB {}",
+                bytes as f32 / 1024.0 / 1024.0,
+                n_mem
+            ),
+            LoadProgress::PartLoading {
+                file,
+                current_part,
+                total_parts,
+            } => log::info!(
+                "Loading model part {}/{} from '{}'\n",
+                current_part,
+                total_parts,
+                file.to_string_lossy(),
+            ),
+            LoadProgress::PartTensorLoaded {
+                current_tensor,
+                tensor_count,
+                ..
+            } => {
+                if current_tensor % 8 == 0 {
+                    log::info!("Loaded tensor {current_tensor}/{tensor_count}");
+                }
+            }
+            LoadProgress::PartLoaded {
+                file,
+                byte_size,
+                tensor_count,
+            } => {
+                log::info!("Loading of '{}' complete", file.to_string_lossy());
+                log::info!(
+                    "Model size = {:.2} MB / num tensors = {}",
+                    byte_size as f64 / 1024.0 / 1024.0,
+                    tensor_count
+                );
+            }
+        }
+    })
+    .expect("Could not load model");
+
+    log::info!("Model fully loaded!");
+    loaded
+}
+
 fn main() {
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
@@ -123,6 +418,11 @@ fn main() {
         .init();
 
     let args = &*CLI_ARGS;
+    #[cfg(not(unix))]
+    if args.execute {
+        log::error!("--execute runs commands in a pseudo terminal, which needs a Unix system");
+        std::process::exit(1);
+    }
 
     let inference_params = InferenceParameters {
         n_threads: args.num_threads as i32,
@@ -152,6 +452,26 @@ fn main() {
         }
     };
 
+    if let Some(command) = &args.command {
+        match command {
+            Command::Eval(eval_args) => eval::run(eval_args, &inference_session_params),
+            Command::Fix(fix_args) => fix::run(fix_args, &inference_session_params),
+            Command::Explain { command } => explain::run(
+                command.as_deref(),
+                &inference_params,
+                &inference_session_params,
+            ),
+            Command::History { command } => {
+                history::run(command, &inference_params, &inference_session_params)
+            }
+            Command::Journal { command } => journal::run(command),
+            Command::ShellInit { shell } => shell_init::print_init(*shell),
+            Command::Line(line_args) => shell_init::line(line_args),
+            Command::Ask { query } => shell_init::ask(query),
+        }
+        return;
+    }
+
     let prompt = if let Some(path) = &args.prompt_file {
         match std::fs::read_to_string(path) {
             Ok(mut prompt) => {
@@ -181,61 +501,22 @@ fn main() {
         std::process::exit(1);
     };
 
-    let (mut model, vocab) =
-        wiz_rs::Model::load(&args.model_path, args.num_ctx_tokens as i32, |progress| {
-            use wiz_rs::LoadProgress;
-            match progress {
-                LoadProgress::HyperparametersLoaded(hparams) => {
-                    log::debug!("Loaded HyperParams {hparams:#?}")
-                }
-                LoadProgress::BadToken { index } => {
-                    log::info!("Warning: Bad token in vocab at index {index}")
-                }
-                LoadProgress::ContextSize { bytes } => log::info!(
-                    "ggml ctx size = {:.2} MB\n",
-                    bytes as f64 / (1024.0 * 1024.0)
-                ),
-                LoadProgress::MemorySize { bytes, n_mem } => log::info!(
-                    "Memory size: {} MB {}",
-                    bytes as f32 / 1024.0 / 1024.0,
-                    n_mem
-                ),
-                LoadProgress::PartLoading {
-                    file,
-                    current_part,
-                    total_parts,
-                } => log::info!(
-                    "Loading model part {}/{} from '{}'\n",
-                    current_part,
-                    total_parts,
-                    file.to_string_lossy(),
-                ),
-                LoadProgress::PartTensorLoaded {
-                    current_tensor,
-                    tensor_count,
-                    ..
-                } => {
-                    if current_tensor % 8 == 0 {
-                        log::info!("Loaded tensor {current_tensor}/{tensor_count}");
-                    }
-                }
-                LoadProgress::PartLoaded {
-                    file,
-                    byte_size,
-                    tensor_count,
-                } => {
-                    log::info!("Loading of '{}' complete", file.to_string_lossy());
-                    log::info!(
-                        "Model size = {:.2} MB / num tensors = {}",
-                        byte_size as f64 / 1024.0 / 1024.0,
-                        tensor_count
-                    );
-                }
-            }
-        })
-        .expect("Could not load model");
+    let query = prompt.clone();
+    let template = load_template(args.repl.then_some("chat"));
+    let examples = template.as_ref().and_then(|_| load_example_index());
+    let context = match &template {
+        Some(_) if !args.no_context => ShellContext::gather().render(),
+        _ => None,
+    };
+    let prompt = match &template {
+        Some(template) if !args.repl => {
+            let examples = few_shot(examples.as_ref(), &prompt);
+            template.render(&[], &prompt, context.as_deref(), &examples)
+        }
+        _ => prompt,
+    };
 
-    log::info!("Model fully loaded!");
+    let (model, vocab) = load_model();
 
     let mut rng = if let Some(seed) = CLI_ARGS.seed {
         rand::rngs::StdRng::seed_from_u64(seed)
@@ -245,7 +526,13 @@ fn main() {
 
     let mut session = if let Some(restore_path) = &args.restore_prompt {
         let snapshot = InferenceSnapshot::load_from_disk(restore_path);
-        match snapshot.and_then(|snapshot| model.session_from_snapshot(snapshot)) {
+        if let Ok(InferenceSnapshot { header: None, .. }) = &snapshot {
+            log::warn!(
+                "{restore_path} uses the old snapshot format, which cannot be checked \
+                 against the model. Recreate it with --cache-prompt."
+            );
+        }
+        match snapshot.and_then(|snapshot| model.session_from_snapshot(&snapshot)) {
             Ok(session) => {
                 log::info!("Restored cached memory from {restore_path}");
                 session
@@ -260,8 +547,16 @@ fn main() {
     };
 
     if args.repl {
-        repl_mode(&model, &vocab, &inference_params, &inference_session_params);
-    } else if let Some(cache_path) = &args.cache_prompt {
+        repl_mode(
+            &model,
+            &vocab,
+            &inference_params,
+            &inference_session_params,
+            template.as_ref().expect("REPL mode has a default template"),
+            context.as_deref(),
+            examples.as_ref(),
+        );
+    } else if let Some(cache_path) = args.cache_prompt.as_ref().filter(|_| !args.execute) {
         let text: Rc<RefCell<String>> = Rc::new(RefCell::new("".to_string()));
 
         let new_inference_params: InferenceParameters = InferenceParameters {
@@ -306,21 +601,18 @@ fn main() {
         }
 
         // Write the memory to the cache file
-        // SAFETY: no other model functions used inside the block
-        unsafe {
-            let memory = session.get_snapshot();
-            match memory.write_to_disk(cache_path) {
-                Ok(_) => {
-                    log::info!("Successfully written prompt cache to {cache_path}");
-                }
-                Err(err) => {
-                    eprintln!("Could not restore prompt. Error: {err}");
-                    std::process::exit(1);
-                }
+        match session.write_snapshot_to_disk(cache_path, args.snapshot_compression) {
+            Ok(_) => {
+                log::info!("Successfully written prompt cache to {cache_path}");
+            }
+            Err(err) => {
+                eprintln!("Could not store prompt. Error: {err}");
+                std::process::exit(1);
             }
         }
     } else {
         let text: Rc<RefCell<String>> = Rc::new(RefCell::new("".to_string()));
+        let answer = RefCell::new(String::new());
 
         let new_inference_params: InferenceParameters = InferenceParameters {
             bias_tokens: Box::new(CustomTokenBias::new(text.clone())),
@@ -341,11 +633,18 @@ fn main() {
                     let mut text = text.borrow_mut();
                     *text += &format!("{t}");
                 }
+                if let wiz_rs::OutputToken::Token(t, true, _) = &t {
+                    *answer.borrow_mut() += t;
+                }
 
                 Ok(())
             },
         );
         println!();
+        print_risks(&prompt, &answer.borrow());
+        if args.execute {
+            offer_to_run(&query, &prompt, &answer.borrow());
+        }
 
         match res {
             Ok(stats) => {
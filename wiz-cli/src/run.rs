@@ -0,0 +1,253 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, Read, Write},
+    os::unix::{
+        io::{AsRawFd, FromRawFd},
+        process::{CommandExt, ExitStatusExt},
+    },
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use colored::Colorize;
+use nix::{
+    pty::{openpty, Winsize},
+    sys::termios::{self, SetArg, Termios},
+};
+use wiz_rs::{Outcome, RiskLevel};
+
+/// How much of the end of the output is kept in the record of a run, in
+/// bytes.
+const RECORDED_OUTPUT: usize = 4096;
+
+/// A command that was run.
+pub struct RunRecord {
+    pub command: String,
+    pub cwd: Option<String>,
+    /// `None` if the command was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub duration_ms: u64,
+    /// The end of the output, stdout and stderr interleaved as they appeared
+    /// in the terminal.
+    pub output: String,
+}
+
+impl RunRecord {
+    /// The run as the outcome of the answer `id` in the journal.
+    pub fn outcome(&self, id: &str) -> Outcome {
+        Outcome {
+            id: id.to_string(),
+            time: crate::journal::now(),
+            command: self.command.clone(),
+            exit_code: self.exit_code,
+            signal: self.signal,
+            duration_ms: Some(self.duration_ms),
+            cwd: self.cwd.clone(),
+            output: Some(self.output.clone()),
+        }
+    }
+}
+
+/// Asks whether to run `command`. Commands with a high risk or worse must be
+/// confirmed by typing `yes`, others with `y`.
+pub fn confirm(command: &str, risk: RiskLevel) -> bool {
+    let careful = risk >= RiskLevel::High;
+    let question = if careful {
+        format!("Run {}? Type 'yes' to confirm: ", command.bold())
+    } else {
+        format!("Run {}? [y/N] ", command.bold())
+    };
+    print!("{question}");
+    _ = io::stdout().flush();
+
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    match answer.trim().to_lowercase().as_str() {
+        "yes" => true,
+        "y" => !careful,
+        _ => false,
+    }
+}
+
+/// Runs `command` with the user's shell in a pseudo terminal, so programs
+/// behave like they do when typed in, passing its output through and the
+/// input of the terminal to it.
+pub fn run_in_pty(command: &str) -> io::Result<RunRecord> {
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+    let pty = openpty(window_size().as_ref(), None)?;
+    // SAFETY: `openpty` returned new descriptors that nothing else owns
+    let (mut master, slave) =
+        unsafe { (File::from_raw_fd(pty.master), File::from_raw_fd(pty.slave)) };
+
+    let start = Instant::now();
+    let mut child = {
+        let mut command_builder = Command::new(&shell);
+        command_builder
+            .arg("-c")
+            .arg(command)
+            .stdin(slave.try_clone()?)
+            .stdout(slave.try_clone()?)
+            .stderr(slave);
+        // SAFETY: only async-signal-safe calls between fork and exec. The
+        // command gets its own session with the pseudo terminal as the
+        // controlling terminal, for job control and `Ctrl-C`
+        unsafe {
+            command_builder.pre_exec(|| {
+                if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        // Dropping the builder closes our copies of the slave, so reading the
+        // master ends once the command exits
+        command_builder.spawn()?
+    };
+
+    let raw_mode = RawMode::enable();
+    let done = Arc::new(AtomicBool::new(false));
+    let input = {
+        let mut master = master.try_clone()?;
+        let done = done.clone();
+        std::thread::spawn(move || forward_input(&mut master, &done))
+    };
+
+    let mut output = vec![];
+    let mut buffer = [0; 4096];
+    let mut stdout = io::stdout();
+    loop {
+        match master.read(&mut buffer) {
+            Ok(0) => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            // EIO once the slave side is closed
+            Err(err) if err.raw_os_error() == Some(libc::EIO) => break,
+            Err(err) => {
+                log::warn!("Could not read the output of the command: {err}");
+                break;
+            }
+            Ok(n) => {
+                _ = stdout.write_all(&buffer[..n]);
+                _ = stdout.flush();
+                output.extend_from_slice(&buffer[..n]);
+                if output.len() > 2 * RECORDED_OUTPUT {
+                    output.drain(..output.len() - RECORDED_OUTPUT);
+                }
+            }
+        }
+    }
+    let status = child.wait()?;
+    done.store(true, Ordering::Relaxed);
+    _ = input.join();
+    drop(raw_mode);
+
+    let output = &output[output.len().saturating_sub(RECORDED_OUTPUT)..];
+    Ok(RunRecord {
+        command: command.to_string(),
+        cwd: std::env::current_dir()
+            .ok()
+            .map(|dir| dir.to_string_lossy().into_owned()),
+        exit_code: status.code(),
+        signal: status.signal(),
+        duration_ms: start.elapsed().as_millis() as u64,
+        output: String::from_utf8_lossy(output).into_owned(),
+    })
+}
+
+/// Copies the terminal input to the command until `done` is set. Polls so
+/// that it does not keep reading input meant for what comes next.
+fn forward_input(master: &mut File, done: &AtomicBool) {
+    let mut buffer = [0; 1024];
+    while !done.load(Ordering::Relaxed) {
+        let mut fds = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `fds` is a valid array of one descriptor
+        let ready = unsafe { libc::poll(&mut fds, 1, 50) };
+        if ready <= 0 || fds.revents & libc::POLLIN == 0 {
+            if fds.revents & (libc::POLLHUP | libc::POLLNVAL) != 0 {
+                return;
+            }
+            continue;
+        }
+        // SAFETY: reading into a buffer of the given length
+        let n = unsafe {
+            libc::read(
+                libc::STDIN_FILENO,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+            )
+        };
+        if n <= 0 || master.write_all(&buffer[..n as usize]).is_err() {
+            return;
+        }
+    }
+}
+
+/// The size of the terminal the output goes to.
+fn window_size() -> Option<Winsize> {
+    // SAFETY: `TIOCGWINSZ` writes a `winsize`
+    unsafe {
+        let mut size: Winsize = std::mem::zeroed();
+        (libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0).then_some(size)
+    }
+}
+
+/// Puts the terminal into raw mode while alive, so keys reach the command
+/// unprocessed. Does nothing if stdin is not a terminal.
+struct RawMode(Option<Termios>);
+
+impl RawMode {
+    fn enable() -> Self {
+        let stdin = io::stdin().as_raw_fd();
+        let Ok(original) = termios::tcgetattr(stdin) else {
+            return Self(None);
+        };
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        match termios::tcsetattr(stdin, SetArg::TCSANOW, &raw) {
+            Ok(()) => Self(Some(original)),
+            Err(_) => Self(None),
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(original) = &self.0 {
+            _ = termios::tcsetattr(io::stdin().as_raw_fd(), SetArg::TCSANOW, original);
+        }
+    }
+}
+
+/// Asks for confirmation and runs the command. Returns the record to add to
+/// the journal, `None` if the command was not run.
+pub fn execute(command: &str, risk: RiskLevel) -> Option<RunRecord> {
+    let command = command.trim();
+    if command.is_empty() || !confirm(command, risk) {
+        return None;
+    }
+
+    let run = match run_in_pty(command) {
+        Ok(run) => run,
+        Err(err) => {
+            log::error!("Could not run the command: {err}");
+            return None;
+        }
+    };
+    match (run.exit_code, run.signal) {
+        (Some(0), _) => println!("{}", "exit code 0".dimmed()),
+        (Some(code), _) => println!("{}", format!("exit code {code}").red()),
+        (None, Some(signal)) => println!("{}", format!("killed by signal {signal}").red()),
+        (None, None) => {}
+    }
+    Some(run)
+}
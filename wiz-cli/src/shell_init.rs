@@ -0,0 +1,247 @@
+use colored::Colorize;
+use wiz_protocol::{PartKind, RiskLevel, StreamEvent};
+use wiz_rs::Outcome;
+
+use crate::{
+    cli_args::{LineArgs, Shell},
+    client::Client,
+    CLI_ARGS,
+};
+
+/// `{wiz}` is replaced with the quoted path of this executable. The key
+/// binding passes the line to `wiz-cli line`, whose output is the new cursor
+/// position followed by the new line. The prompt hook writes the file read by
+/// `wiz-cli fix`.
+const BASH: &str = r#"# wiz shell integration for bash
+__wiz_line() {
+    local output
+    output=$({wiz} line --cursor "$READLINE_POINT" -- "$READLINE_LINE") || return
+    READLINE_LINE=${output#*$'\n'}
+    READLINE_POINT=${output%%$'\n'*}
+}
+bind -x '"\ew": __wiz_line'
+
+__wiz_last_history=
+__wiz_precmd() {
+    local exit_status=$?
+    local entry
+    entry=$(HISTTIMEFORMAT= builtin history 1)
+    # Pressing enter on an empty line runs nothing
+    if [[ -n $entry && $entry != "$__wiz_last_history" ]]; then
+        __wiz_last_history=$entry
+        command mkdir -p ~/.wiz
+        printf '%s\n%s\n' "$exit_status" "$(sed -E '1s/^ *[0-9]+\*? *//' <<< "$entry")" > ~/.wiz/last_command
+    fi
+    return $exit_status
+}
+PROMPT_COMMAND="__wiz_precmd${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+
+explain() {
+    {wiz} explain "$@"
+}
+"#;
+
+const ZSH: &str = r#"# wiz shell integration for zsh
+__wiz_line() {
+    local output
+    output=$({wiz} line --cursor "$CURSOR" -- "$BUFFER") || return
+    BUFFER=${output#*$'\n'}
+    CURSOR=${output%%$'\n'*}
+    zle redisplay
+}
+zle -N __wiz_line
+bindkey '^[w' __wiz_line
+
+__wiz_command=
+__wiz_preexec() {
+    __wiz_command=$1
+}
+__wiz_precmd() {
+    local exit_status=$?
+    [[ -n $__wiz_command ]] || return
+    command mkdir -p ~/.wiz
+    printf '%s\n%s\n' "$exit_status" "$__wiz_command" >| ~/.wiz/last_command
+    __wiz_command=
+}
+autoload -Uz add-zsh-hook
+add-zsh-hook preexec __wiz_preexec
+# First, so that no other hook changes the exit status before
+precmd_functions=(__wiz_precmd ${precmd_functions:#__wiz_precmd})
+
+explain() {
+    {wiz} explain "$@"
+}
+"#;
+
+const FISH: &str = r#"# wiz shell integration for fish
+function __wiz_line
+    set -l output ({wiz} line --cursor (commandline -C) -- (commandline -b | string collect))
+    or return
+    set -l line (string join \n -- $output[2..-1])
+    commandline -r -- "$line"
+    commandline -C -- $output[1]
+end
+bind \ew __wiz_line
+
+function __wiz_postexec --on-event fish_postexec
+    set -l exit_status $status
+    test -n "$argv[1]"; or return
+    command mkdir -p ~/.wiz
+    printf '%s\n%s\n' $exit_status $argv[1] > ~/.wiz/last_command
+end
+
+function explain
+    {wiz} explain $argv
+end
+"#;
+
+/// Puts `text` in single quotes, escaped the way `shell` understands.
+fn quote(text: &str, shell: Shell) -> String {
+    match shell {
+        Shell::Bash | Shell::Zsh => format!("'{}'", text.replace('\'', r"'\''")),
+        Shell::Fish => format!("'{}'", text.replace('\\', r"\\").replace('\'', r"\'")),
+    }
+}
+
+/// Prints the integration code for `shell`.
+pub fn print_init(shell: Shell) {
+    let wiz = std::env::current_exe()
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "wiz-cli".to_string());
+    let mut wiz = quote(&wiz, shell);
+    if CLI_ARGS.server != "http://localhost:8085" {
+        wiz += &format!(" --server {}", quote(&CLI_ARGS.server, shell));
+    }
+    let script = match shell {
+        Shell::Bash => BASH,
+        Shell::Zsh => ZSH,
+        Shell::Fish => FISH,
+    };
+    print!("{}", script.replace("{wiz}", &wiz));
+}
+
+/// Rewrites a command line: a line starting with `#` is replaced by the
+/// command it describes, any other line is completed at the cursor. Prints the
+/// cursor position and the line, unchanged if nothing came back.
+pub fn line(args: &LineArgs) {
+    let line = args.line.as_str();
+    let cursor = match args.cursor {
+        Some(cursor) => line
+            .char_indices()
+            .nth(cursor)
+            .map_or(line.len(), |(i, _)| i),
+        None => line.len(),
+    };
+    let client = Client::new(&CLI_ARGS.server);
+
+    let rewritten = match line.trim_start().strip_prefix('#') {
+        Some(description) => client.command(description.trim()).map(|suggestion| {
+            suggestion.map(|suggestion| {
+                let command = suggestion.command.trim().to_string();
+                // What happens when it runs is up to the shell
+                let chosen = Outcome {
+                    id: suggestion.id,
+                    command: command.clone(),
+                    ..Default::default()
+                };
+                if let Err(err) = client.outcome(&chosen) {
+                    log::debug!("Could not report the chosen command: {err}");
+                }
+                (command, None)
+            })
+        }),
+        None => client.complete(line, cursor).map(|completions| {
+            completions.into_iter().next().map(|completion| {
+                let rewritten = format!("{}{completion}{}", &line[..cursor], &line[cursor..]);
+                (rewritten, Some(cursor + completion.len()))
+            })
+        }),
+    };
+    let (line, cursor) = match rewritten {
+        Ok(Some((line, cursor))) => (line, cursor),
+        Ok(None) => (line.to_string(), Some(cursor)),
+        Err(err) => {
+            // The shell keeps the line as it is
+            eprintln!("\nwiz: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let cursor = cursor.unwrap_or(line.len());
+    println!("{}", line[..cursor].chars().count());
+    print!("{line}");
+}
+
+/// Prints the answer of `wiz-server` to `query` as it streams in. With
+/// `--execute`, offers to run the first command and reports the outcome to
+/// the server.
+pub fn ask(query: &str) {
+    let client = Client::new(&CLI_ARGS.server);
+    let mut id = String::new();
+    let mut kinds: Vec<PartKind> = vec![];
+    let mut command: Option<String> = None;
+    let result = client.query(query, |event| match event {
+        StreamEvent::Start { id: start, .. } => id = start,
+        StreamEvent::Candidate { index } if index > 0 => {
+            kinds.clear();
+            command = None;
+            println!("\n{}", "That does not work here, trying again".dimmed());
+        }
+        StreamEvent::PartStart { kind, .. } => {
+            if !kinds.is_empty() {
+                println!("\n");
+            }
+            if kind == PartKind::Command && command.is_none() {
+                command = Some(String::new());
+            }
+            kinds.push(kind);
+        }
+        StreamEvent::PartText { index, text } => {
+            let first_command = kinds.iter().position(|kind| *kind == PartKind::Command);
+            if first_command == Some(index) {
+                command.get_or_insert_with(String::new).push_str(&text);
+            }
+            match kinds.get(index) {
+                Some(PartKind::Command) => print!("{}", text.yellow().bold()),
+                Some(PartKind::Warning) => print!("{}", text.red()),
+                _ => print!("{text}"),
+            }
+            _ = std::io::Write::flush(&mut std::io::stdout());
+        }
+        StreamEvent::Risk { level, reasons, .. } if level != RiskLevel::None => {
+            let label = format!("{level:?} risk").to_lowercase();
+            println!("\n{}", label.red().bold());
+            for reason in reasons {
+                println!("  {} {}", "-".dimmed(), reason.message);
+            }
+        }
+        StreamEvent::Error { message, .. } => eprintln!("\n{}", message.red()),
+        _ => {}
+    });
+    println!();
+    if let Err(err) = result {
+        log::error!("Could not ask wiz-server at {}: {err}", CLI_ARGS.server);
+        std::process::exit(1);
+    }
+
+    if let Some(command) = command.filter(|_| CLI_ARGS.execute) {
+        run_and_report(&client, &id, &command);
+    }
+}
+
+/// Offers to run `command` of the answer `id` and reports the outcome to the
+/// server, which adds it to its journal.
+#[cfg(unix)]
+fn run_and_report(client: &Client, id: &str, command: &str) {
+    let cwd = std::env::current_dir().ok();
+    let risk = wiz_rs::assess_risk(command, cwd.as_deref()).level();
+    if let Some(run) = crate::run::execute(command, risk) {
+        if let Err(err) = client.outcome(&run.outcome(id)) {
+            log::warn!("Could not report the outcome to wiz-server: {err}");
+        }
+    }
+}
+
+/// `--execute` is rejected up front on other systems, see `main`.
+#[cfg(not(unix))]
+fn run_and_report(_client: &Client, _id: &str, _command: &str) {}
@@ -0,0 +1,383 @@
+//! The event stream served by `wiz-server` on `POST /api/v1/completions`.
+//!
+//! Every server-sent event carries one JSON encoded [`StreamEvent`] in its
+//! `data` field, and the variant name in its `event` field. The variant is also
+//! stored in the `"type"` key of the JSON object, so clients that ignore SSE
+//! event names can still tell the events apart.
+//!
+//! A stream always looks like this:
+//!
+//! ```text
+//! start
+//! candidate            (once per candidate, index 0, 1, ...)
+//!   token*             (tokens of that candidate)
+//!   part_start         (interleaved with the tokens: the same text split
+//!   part_text*          into commands, explanation paragraphs and warnings)
+//!   risk               (once per command part, after its last part_text)
+//!   validation         (after each risk, unless the server does not validate)
+//!   usage
+//! done                 (always the last event)
+//! ```
+//!
+//! Candidates after the first one are only generated when the server is told
+//! to retry answers whose commands fail validation. Each one replaces the
+//! previous candidate.
+//!
+//! If something goes wrong, an `error` event is sent and the stream still ends
+//! with `done`. Clients should ignore event types they do not know about, new
+//! events and error codes may be added without bumping [`PROTOCOL_VERSION`].
+//! They deserialize as [`StreamEvent::Unknown`] and [`ErrorCode::Unknown`], and
+//! the same goes for new phases, part kinds, risk levels and issue kinds.
+
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever an existing event changes in an incompatible way.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Which part of the answer a token belongs to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Phase {
+    /// The generated shell command.
+    Command,
+    /// The explanation following the command.
+    Explanation,
+    /// A phase added by a newer server.
+    #[serde(other)]
+    Unknown,
+}
+
+/// What a part of the answer is.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum PartKind {
+    /// The contents of a code block.
+    Command,
+    /// A paragraph of the explanation.
+    Explanation,
+    /// A paragraph warning about the command.
+    Warning,
+    /// A kind of part added by a newer server.
+    #[serde(other)]
+    Unknown,
+}
+
+/// How much damage a command can do, from harmless to destructive.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum RiskLevel {
+    /// Nothing suspicious was found.
+    None,
+    /// Changes something, but in a way that is easy to undo or expected.
+    Low,
+    /// Loses data that may be hard to get back.
+    Medium,
+    /// Loses data or runs untrusted code.
+    High,
+    /// Can destroy the system or whole disks.
+    Critical,
+    /// A level added by a newer server. It orders above all known levels, so
+    /// clients treat it at least as carefully as `Critical`.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Something a command does that makes it risky.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RiskReason {
+    pub level: RiskLevel,
+    pub message: String,
+    /// Byte offsets into the text of the part, of the shell code the reason is
+    /// about.
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Why a command fails validation.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum IssueKind {
+    /// The shell syntax is invalid.
+    Syntax,
+    /// A program is not installed on the server's machine.
+    MissingExecutable,
+    /// An option does not appear in the help text of its program.
+    UnknownFlag,
+    /// A kind of issue added by a newer server.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ValidationIssue {
+    pub kind: IssueKind,
+    pub message: String,
+    /// Byte offsets into the text of the part, if the issue is about a
+    /// specific piece of it.
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+}
+
+/// Machine readable reason of an `error` event.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The prompt does not fit into the context window.
+    ContextFull,
+    /// The prompt could not be tokenized.
+    TokenizationFailed,
+    /// The server is shutting down and cancelled the generation.
+    ShuttingDown,
+    /// The request could not be handed to the inference worker, or the model
+    /// could not be loaded.
+    Unavailable,
+    /// The conversation does not exist (anymore).
+    NotFound,
+    /// A code added by a newer server.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Why the stream ended.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model finished its answer.
+    Stop,
+    /// The context window is full.
+    Length,
+    /// An `error` event was sent before.
+    Error,
+}
+
+/// Token counts and timings of a finished generation.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    /// Tokens fed to the model, including any cached prompt prefix.
+    pub prompt_tokens: usize,
+    /// Tokens generated by the model.
+    pub completion_tokens: usize,
+    /// Time spent feeding the prompt, in milliseconds.
+    pub prompt_ms: u64,
+    /// Time spent generating, in milliseconds.
+    pub completion_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum StreamEvent {
+    /// First event of every stream.
+    Start {
+        version: u32,
+        id: String,
+        model: String,
+    },
+    /// Marks the beginning of a candidate answer. All following `token`
+    /// events belong to it, until the next `candidate` event.
+    Candidate {
+        index: usize,
+    },
+    /// A piece of generated text, as the model wrote it.
+    Token {
+        text: String,
+        phase: Phase,
+        token_id: Option<u32>,
+    },
+    /// A new part of the answer begins. Parts are numbered from 0 within a
+    /// candidate, their text follows in `part_text` events.
+    PartStart {
+        index: usize,
+        kind: PartKind,
+        /// The language tag of a code block, e.g. `bash`.
+        language: Option<String>,
+    },
+    /// More text of a part, without code fences and the blank lines between
+    /// parts.
+    PartText {
+        index: usize,
+        text: String,
+    },
+    /// The assessment of a complete command part, with the level of its worst
+    /// reason.
+    Risk {
+        index: usize,
+        level: RiskLevel,
+        reasons: Vec<RiskReason>,
+    },
+    /// Whether a complete command part works on the server's machine.
+    Validation {
+        index: usize,
+        valid: bool,
+        issues: Vec<ValidationIssue>,
+    },
+    Usage(Usage),
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// Last event of every stream.
+    Done {
+        finish_reason: FinishReason,
+    },
+    /// An event added by a newer server, which clients should skip.
+    #[serde(other)]
+    Unknown,
+}
+
+impl StreamEvent {
+    /// The name used as the SSE `event` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamEvent::Start { .. } => "start",
+            StreamEvent::Candidate { .. } => "candidate",
+            StreamEvent::Token { .. } => "token",
+            StreamEvent::PartStart { .. } => "part_start",
+            StreamEvent::PartText { .. } => "part_text",
+            StreamEvent::Risk { .. } => "risk",
+            StreamEvent::Validation { .. } => "validation",
+            StreamEvent::Usage(_) => "usage",
+            StreamEvent::Error { .. } => "error",
+            StreamEvent::Done { .. } => "done",
+            StreamEvent::Unknown => "unknown",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(event: StreamEvent) {
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            serde_json::from_str::<StreamEvent>(&json).unwrap(),
+            event,
+            "{json}"
+        );
+    }
+
+    #[test]
+    fn events_round_trip() {
+        round_trip(StreamEvent::Start {
+            version: PROTOCOL_VERSION,
+            id: "cmpl-1".to_string(),
+            model: "wizard".to_string(),
+        });
+        round_trip(StreamEvent::Token {
+            text: "ls".to_string(),
+            phase: Phase::Command,
+            token_id: Some(42),
+        });
+        round_trip(StreamEvent::PartStart {
+            index: 1,
+            kind: PartKind::Warning,
+            language: None,
+        });
+        round_trip(StreamEvent::Risk {
+            index: 0,
+            level: RiskLevel::High,
+            reasons: vec![RiskReason {
+                level: RiskLevel::High,
+                message: "deletes files".to_string(),
+                start: 0,
+                end: 5,
+            }],
+        });
+        round_trip(StreamEvent::Validation {
+            index: 0,
+            valid: false,
+            issues: vec![ValidationIssue {
+                kind: IssueKind::UnknownFlag,
+                message: "ls has no option --frobnicate".to_string(),
+                start: Some(3),
+                end: None,
+            }],
+        });
+        round_trip(StreamEvent::Usage(Usage {
+            prompt_tokens: 10,
+            completion_tokens: 3,
+            prompt_ms: 120,
+            completion_ms: 45,
+        }));
+        round_trip(StreamEvent::Error {
+            code: ErrorCode::ContextFull,
+            message: "the prompt is too long".to_string(),
+        });
+        round_trip(StreamEvent::Done {
+            finish_reason: FinishReason::Stop,
+        });
+    }
+
+    #[test]
+    fn event_names_match_the_type_key() {
+        let event = StreamEvent::PartText {
+            index: 0,
+            text: "ls".to_string(),
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], event.name());
+    }
+
+    #[test]
+    fn unknown_variants_deserialize() {
+        let event: StreamEvent =
+            serde_json::from_str(r#"{"type":"thinking","text":"hm"}"#).unwrap();
+        assert_eq!(event, StreamEvent::Unknown);
+
+        let event: StreamEvent = serde_json::from_str(
+            r#"{"type":"token","text":"x","phase":"reasoning","token_id":null}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            event,
+            StreamEvent::Token {
+                phase: Phase::Unknown,
+                ..
+            }
+        ));
+
+        let event: StreamEvent = serde_json::from_str(
+            r#"{"type":"part_start","index":0,"kind":"table","language":null}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            event,
+            StreamEvent::PartStart {
+                kind: PartKind::Unknown,
+                ..
+            }
+        ));
+
+        let event: StreamEvent = serde_json::from_str(
+            r#"{"type":"validation","index":0,"valid":false,"issues":[{"kind":"deprecated","message":"","start":null,"end":null}]}"#,
+        )
+        .unwrap();
+        let StreamEvent::Validation { issues, .. } = event else {
+            panic!("{event:?}");
+        };
+        assert_eq!(issues[0].kind, IssueKind::Unknown);
+
+        let event: StreamEvent =
+            serde_json::from_str(r#"{"type":"error","code":"rate_limited","message":""}"#).unwrap();
+        assert!(matches!(
+            event,
+            StreamEvent::Error {
+                code: ErrorCode::Unknown,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn unknown_risk_levels_are_the_most_careful() {
+        let level: RiskLevel = serde_json::from_str(r#""catastrophic""#).unwrap();
+        assert_eq!(level, RiskLevel::Unknown);
+        assert!(level > RiskLevel::Critical);
+    }
+}
@@ -0,0 +1,40 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{InferenceParameters, ResponseParser, TokenBias, EOD_TOKEN_ID};
+
+/// Keeps the model from ending its answer before it completed the command and
+/// wrote something after it, the explanation.
+#[derive(Clone, Debug)]
+pub struct CommandBlockBias(Rc<RefCell<ResponseParser>>);
+
+impl CommandBlockBias {
+    /// `parser` must be fed the generated text, without the prompt.
+    pub fn new(parser: Rc<RefCell<ResponseParser>>) -> Self {
+        Self(parser)
+    }
+}
+
+impl TokenBias for CommandBlockBias {
+    fn get(&self, tid: u32) -> Option<f32> {
+        if tid == EOD_TOKEN_ID && !self.0.borrow().answered() {
+            Some(-1.0)
+        } else {
+            None
+        }
+    }
+}
+
+/// The sampling used to answer queries: greedy, and not stopping before the
+/// command is complete. `parser` must be kept up to date with the generated
+/// text, see `CommandBlockBias`.
+pub fn answer_parameters(parser: Rc<RefCell<ResponseParser>>) -> InferenceParameters {
+    InferenceParameters {
+        n_threads: 4,
+        n_batch: 8,
+        top_k: 1,
+        top_p: 1.0,
+        repeat_penalty: 1.0,
+        temp: 1.0,
+        bias_tokens: Box::new(CommandBlockBias::new(parser)),
+    }
+}
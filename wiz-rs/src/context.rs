@@ -0,0 +1,152 @@
+use std::path::{Path, PathBuf};
+
+/// Binaries whose presence tells the model which package manager and tools it
+/// can suggest.
+pub const KEY_BINARIES: &[&str] = &[
+    "apt",
+    "dnf",
+    "yum",
+    "pacman",
+    "zypper",
+    "apk",
+    "brew",
+    "port",
+    "nix",
+    "snap",
+    "flatpak",
+    "sudo",
+    "doas",
+    "systemctl",
+    "git",
+    "docker",
+    "podman",
+    "kubectl",
+    "python3",
+    "node",
+    "curl",
+    "wget",
+    "jq",
+    "rg",
+    "fd",
+    "fzf",
+    "gsed",
+    "gawk",
+];
+
+/// What the model should know about the machine a command is meant for. Every
+/// field is optional, a context without any information is not put into the
+/// prompt at all.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct ShellContext {
+    /// Operating system, e.g. `linux` or `macos`.
+    pub os: Option<String>,
+    /// Name and version of the distribution or OS release.
+    pub distro: Option<String>,
+    /// Name of the user's shell, e.g. `zsh`.
+    pub shell: Option<String>,
+    /// The current working directory.
+    pub cwd: Option<String>,
+    /// The `KEY_BINARIES` found on `PATH`.
+    pub tools: Vec<String>,
+}
+
+impl ShellContext {
+    /// Gathers the context of the current process.
+    pub fn gather() -> Self {
+        Self {
+            os: Some(std::env::consts::OS.to_string()),
+            distro: distro(),
+            shell: std::env::var_os("SHELL").and_then(|shell| {
+                Path::new(&shell)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            }),
+            cwd: std::env::current_dir()
+                .ok()
+                .map(|dir| dir.to_string_lossy().into_owned()),
+            tools: KEY_BINARIES
+                .iter()
+                .filter(|name| find_executable(name).is_some())
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+
+    /// Fills the fields missing here from `fallback`.
+    pub fn or(self, fallback: ShellContext) -> ShellContext {
+        ShellContext {
+            os: self.os.or(fallback.os),
+            distro: self.distro.or(fallback.distro),
+            shell: self.shell.or(fallback.shell),
+            cwd: self.cwd.or(fallback.cwd),
+            tools: if self.tools.is_empty() {
+                fallback.tools
+            } else {
+                self.tools
+            },
+        }
+    }
+
+    /// One line per known field, to be put into the `{context}` placeholder
+    /// of a prompt template. `None` if nothing is known.
+    pub fn render(&self) -> Option<String> {
+        let os = match (&self.os, &self.distro) {
+            (Some(os), Some(distro)) => Some(format!("{os} ({distro})")),
+            (os, distro) => os.clone().or_else(|| distro.clone()),
+        };
+        let lines: Vec<String> = [
+            os.map(|os| format!("OS: {os}")),
+            self.shell.as_ref().map(|shell| format!("Shell: {shell}")),
+            self.cwd.as_ref().map(|cwd| format!("Directory: {cwd}")),
+            (!self.tools.is_empty()).then(|| format!("Available tools: {}", self.tools.join(", "))),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+}
+
+/// The `PRETTY_NAME` from `/etc/os-release` on Linux, the product version on
+/// macOS.
+fn distro() -> Option<String> {
+    if cfg!(target_os = "macos") {
+        let output = std::process::Command::new("sw_vers")
+            .arg("-productVersion")
+            .output()
+            .ok()?;
+        let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        return (!version.is_empty()).then(|| format!("macOS {version}"));
+    }
+
+    let os_release = std::fs::read_to_string("/etc/os-release")
+        .or_else(|_| std::fs::read_to_string("/usr/lib/os-release"))
+        .ok()?;
+    os_release.lines().find_map(|line| {
+        let value = line.strip_prefix("PRETTY_NAME=")?;
+        Some(value.trim_matches('"').to_string())
+    })
+}
+
+/// Looks up an executable by name in the directories on `PATH`.
+pub fn find_executable(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file() || path.with_extension("exe").is_file()
+}
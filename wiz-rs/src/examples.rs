@@ -0,0 +1,253 @@
+use std::{collections::HashMap, path::Path};
+
+/// A query along with the commands that are acceptable answers to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Example {
+    pub query: String,
+    pub commands: Vec<String>,
+    /// The `# <section>` the example is listed under, e.g. `train` or `eval`.
+    pub section: Option<String>,
+}
+
+/// Parses the `examples.txt` format: a query line followed by one or more
+/// `> command` lines, with examples separated by blank lines and grouped by
+/// `# section` headers.
+pub fn parse_examples(text: &str) -> Vec<Example> {
+    let mut examples: Vec<Example> = vec![];
+    let mut section = None;
+    let mut current: Option<Example> = None;
+
+    for line in text.lines().map(str::trim) {
+        if let Some(command) = line.strip_prefix('>') {
+            if let Some(example) = &mut current {
+                example.commands.push(command.trim().to_string());
+            }
+            continue;
+        }
+
+        examples.extend(current.take().filter(|e| !e.commands.is_empty()));
+        if let Some(name) = line.strip_prefix('#') {
+            section = Some(name.trim().to_string());
+        } else if !line.is_empty() {
+            current = Some(Example {
+                query: line.to_string(),
+                commands: vec![],
+                section: section.clone(),
+            });
+        }
+    }
+    examples.extend(current.filter(|e| !e.commands.is_empty()));
+
+    examples
+}
+
+/// Reads and parses an example file, see `parse_examples`.
+pub fn load_examples(path: impl AsRef<Path>) -> std::io::Result<Vec<Example>> {
+    Ok(parse_examples(&std::fs::read_to_string(path)?))
+}
+
+fn terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+const BM25_K1: f32 = 1.2;
+const BM25_B: f32 = 0.75;
+
+/// Ranks texts by their similarity to a query with Okapi BM25 over their
+/// words.
+pub(crate) struct Bm25 {
+    /// The term frequencies of each text.
+    documents: Vec<HashMap<String, usize>>,
+    lengths: Vec<usize>,
+    /// In how many texts each term appears.
+    document_frequency: HashMap<String, usize>,
+    average_length: f32,
+}
+
+impl Bm25 {
+    pub(crate) fn new<'a>(texts: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut documents = vec![];
+        let mut lengths = vec![];
+        let mut document_frequency: HashMap<String, usize> = HashMap::new();
+
+        for text in texts {
+            let terms = terms(text);
+            let mut frequencies: HashMap<String, usize> = HashMap::new();
+            for term in &terms {
+                *frequencies.entry(term.clone()).or_default() += 1;
+            }
+            for term in frequencies.keys() {
+                *document_frequency.entry(term.clone()).or_default() += 1;
+            }
+            lengths.push(terms.len());
+            documents.push(frequencies);
+        }
+
+        let average_length = lengths.iter().sum::<usize>() as f32 / lengths.len().max(1) as f32;
+        Self {
+            documents,
+            lengths,
+            document_frequency,
+            average_length,
+        }
+    }
+
+    /// The indices and scores of the `k` texts most similar to `query`, best
+    /// first. Texts that do not share a word with the query are left out.
+    pub(crate) fn top_k(&self, query: &str, k: usize) -> Vec<(usize, f32)> {
+        let query_terms = terms(query);
+        let n = self.documents.len() as f32;
+
+        let mut scored: Vec<(usize, f32)> = self
+            .documents
+            .iter()
+            .zip(&self.lengths)
+            .map(|(frequencies, &length)| {
+                query_terms
+                    .iter()
+                    .filter_map(|term| {
+                        let tf = *frequencies.get(term)? as f32;
+                        let df = self.document_frequency[term] as f32;
+                        let idf = ((n - df + 0.5) / (df + 0.5) + 1.0).ln();
+                        let norm = 1.0 - BM25_B + BM25_B * length as f32 / self.average_length;
+                        Some(idf * tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * norm))
+                    })
+                    .sum::<f32>()
+            })
+            .enumerate()
+            .filter(|(_, score)| *score > 0.0)
+            .collect();
+
+        // Stable, so equally similar texts keep their order
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.truncate(k);
+        scored
+    }
+}
+
+/// Finds the examples whose queries are most similar to a new query, ranked
+/// with Okapi BM25 over their words.
+pub struct ExampleIndex {
+    examples: Vec<Example>,
+    ranking: Bm25,
+}
+
+impl ExampleIndex {
+    pub fn new(examples: Vec<Example>) -> Self {
+        let ranking = Bm25::new(examples.iter().map(|example| example.query.as_str()));
+        Self { examples, ranking }
+    }
+
+    pub fn len(&self) -> usize {
+        self.examples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.examples.is_empty()
+    }
+
+    /// The `k` examples most similar to `query`, best first. Examples that do
+    /// not share a word with the query are never returned.
+    pub fn top_k(&self, query: &str, k: usize) -> Vec<(&Example, f32)> {
+        self.ranking
+            .top_k(query, k)
+            .into_iter()
+            .map(|(i, score)| (&self.examples[i], score))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLES: &str = "
+# eval
+
+docker stop and remove all containers
+> docker stop $(docker ps -a -q) && docker rm $(docker ps -a -q)
+
+a query without any command
+
+# train
+list files sorted by size
+>  ls -lS
+> du -sh * | sort -h
+git remove untracked files
+> git clean -fd
+";
+
+    fn example(query: &str) -> Example {
+        Example {
+            query: query.to_string(),
+            commands: vec![],
+            section: None,
+        }
+    }
+
+    #[test]
+    fn sections_and_commands() {
+        let examples = parse_examples(EXAMPLES);
+        let summary: Vec<_> = examples
+            .iter()
+            .map(|e| (e.query.as_str(), e.commands.len(), e.section.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("docker stop and remove all containers", 1, Some("eval")),
+                ("list files sorted by size", 2, Some("train")),
+                ("git remove untracked files", 1, Some("train")),
+            ]
+        );
+        assert_eq!(examples[1].commands, ["ls -lS", "du -sh * | sort -h"]);
+    }
+
+    #[test]
+    fn ranks_by_shared_rare_words() {
+        let index = ExampleIndex::new(vec![
+            example("list all files"),
+            example("list all running docker containers"),
+            example("remove all stopped docker containers"),
+            example("show disk usage"),
+        ]);
+        let ranked: Vec<_> = index
+            .top_k("Remove Docker containers", 3)
+            .into_iter()
+            .map(|(example, _)| example.query.as_str())
+            .collect();
+        assert_eq!(
+            ranked,
+            [
+                "remove all stopped docker containers",
+                "list all running docker containers",
+            ]
+        );
+    }
+
+    #[test]
+    fn limits_results_and_skips_unrelated() {
+        let index = ExampleIndex::new(vec![
+            example("list files"),
+            example("list directories"),
+            example("list users"),
+        ]);
+        assert_eq!(index.top_k("list", 2).len(), 2);
+        assert!(index.top_k("reboot now", 3).is_empty());
+        assert!(ExampleIndex::new(vec![]).top_k("list", 3).is_empty());
+    }
+
+    #[test]
+    fn equal_scores_keep_their_order() {
+        let index = ExampleIndex::new(vec![example("copy a"), example("copy b")]);
+        let ranked: Vec<_> = index
+            .top_k("copy", 2)
+            .into_iter()
+            .map(|(example, _)| example.query.as_str())
+            .collect();
+        assert_eq!(ranked, ["copy a", "copy b"]);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{
+    safety::{program, unwrap_command},
+    shell::{self, ParseError, SimpleCommand, Span},
+};
+
+/// Programs whose first argument names a subcommand, which is explained
+/// together with the program, like `git log`.
+const SUBCOMMAND_PROGRAMS: &[&str] = &[
+    "apt",
+    "apt-get",
+    "brew",
+    "cargo",
+    "dnf",
+    "docker",
+    "gh",
+    "git",
+    "go",
+    "helm",
+    "kubectl",
+    "npm",
+    "pip",
+    "pip3",
+    "podman",
+    "snap",
+    "systemctl",
+    "terraform",
+    "yarn",
+];
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentKind {
+    /// A command with its arguments, e.g. one stage of a pipeline.
+    Command,
+    /// An option of a command, like `-la` or `--color=auto`.
+    Flag,
+    /// A redirect, like `2>/dev/null`.
+    Redirect,
+}
+
+/// A piece of a command that is explained on its own.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    /// The segment as written in the command.
+    pub text: String,
+    /// Byte offsets into the explained command.
+    pub start: usize,
+    pub end: usize,
+    /// Index of the `Command` segment this segment belongs to, its own index
+    /// for commands.
+    pub command: usize,
+    /// What the segment does, `None` until an answer of the model was applied
+    /// or if the answer left the segment out.
+    pub explanation: Option<String>,
+}
+
+/// A shell command split into the pieces explained by the model, with the
+/// explanations once the answer is in.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct CommandBreakdown {
+    pub command: String,
+    /// What the command as a whole does.
+    pub summary: Option<String>,
+    /// In order of appearance, every command followed by its flags and
+    /// redirects. Commands in substitutions follow the command they are in.
+    pub segments: Vec<Segment>,
+    /// The label of every segment's command, for the query.
+    #[serde(skip)]
+    labels: Vec<String>,
+}
+
+impl CommandBreakdown {
+    /// Splits `command` into its commands, flags and redirects.
+    pub fn new(command: &str) -> Result<Self, ParseError> {
+        let script = shell::parse(command)?;
+        let mut segments = vec![];
+        let mut labels = vec![];
+        for simple in script.commands() {
+            let index = segments.len();
+            let label = label(simple);
+            let mut push = |kind, span: Span| {
+                segments.push(Segment {
+                    kind,
+                    text: command[span.start..span.end].to_string(),
+                    start: span.start,
+                    end: span.end,
+                    command: index,
+                    explanation: None,
+                });
+                labels.push(label.clone());
+            };
+            push(SegmentKind::Command, simple.span);
+            for span in flags(simple) {
+                push(SegmentKind::Flag, span);
+            }
+            // A command that is nothing but redirects is explained as a whole
+            if simple.words.is_empty() {
+                continue;
+            }
+            for redirect in &simple.redirects {
+                push(SegmentKind::Redirect, redirect.span);
+            }
+        }
+
+        Ok(Self {
+            command: command.to_string(),
+            summary: None,
+            segments,
+            labels,
+        })
+    }
+
+    /// The query for the `explain` prompt template: the command followed by a
+    /// numbered list of its segments, which the answer explains in the same
+    /// order.
+    pub fn query(&self) -> String {
+        let mut query = format!("{}\n\n", self.command.trim());
+        for (i, segment) in self.segments.iter().enumerate() {
+            let text = segment.text.trim();
+            let label = &self.labels[i];
+            query += &match segment.kind {
+                SegmentKind::Command => format!("{}. `{text}`\n", i + 1),
+                _ if label.is_empty() => format!("{}. `{text}`\n", i + 1),
+                _ => format!("{}. `{text}` in `{label}`\n", i + 1),
+            };
+        }
+        query
+    }
+
+    /// Takes the summary and the explanations of the segments from an answer
+    /// to `query`: a summary, then one line per segment starting with its
+    /// number. Lines without a number continue the line before them.
+    pub fn apply_answer(&mut self, answer: &str) {
+        let mut summary: Vec<&str> = vec![];
+        let mut current: Option<usize> = None;
+        for line in answer.lines().map(str::trim) {
+            if line.is_empty() {
+                continue;
+            }
+            if let Some((number, text)) = numbered(line) {
+                current = number
+                    .checked_sub(1)
+                    .filter(|&index| index < self.segments.len());
+                if let Some(index) = current {
+                    let text = strip_echo(text, &self.segments[index].text);
+                    self.segments[index].explanation = Some(text.to_string());
+                }
+                continue;
+            }
+            match current {
+                Some(index) => {
+                    let explanation = self.segments[index]
+                        .explanation
+                        .get_or_insert_with(String::new);
+                    explanation.push(' ');
+                    explanation.push_str(line);
+                }
+                None if self.segments.iter().all(|s| s.explanation.is_none()) => summary.push(line),
+                // Lines after a number that is out of range are dropped
+                None => {}
+            }
+        }
+        self.summary = (!summary.is_empty()).then(|| summary.join(" "));
+    }
+}
+
+/// The program of a command with its subcommand if it has one, e.g. `git log`.
+/// Wrappers like `sudo` are left out.
+fn label(simple: &SimpleCommand) -> String {
+    let (words, _) = unwrap_command(&simple.words);
+    let Some(name) = words.first() else {
+        return String::new();
+    };
+    match words.get(1) {
+        Some(sub)
+            if SUBCOMMAND_PROGRAMS.contains(&program(name))
+                && !sub.text.starts_with('-')
+                && !sub.quoted =>
+        {
+            format!("{} {}", name.text, sub.text)
+        }
+        _ => name.text.clone(),
+    }
+}
+
+/// The spans of the options of a command, up to a `--` that ends them.
+fn flags(simple: &SimpleCommand) -> Vec<Span> {
+    let (words, _) = unwrap_command(&simple.words);
+    words
+        .iter()
+        .skip(1)
+        .take_while(|word| word.text != "--")
+        .filter(|word| !word.quoted && word.text.len() > 1 && word.text.starts_with('-'))
+        .map(|word| word.span)
+        .collect()
+}
+
+/// Splits `12. text` (or `12)`, `12:`) into the number and the text.
+fn numbered(line: &str) -> Option<(usize, &str)> {
+    let digits = line.find(|c: char| !c.is_ascii_digit())?;
+    let number = line[..digits].parse().ok()?;
+    let rest = line[digits..].strip_prefix(['.', ')', ':'])?;
+    Some((number, rest.trim()))
+}
+
+/// Removes the segment from the start of its explanation, which models tend to
+/// repeat, as in ``1. `ls -l`: lists files``.
+fn strip_echo<'a>(text: &'a str, segment: &str) -> &'a str {
+    let echoed = format!("`{}`", segment.trim());
+    match text.strip_prefix(&echoed) {
+        Some(rest) => rest.trim_start_matches([':', '-', ' ']).trim_start(),
+        None => text,
+    }
+}
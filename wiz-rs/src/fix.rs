@@ -0,0 +1,41 @@
+/// At most this many bytes from the end of the error output go into the
+/// prompt, which is where the actual error usually is.
+const MAX_STDERR: usize = 1500;
+
+/// A command that did not work, as reported by the user's shell.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct FailedCommand {
+    pub command: String,
+    /// `None` if unknown, e.g. when the command was taken from the history.
+    pub exit_code: Option<i32>,
+    /// What the command wrote to stderr, may be empty.
+    pub stderr: String,
+}
+
+impl FailedCommand {
+    /// The query asking the model for a corrected command. It is put into a
+    /// prompt template like any other query, so the answer has the usual form
+    /// of a command followed by an explanation.
+    pub fn correction_query(&self) -> String {
+        let mut query = match self.exit_code {
+            Some(code) => format!(
+                "This command failed with exit code {code}:\n{}\n",
+                self.command.trim()
+            ),
+            None => format!("This command failed:\n{}\n", self.command.trim()),
+        };
+
+        let stderr = self.stderr.trim();
+        if !stderr.is_empty() {
+            let mut start = stderr.len().saturating_sub(MAX_STDERR);
+            while !stderr.is_char_boundary(start) {
+                start += 1;
+            }
+            let cut = if start > 0 { "...\n" } else { "" };
+            query += &format!("It printed:\n{cut}{}\n", &stderr[start..]);
+        }
+
+        query + "Give the corrected command, then explain what was wrong with the original one."
+    }
+}
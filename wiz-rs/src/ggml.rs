@@ -36,6 +36,7 @@ impl Context {
                 no_alloc: false,
             })
         };
+        #[allow(clippy::arc_with_non_send_sync)]
         Self {
             ptr: Arc::new(NonNull::new(raw).expect("Should not be null")),
         }
@@ -270,7 +271,7 @@ impl Tensor {
 }
 impl Debug for Tensor {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        self.with_alive_ctx(|| return unsafe { (*self.ptr.as_ptr()).fmt(f) })
+        self.with_alive_ctx(|| unsafe { (*self.ptr.as_ptr()).fmt(f) })
     }
 }
 
@@ -0,0 +1,403 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::examples::Bm25;
+
+/// A command from a shell's history file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub command: String,
+    /// Seconds since the Unix epoch, if the shell recorded when the command
+    /// was run.
+    pub timestamp: Option<u64>,
+}
+
+/// The history file formats of the shells wiz knows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryFormat {
+    /// One command per line, preceded by a `#<timestamp>` line if
+    /// `HISTTIMEFORMAT` is set.
+    Bash,
+    /// One command per line, `: <timestamp>:<duration>;<command>` with
+    /// `EXTENDED_HISTORY`. Lines of multi-line commands end in a backslash.
+    Zsh,
+    /// YAML-like `- cmd: <command>` entries followed by `when: <timestamp>`.
+    Fish,
+}
+
+impl HistoryFormat {
+    /// The format of the shell named `shell`, e.g. `zsh` or `/bin/zsh`.
+    pub fn for_shell(shell: &str) -> Option<Self> {
+        match Path::new(shell).file_name()?.to_str()? {
+            "bash" | "sh" => Some(Self::Bash),
+            "zsh" => Some(Self::Zsh),
+            "fish" => Some(Self::Fish),
+            _ => None,
+        }
+    }
+
+    /// Where the shell keeps its history by default. `HISTFILE` is only
+    /// respected for bash and zsh, fish does not use it. It is set by the
+    /// shell wiz runs in, so it only counts if that is `$SHELL` and of this
+    /// format.
+    pub fn default_file(self, home: &Path) -> PathBuf {
+        let histfile = std::env::var_os("HISTFILE")
+            .filter(|_| {
+                std::env::var("SHELL")
+                    .ok()
+                    .and_then(|shell| Self::for_shell(&shell))
+                    == Some(self)
+            })
+            .map(PathBuf::from);
+        match self {
+            Self::Bash => histfile.unwrap_or_else(|| home.join(".bash_history")),
+            Self::Zsh => histfile.unwrap_or_else(|| home.join(".zsh_history")),
+            Self::Fish => {
+                let data = std::env::var_os("XDG_DATA_HOME")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| home.join(".local").join("share"));
+                data.join("fish").join("fish_history")
+            }
+        }
+    }
+
+    /// Parses the contents of a history file, oldest entry first.
+    pub fn parse(self, contents: &[u8]) -> Vec<HistoryEntry> {
+        match self {
+            Self::Bash => parse_bash(&String::from_utf8_lossy(contents)),
+            Self::Zsh => parse_zsh(&String::from_utf8_lossy(&unmetafy(contents))),
+            Self::Fish => parse_fish(&String::from_utf8_lossy(contents)),
+        }
+    }
+
+    /// Reads and parses a history file.
+    pub fn read(self, path: impl AsRef<Path>) -> std::io::Result<Vec<HistoryEntry>> {
+        Ok(self.parse(&std::fs::read(path)?))
+    }
+}
+
+/// A command in the `HistoryStore`, imported from one or more shells.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StoredCommand {
+    pub command: String,
+    /// When the command was last run, in seconds since the Unix epoch, if a
+    /// shell recorded it.
+    #[serde(default)]
+    pub last_run: Option<u64>,
+    /// The shells whose history contains the command, e.g. `zsh`.
+    #[serde(default)]
+    pub shells: Vec<String>,
+}
+
+/// A copy of the user's shell histories, stored as one JSON encoded
+/// `StoredCommand` per line. Every command is stored once, and keeps its
+/// position when it is imported again, so positions can serve as ids.
+pub struct HistoryStore {
+    path: PathBuf,
+    commands: Vec<StoredCommand>,
+    positions: HashMap<String, usize>,
+}
+
+impl HistoryStore {
+    /// Opens the store at `path`, empty if the file does not exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        let mut store = Self {
+            path,
+            commands: vec![],
+            positions: HashMap::new(),
+        };
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let command: StoredCommand = serde_json::from_str(line).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} line {}: {err}", store.path, number + 1),
+                )
+            })?;
+            store
+                .positions
+                .insert(command.command.clone(), store.commands.len());
+            store.commands.push(command);
+        }
+        Ok(store)
+    }
+
+    /// Every stored command, in the order they were first imported.
+    pub fn commands(&self) -> &[StoredCommand] {
+        &self.commands
+    }
+
+    /// Adds the entries of the history of `shell`, oldest first. Returns how
+    /// many of the commands were not stored before.
+    pub fn import(
+        &mut self,
+        shell: &str,
+        entries: impl IntoIterator<Item = HistoryEntry>,
+    ) -> usize {
+        let mut added = 0;
+        for entry in entries {
+            let command = entry.command.trim_end();
+            if command.trim().is_empty() {
+                continue;
+            }
+            let position = *self
+                .positions
+                .entry(command.to_string())
+                .or_insert_with(|| {
+                    added += 1;
+                    self.commands.push(StoredCommand {
+                        command: command.to_string(),
+                        last_run: None,
+                        shells: vec![],
+                    });
+                    self.commands.len() - 1
+                });
+
+            let stored = &mut self.commands[position];
+            stored.last_run = stored.last_run.max(entry.timestamp);
+            if !stored.shells.iter().any(|name| name == shell) {
+                stored.shells.push(shell.to_string());
+            }
+        }
+        added
+    }
+
+    /// Writes the store to its file, replacing it at once so that readers
+    /// never see a partially written store.
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let partial = self.path.with_extension("partial");
+        let mut file = io::BufWriter::new(std::fs::File::create(&partial)?);
+        for command in &self.commands {
+            serde_json::to_writer(&mut file, command)?;
+            file.write_all(b"\n")?;
+        }
+        file.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        std::fs::rename(&partial, &self.path)
+    }
+
+    /// The positions of the `k` commands most similar to `query`, a
+    /// description in words or part of the command, best first. Of equally
+    /// similar commands, the ones imported last come first.
+    pub fn search(&self, query: &str, k: usize) -> Vec<(usize, f32)> {
+        let last = self.commands.len().saturating_sub(1);
+        let ranking = Bm25::new(self.commands.iter().rev().map(|c| c.command.as_str()));
+        ranking
+            .top_k(query, k)
+            .into_iter()
+            .map(|(i, score)| (last - i, score))
+            .collect()
+    }
+}
+
+/// The query asking the model to change a command from the history so that
+/// it does something else. It is put into a prompt template like any other
+/// query.
+pub fn adaptation_query(command: &str, change: &str) -> String {
+    format!(
+        "I ran this command before:\n{}\nChange it to this: {}\nGive the new command, then explain \
+         what changed.",
+        command.trim(),
+        change.trim()
+    )
+}
+
+fn parse_bash(text: &str) -> Vec<HistoryEntry> {
+    let mut entries = vec![];
+    let mut timestamp = None;
+    for line in text.lines() {
+        if let Some(seconds) = line.strip_prefix('#').and_then(|s| s.parse().ok()) {
+            timestamp = Some(seconds);
+            continue;
+        }
+        if !line.trim().is_empty() {
+            entries.push(HistoryEntry {
+                command: line.to_string(),
+                timestamp: timestamp.take(),
+            });
+        }
+    }
+    entries
+}
+
+fn parse_zsh(text: &str) -> Vec<HistoryEntry> {
+    let mut entries: Vec<HistoryEntry> = vec![];
+    // Whether the last line ended in a backslash, continuing the entry
+    let mut continued = false;
+    for line in text.lines() {
+        let (line, continues) = match line.strip_suffix('\\') {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        match entries.last_mut() {
+            Some(entry) if continued => {
+                entry.command.push('\n');
+                entry.command.push_str(line);
+            }
+            _ => entries.push(parse_zsh_line(line)),
+        }
+        continued = continues;
+    }
+    entries.retain(|entry| !entry.command.trim().is_empty());
+    entries
+}
+
+/// Parses `: <timestamp>:<duration>;<command>`, or a plain command.
+fn parse_zsh_line(line: &str) -> HistoryEntry {
+    let extended = line.strip_prefix(": ").and_then(|rest| {
+        let (meta, command) = rest.split_once(';')?;
+        let (timestamp, _duration) = meta.split_once(':')?;
+        Some(HistoryEntry {
+            command: command.to_string(),
+            timestamp: Some(timestamp.trim().parse().ok()?),
+        })
+    });
+    extended.unwrap_or_else(|| HistoryEntry {
+        command: line.to_string(),
+        timestamp: None,
+    })
+}
+
+/// zsh stores bytes above 0x82 as 0x83 followed by the byte xor 32.
+fn unmetafy(bytes: &[u8]) -> Vec<u8> {
+    let mut unmetafied = Vec::with_capacity(bytes.len());
+    let mut bytes = bytes.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            0x83 => unmetafied.extend(bytes.next().map(|next| next ^ 32)),
+            byte => unmetafied.push(byte),
+        }
+    }
+    unmetafied
+}
+
+fn parse_fish(text: &str) -> Vec<HistoryEntry> {
+    let mut entries: Vec<HistoryEntry> = vec![];
+    for line in text.lines() {
+        if let Some(command) = line.strip_prefix("- cmd: ") {
+            entries.push(HistoryEntry {
+                command: unescape_fish(command),
+                timestamp: None,
+            });
+        } else if let Some(when) = line.trim_start().strip_prefix("when: ") {
+            if let Some(entry) = entries.last_mut() {
+                entry.timestamp = when.trim().parse().ok();
+            }
+        }
+    }
+    entries
+}
+
+/// fish escapes newlines as `\n` and backslashes as `\\`.
+fn unescape_fish(command: &str) -> String {
+    let mut unescaped = String::with_capacity(command.len());
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                unescaped.push('\\');
+                chars.next();
+            }
+            (c, _) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(command: &str, timestamp: Option<u64>) -> HistoryEntry {
+        HistoryEntry {
+            command: command.to_string(),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn bash_timestamps() {
+        let history = "#1700000000\nls -la\ncd /tmp\n\n#1700000100\ngit status\n";
+        assert_eq!(
+            HistoryFormat::Bash.parse(history.as_bytes()),
+            [
+                entry("ls -la", Some(1700000000)),
+                entry("cd /tmp", None),
+                entry("git status", Some(1700000100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn zsh_extended_and_multi_line() {
+        let history = ": 1700000000:0;ls -la\n\
+                       : 1700000005:3;for f in *; do\\\n  echo $f\\\ndone\n\
+                       plain command\n";
+        assert_eq!(
+            HistoryFormat::Zsh.parse(history.as_bytes()),
+            [
+                entry("ls -la", Some(1700000000)),
+                entry("for f in *; do\n  echo $f\ndone", Some(1700000005)),
+                entry("plain command", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn zsh_metafied() {
+        // `→` is E2 86 92, zsh writes the last two bytes as 0x83 and the byte
+        // xor 32
+        let mut history = b": 1700000000:0;echo ".to_vec();
+        history.extend([0xE2, 0x83, 0x86 ^ 32, 0x83, 0x92 ^ 32]);
+        history.extend(b" done\n");
+        assert_eq!(
+            HistoryFormat::Zsh.parse(&history),
+            [entry("echo → done", Some(1700000000))]
+        );
+    }
+
+    #[test]
+    fn fish_multi_line() {
+        let history = "- cmd: ls -la\n  when: 1700000000\n\
+                       - cmd: for f in *\\n  echo \\\\$f\\nend\n  when: 1700000005\n  paths:\n    - *\n\
+                       - cmd: pwd\n";
+        assert_eq!(
+            HistoryFormat::Fish.parse(history.as_bytes()),
+            [
+                entry("ls -la", Some(1700000000)),
+                entry("for f in *\n  echo \\$f\nend", Some(1700000005)),
+                entry("pwd", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn shells_by_path() {
+        assert_eq!(
+            HistoryFormat::for_shell("/bin/zsh"),
+            Some(HistoryFormat::Zsh)
+        );
+        assert_eq!(HistoryFormat::for_shell("bash"), Some(HistoryFormat::Bash));
+        assert_eq!(HistoryFormat::for_shell("/usr/bin/nu"), None);
+    }
+}
@@ -0,0 +1,239 @@
+use fs2::FileExt;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::examples::Bm25;
+
+/// An answer generated for a query. There is more than one if answers whose
+/// commands failed validation were generated again.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Candidate {
+    /// The text generated by the model.
+    pub answer: String,
+    /// The command parts of the answer.
+    pub commands: Vec<String>,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub prompt_ms: u64,
+    pub completion_ms: u64,
+}
+
+/// A query answered by `wiz-server`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct QueryRecord {
+    /// The id of the answer's event stream.
+    pub id: String,
+    /// Seconds since the Unix epoch when the query arrived.
+    pub time: u64,
+    /// What was asked for: `query`, `fix` or `turn` for a turn of a
+    /// conversation, or `cli` for an answer of the model `wiz-cli` runs
+    /// itself.
+    pub kind: String,
+    /// The text of the query, the failed command for `fix`.
+    pub query: String,
+    pub template: String,
+    pub candidates: Vec<Candidate>,
+    /// Why no complete answer was generated, if something went wrong.
+    pub error: Option<String>,
+    /// How long answering took in total, in milliseconds.
+    pub ms: u64,
+}
+
+/// Which command of an answer the user chose, reported by the client, and
+/// what happened when it ran.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Outcome {
+    /// The id of the answer, see `QueryRecord`.
+    pub id: String,
+    /// Seconds since the Unix epoch when the outcome was reported.
+    pub time: u64,
+    /// The chosen command, possibly edited by the user.
+    pub command: String,
+    /// `None` if the command was not run by the client, or was killed by a
+    /// signal.
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub duration_ms: Option<u64>,
+    /// The directory the command ran in.
+    pub cwd: Option<String>,
+    /// The end of what the command printed, stdout and stderr interleaved.
+    pub output: Option<String>,
+}
+
+/// A line of the journal file.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JournalEvent {
+    Query(QueryRecord),
+    Outcome(Outcome),
+}
+
+impl JournalEvent {
+    fn time(&self) -> u64 {
+        match self {
+            JournalEvent::Query(record) => record.time,
+            JournalEvent::Outcome(outcome) => outcome.time,
+        }
+    }
+}
+
+/// A query with the outcome reported for it, if any.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct JournalEntry {
+    #[serde(flatten)]
+    pub record: QueryRecord,
+    /// The last outcome reported for the query.
+    pub outcome: Option<Outcome>,
+}
+
+/// An append-only log of the queries answered by `wiz-server` and the
+/// outcomes reported for them, one JSON encoded `JournalEvent` per line.
+#[derive(Clone, Debug)]
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    /// The journal kept in the directory `dir`, usually `~/.wiz`.
+    pub fn in_dir(dir: impl AsRef<Path>) -> Self {
+        Self {
+            path: dir.as_ref().join("journal.jsonl"),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Locks `journal.lock` next to the journal exclusively until the
+    /// returned file is dropped. Appends and purges of every process take the
+    /// lock, so that a purge does not drop events appended while it rewrites
+    /// the journal.
+    fn lock(&self) -> io::Result<File> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.with_extension("lock"))?;
+        file.lock_exclusive()?;
+        Ok(file)
+    }
+
+    /// Adds an event to the end of the journal.
+    pub fn append(&self, event: &JournalEvent) -> io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let _lock = self.lock()?;
+        // A single write, so that readers never see a partial line of it
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)
+    }
+
+    /// Every event in the journal, oldest first. Lines that cannot be parsed,
+    /// e.g. ones written by a newer version, are skipped.
+    fn events(&self) -> io::Result<Vec<JournalEvent>> {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        Ok(text
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    /// Every query in the journal with its outcome, oldest first.
+    pub fn entries(&self) -> io::Result<Vec<JournalEntry>> {
+        let mut entries = vec![];
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        let mut outcomes = vec![];
+        for event in self.events()? {
+            match event {
+                JournalEvent::Query(record) => {
+                    positions.insert(record.id.clone(), entries.len());
+                    entries.push(JournalEntry {
+                        record,
+                        outcome: None,
+                    });
+                }
+                JournalEvent::Outcome(outcome) => outcomes.push(outcome),
+            }
+        }
+        // Outcomes of queries that are no longer in the journal are dropped
+        for outcome in outcomes {
+            if let Some(&position) = positions.get(&outcome.id) {
+                entries[position].outcome = Some(outcome);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The `k` entries whose queries and commands are most similar to
+    /// `query`, best first.
+    pub fn search(&self, query: &str, k: usize) -> io::Result<Vec<JournalEntry>> {
+        let mut entries = self.entries()?;
+        // Newest first, so that they win ties
+        entries.reverse();
+        let texts: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                let commands = entry.record.candidates.iter().flat_map(|c| &c.commands);
+                let chosen = entry.outcome.iter().map(|outcome| &outcome.command);
+                std::iter::once(&entry.record.query)
+                    .chain(commands)
+                    .chain(chosen)
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+            .collect();
+        let ranking = Bm25::new(texts.iter().map(String::as_str));
+        Ok(ranking
+            .top_k(query, k)
+            .into_iter()
+            .map(|(i, _)| entries[i].clone())
+            .collect())
+    }
+
+    /// Removes the events from before `before`, in seconds since the Unix
+    /// epoch, or all events if `None`. Returns how many queries were removed.
+    pub fn purge(&self, before: Option<u64>) -> io::Result<usize> {
+        let _lock = self.lock()?;
+        let events = self.events()?;
+        let (kept, removed): (Vec<JournalEvent>, Vec<JournalEvent>) = events
+            .into_iter()
+            .partition(|event| before.map_or(false, |before| event.time() >= before));
+        if removed.is_empty() {
+            return Ok(0);
+        }
+
+        let partial = self.path.with_extension("partial");
+        let mut file = io::BufWriter::new(File::create(&partial)?);
+        for event in &kept {
+            serde_json::to_writer(&mut file, event)?;
+            file.write_all(b"\n")?;
+        }
+        file.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        std::fs::rename(&partial, &self.path)?;
+        Ok(removed
+            .iter()
+            .filter(|event| matches!(event, JournalEvent::Query(_)))
+            .count())
+    }
+}
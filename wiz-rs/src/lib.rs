@@ -1,9 +1,47 @@
+mod answer;
+mod context;
+mod examples;
+mod explain;
+mod fix;
 mod ggml;
+mod history;
+mod journal;
+mod prefix_cache;
+mod response;
+mod safety;
+mod shell;
+mod snapshot;
+mod template;
+mod validate;
+
+pub use answer::{answer_parameters, CommandBlockBias};
+pub use context::{find_executable, ShellContext, KEY_BINARIES};
+pub use examples::{load_examples, parse_examples, Example, ExampleIndex};
+pub use explain::{CommandBreakdown, Segment, SegmentKind};
+pub use fix::FailedCommand;
+pub use history::{adaptation_query, HistoryEntry, HistoryFormat, HistoryStore, StoredCommand};
+pub use journal::{Candidate, Journal, JournalEntry, JournalEvent, Outcome, QueryRecord};
+pub use prefix_cache::PrefixCache;
+pub use response::{PartKind, ResponseEvent, ResponseParser, ResponsePart};
+pub use safety::{assess_risk, Risk, RiskLevel, RiskReason};
+pub use shell::{
+    parse as parse_shell, ParseError as ShellParseError, Pipeline, Redirect, RedirectKind, Script,
+    Separator, SimpleCommand, Span, Word,
+};
+pub use template::{PromptTemplate, PromptTemplates, TemplateError};
+pub use validate::{validation_feedback, CommandValidator, IssueKind, Validation, ValidationIssue};
+
+pub use snapshot::{
+    InferenceSnapshot, SnapshotCompression, SnapshotHeader, SNAPSHOT_FORMAT_VERSION,
+};
+
+use snapshot::{InferenceSnapshotRef, MemoryChunks};
 
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fmt::Display,
-    io::{BufRead, Read, Seek, SeekFrom},
+    fs::File,
+    io::{BufRead, BufWriter, Read, Seek, SeekFrom, Write},
     mem::size_of,
     path::{Path, PathBuf},
     str::FromStr,
@@ -17,14 +55,16 @@ use tokenizers::{models::unigram::Unigram, ModelWrapper, Tokenizer};
 
 pub const EOD_TOKEN_ID: TokenId = 1; // Hardcoded (for now?)
 
-#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(
+    Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
 pub struct Hyperparameters {
-    d_model: i32,
-    max_seq_len: i32,
-    n_heads: i32,
-    n_layers: i32,
-    n_vocab: i32,
-    ftype: ggml::Type,
+    pub d_model: i32,
+    pub max_seq_len: i32,
+    pub n_heads: i32,
+    pub n_layers: i32,
+    pub n_vocab: i32,
+    pub ftype: ggml::Type,
 }
 
 struct Layer {
@@ -59,6 +99,9 @@ pub struct Model {
     // Must be kept alive for the model
     _context: ggml::Context,
     tensors: HashMap<String, ggml::Tensor>,
+
+    /// See `Model::fingerprint`.
+    fingerprint: [u8; 32],
 }
 
 /// An inference session represents the state of the text generation. This holds
@@ -71,6 +114,10 @@ pub struct InferenceSession {
     // Parameters for the session.
     params: InferenceSessionParameters,
 
+    // Identity of the model that created the session, stored in snapshots.
+    model_fingerprint: [u8; 32],
+    hparams: Hyperparameters,
+
     memory_k: ggml::Tensor,
     memory_v: ggml::Tensor,
 
@@ -97,6 +144,61 @@ pub enum ModelKVMemoryType {
     Float32,
 }
 
+/// Where the positions of a K/V memory tensor live: every layer owns `n_ctx`
+/// consecutive rows of `n_embd` elements, filled from the start.
+struct MemoryLayout {
+    n_layer: usize,
+    layer_size: usize,
+    row_size: usize,
+}
+
+impl MemoryLayout {
+    fn new(hparams: &Hyperparameters, memory: &ggml::Tensor) -> Self {
+        let row_size = memory.element_size() * hparams.d_model as usize;
+        Self {
+            n_layer: hparams.n_layers as usize,
+            layer_size: row_size * hparams.max_seq_len as usize,
+            row_size,
+        }
+    }
+
+    /// Size of the first `n_past` positions of every layer.
+    fn prefix_size(&self, n_past: usize) -> usize {
+        self.n_layer * n_past * self.row_size
+    }
+
+    /// Borrows the first `n_past` positions of every layer of `memory`.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may write to the tensor while the chunks are alive.
+    unsafe fn read_prefix<'a>(&self, memory: &'a ggml::Tensor, n_past: usize) -> Vec<&'a [u8]> {
+        let data = std::slice::from_raw_parts(memory.data() as *const u8, memory.nbytes());
+        let len = n_past * self.row_size;
+        (0..self.n_layer)
+            .map(|il| &data[il * self.layer_size..il * self.layer_size + len])
+            .collect()
+    }
+
+    /// The inverse of `read_prefix`, with the chunks concatenated.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may access the tensor, and `prefix` must be exactly
+    /// `prefix_size(n_past)` bytes long.
+    unsafe fn write_prefix(&self, memory: &ggml::Tensor, n_past: usize, prefix: &[u8]) {
+        let data = memory.data() as *mut u8;
+        let len = n_past * self.row_size;
+        for il in 0..self.n_layer {
+            std::ptr::copy_nonoverlapping(
+                prefix[il * len..].as_ptr(),
+                data.add(il * self.layer_size),
+                len,
+            );
+        }
+    }
+}
+
 impl From<ModelKVMemoryType> for i32 {
     fn from(value: ModelKVMemoryType) -> Self {
         match value {
@@ -130,6 +232,8 @@ pub struct InferenceParameters {
     pub n_batch: usize,
     pub top_k: usize,
     pub top_p: f32,
+    /// Divides the logits of the tokens in the session's last n tokens, `1.0`
+    /// leaves them as they are.
     pub repeat_penalty: f32,
     pub temp: f32,
     pub bias_tokens: Box<dyn TokenBias>,
@@ -142,7 +246,7 @@ impl Default for InferenceParameters {
             n_batch: 1,
             top_k: 1,
             top_p: 0.95,
-            repeat_penalty: 0.0,
+            repeat_penalty: 1.0,
             temp: 0.1,
             bias_tokens: Box::new(ConstantTokenBias::default()),
         }
@@ -181,47 +285,13 @@ impl Display for InferenceStats {
     }
 }
 
-type TokenId = u32;
-
-#[derive(serde::Serialize)]
-/// A serializable snapshot of the inference process. Can be saved to disk.
-/// Useful for prompt caching.
-pub struct InferenceSnapshotRef<'a> {
-    /// How many tokens have been stored in the memory so far.
-    pub npast: usize,
-    // Parameters associated with the saved inference session.
-    pub session_params: InferenceSessionParameters,
-    /// The contents of the 'key' memory tensor
-    pub memory_k: &'a [u8],
-    /// The contents of the 'value' memory tensor
-    pub memory_v: &'a [u8],
-    /// The last n tokens that were predicted during generation
-    pub last_n_tokens: VecDeque<TokenId>,
-    /// The vector of logits that was produced after the last inference
-    pub logits: Vec<f32>,
-}
-
-/// A serializable snapshot of the inference process. Can be restored by calling
-/// `Model::restore_from_snapshot`. Useful for prompt caching.
-#[derive(serde::Deserialize, Clone)]
-pub struct InferenceSnapshot {
-    /// How many tokens have been stored in the memory so far.
-    pub npast: usize,
-    // Parameters associated with the saved inference session.
-    pub session_params: InferenceSessionParameters,
-    /// The contents of the 'key' memory tensor
-    pub memory_k: Vec<u8>,
-    /// The contents of the 'value' memory tensor
-    pub memory_v: Vec<u8>,
-    /// The last n tokens that were predicted during generation
-    pub last_n_tokens: VecDeque<TokenId>,
-    /// The vector of logits that was produced after the last inference
-    pub last_logits: Vec<f32>,
-}
+pub type TokenId = u32;
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum OutputToken {
-    Token(String, bool),
+    /// The decoded text, whether the token was generated by the model (as
+    /// opposed to being part of the prompt), and the token id.
+    Token(String, bool, TokenId),
     EndOfText,
 }
 impl Display for OutputToken {
@@ -230,7 +300,7 @@ impl Display for OutputToken {
             f,
             "{}",
             match self {
-                OutputToken::Token(t, _) => t,
+                OutputToken::Token(t, _, _) => t,
                 OutputToken::EndOfText => "",
             }
         )
@@ -371,6 +441,14 @@ pub enum SnapshotError {
     Serialization(#[from] bincode::Error),
     #[error("could not write memory due to size mismatch (self={self_size}, input={input_size})")]
     MemorySizeMismatch { self_size: usize, input_size: usize },
+    #[error("unsupported snapshot format version {version}")]
+    UnsupportedVersion { version: u32 },
+    #[error("the snapshot header is invalid or does not match the snapshot contents")]
+    InvalidHeader,
+    #[error("the snapshot checksum does not match, the file is corrupted")]
+    ChecksumMismatch,
+    #[error("the snapshot was created with a different model")]
+    ModelMismatch,
 }
 
 #[derive(Error, Debug)]
@@ -613,6 +691,7 @@ impl Model {
                 layers,
                 tensors,
                 _context: context,
+                fingerprint: [0; 32],
             }
         };
 
@@ -685,10 +764,12 @@ impl Model {
 
                 let tensor_name = read_string(&mut part_reader, length as usize)?;
 
-                let Some(tensor) = model.tensors.get(&tensor_name)
-                    else {
-                        return Err(LoadError::UnknownTensor { tensor_name, path: part_path });
-                    };
+                let Some(tensor) = model.tensors.get(&tensor_name) else {
+                    return Err(LoadError::UnknownTensor {
+                        tensor_name,
+                        path: part_path,
+                    });
+                };
 
                 // split_type = 0: split by columns
                 // split_type = 1: split by rows
@@ -863,9 +944,50 @@ impl Model {
             });
         }
 
+        let mut model = model;
+        model.fingerprint = model.compute_fingerprint();
+
         Ok((model, vocab))
     }
 
+    /// Hashes the hyperparameters and the first and last bytes of every
+    /// tensor. Cheap to compute, but distinguishes models well enough to tell
+    /// which one a snapshot belongs to.
+    fn compute_fingerprint(&self) -> [u8; 32] {
+        use sha2::{Digest, Sha256};
+
+        const SAMPLE_SIZE: usize = 4096;
+
+        let mut hasher = Sha256::new();
+        hasher.update(bincode::serialize(&self.hparams).unwrap());
+
+        let mut names: Vec<&String> = self.tensors.keys().collect();
+        names.sort();
+        for name in names {
+            let tensor = &self.tensors[name];
+            // SAFETY: The tensor data is owned by the model context, which is
+            // alive for as long as `self`.
+            let data =
+                unsafe { std::slice::from_raw_parts(tensor.data() as *const u8, tensor.nbytes()) };
+            hasher.update(name.as_bytes());
+            hasher.update(&data[..data.len().min(SAMPLE_SIZE)]);
+            hasher.update(&data[data.len().saturating_sub(SAMPLE_SIZE)..]);
+        }
+
+        hasher.finalize().into()
+    }
+
+    /// Identifies the loaded model weights. Stored in snapshots, so they are
+    /// not restored into a different model.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        self.fingerprint
+    }
+
+    /// The hyperparameters the model was loaded with.
+    pub fn hyperparameters(&self) -> &Hyperparameters {
+        &self.hparams
+    }
+
     /// Starts a new `InferenceSession` for this model.
     pub fn start_session(&self, params: InferenceSessionParameters) -> InferenceSession {
         let Hyperparameters {
@@ -906,6 +1028,8 @@ impl Model {
         InferenceSession {
             _session_ctx: session_ctx,
             params,
+            model_fingerprint: self.fingerprint,
+            hparams: self.hparams.clone(),
             memory_k,
             memory_v,
             n_past: 0,
@@ -919,7 +1043,7 @@ impl Model {
         &self,
         session: &InferenceSession,
         params: &InferenceParameters,
-        _rng: &mut impl rand::Rng,
+        rng: &mut impl rand::Rng,
     ) -> TokenId {
         let logits = &session.last_logits;
         let n_logits = logits.len();
@@ -929,9 +1053,6 @@ impl Model {
             let scale = 1.0 / params.temp;
             for (i, &logit) in logits.iter().enumerate() {
                 let tid = i as TokenId;
-
-                // repetition penalty from CTRL paper (https://arxiv.org/abs/1909.05858)
-                // credit https://github.com/facebookresearch/llama/compare/main...shawwn:llama:main
                 let val = logit * scale;
 
                 if let Some(bias) = params.bias_tokens.get(tid) {
@@ -945,16 +1066,64 @@ impl Model {
                 }
             }
         }
+        // repetition penalty from CTRL paper (https://arxiv.org/abs/1909.05858)
+        // credit https://github.com/facebookresearch/llama/compare/main...shawwn:llama:main
+        if params.repeat_penalty != 1.0 {
+            let repeated: HashSet<TokenId> = session.last_n_tokens.iter().copied().collect();
+            for tid in repeated {
+                if let Some((logit, _)) = logits_id.get_mut(tid as usize) {
+                    // Dividing a negative logit would make the token more likely
+                    if *logit < 0.0 {
+                        *logit *= params.repeat_penalty;
+                    } else {
+                        *logit /= params.repeat_penalty;
+                    }
+                }
+            }
+        }
+
         // find the top K tokens
-        logits_id.partial_sort(params.top_k, |a, b| {
+        let top_k = params.top_k.clamp(1, n_logits);
+        logits_id.partial_sort(top_k, |a, b| {
             // Sort descending
             b.0.total_cmp(&a.0)
         });
-        logits_id.truncate(params.top_k);
+        logits_id.truncate(top_k);
+
+        if top_k == 1 {
+            return logits_id[0].1;
+        }
 
-        //println!("top_k: {:?}", logits_id);
+        // softmax over the remaining candidates, then keep the smallest set
+        // whose cumulative probability exceeds top_p
+        let max_logit = logits_id[0].0;
+        let mut probs: Vec<f32> = logits_id
+            .iter()
+            .map(|(logit, _)| (logit - max_logit).exp())
+            .collect();
+        let sum: f32 = probs.iter().sum();
+        probs.iter_mut().for_each(|p| *p /= sum);
+
+        if params.top_p < 1.0 {
+            let mut cumsum = 0.0;
+            let mut keep = probs.len();
+            for (i, p) in probs.iter().enumerate() {
+                cumsum += p;
+                if cumsum >= params.top_p {
+                    keep = i + 1;
+                    break;
+                }
+            }
+            probs.truncate(keep);
+            logits_id.truncate(keep);
+        }
 
-        logits_id[0].1
+        // A temperature close to zero scales the logits to infinity, which
+        // leaves no valid weights. Sampling is greedy then anyway.
+        match rand::distributions::WeightedIndex::new(&probs) {
+            Ok(dist) => logits_id[rng.sample(dist)].1,
+            Err(_) => logits_id[0].1,
+        }
     }
 
     /// Evaluates the transformer.
@@ -1020,14 +1189,14 @@ impl Model {
                     n_embd,
                     n.try_into().unwrap(),
                     current.get_nb()[1],
-                    0 * size_of::<f32>() * (n_embd as usize),
+                    0,
                 );
                 let k_current = ctx0.op_view_2d(
                     &current,
                     n_embd,
                     n.try_into().unwrap(),
                     current.get_nb()[1],
-                    1 * size_of::<f32>() * (n_embd as usize),
+                    size_of::<f32>() * (n_embd as usize),
                 );
                 let v_current = ctx0.op_view_2d(
                     &current,
@@ -1227,40 +1396,94 @@ impl Model {
 
     /// Sets the state of the model, from a previously obtained InferenceSnapshot
     pub fn session_from_snapshot(
-        &mut self,
-        snapshot: InferenceSnapshot,
+        &self,
+        snapshot: &InferenceSnapshot,
     ) -> Result<InferenceSession, SnapshotError> {
+        // Snapshots in the original layout have no header, those can only be
+        // checked by their memory size below.
+        if let Some(header) = &snapshot.header {
+            if header.model_hash != self.fingerprint || header.hyperparameters != self.hparams {
+                return Err(SnapshotError::ModelMismatch);
+            }
+        }
+        if snapshot.npast >= self.hparams.max_seq_len as usize
+            || snapshot.last_logits.len() != self.hparams.n_vocab as usize
+        {
+            return Err(SnapshotError::InvalidHeader);
+        }
+
         let mut session = self.start_session(InferenceSessionParameters {
             last_n_size: snapshot.last_n_tokens.len(),
             ..snapshot.session_params
         });
 
-        if session.memory_k.nbytes() != snapshot.memory_k.len()
-            || session.memory_v.nbytes() != snapshot.memory_v.len()
-        {
-            return Err(SnapshotError::MemorySizeMismatch {
-                self_size: session.memory_k.nbytes() + session.memory_v.nbytes(),
-                input_size: snapshot.memory_k.len() + snapshot.memory_v.len(),
-            });
-        }
-
-        // SAFETY: We have exclusive access to Session, which means no one else
-        // should be touching the context's memory. We can write to it because
-        // we already checked the size.
-        unsafe {
-            session.memory_k.write_data(&snapshot.memory_k);
-            session.memory_v.write_data(&snapshot.memory_v);
+        // Current snapshots only contain the used positions of every layer,
+        // older ones the whole memory tensors.
+        for (memory, data) in [
+            (&session.memory_k, &snapshot.memory_k),
+            (&session.memory_v, &snapshot.memory_v),
+        ] {
+            let layout = MemoryLayout::new(&self.hparams, memory);
+            // SAFETY: We have exclusive access to Session, which means no one
+            // else should be touching the context's memory. We can write to it
+            // because we checked the size.
+            unsafe {
+                if data.len() == memory.nbytes() {
+                    memory.write_data(data);
+                } else if data.len() == layout.prefix_size(snapshot.npast) {
+                    layout.write_prefix(memory, snapshot.npast, data);
+                } else {
+                    return Err(SnapshotError::MemorySizeMismatch {
+                        self_size: layout.prefix_size(snapshot.npast),
+                        input_size: data.len(),
+                    });
+                }
+            }
         }
 
         session.n_past = snapshot.npast;
-        session.last_n_tokens = snapshot.last_n_tokens;
-        session.last_logits = snapshot.last_logits;
+        session.last_n_tokens = snapshot.last_n_tokens.clone();
+        session.last_logits = snapshot.last_logits.clone();
 
         Ok(session)
     }
 }
 
 impl InferenceSession {
+    /// How many tokens have been fed into the model's working memory so far.
+    pub fn n_past(&self) -> usize {
+        self.n_past
+    }
+
+    /// Forgets everything after the first `n_past` tokens. The logits are not
+    /// recomputed, so at least one token has to be fed before sampling again.
+    fn truncate(&mut self, n_past: usize) {
+        for _ in n_past..self.n_past {
+            self.last_n_tokens.pop_front();
+        }
+        self.n_past = self.n_past.min(n_past);
+    }
+
+    /// Log-probabilities for the next token, computed from the logits of the
+    /// last evaluation. Indexed by token id.
+    pub fn next_token_logprobs(&self) -> Vec<f32> {
+        let max_logit = self
+            .last_logits
+            .iter()
+            .copied()
+            .fold(f32::NEG_INFINITY, f32::max);
+        let log_sum = self
+            .last_logits
+            .iter()
+            .map(|logit| (logit - max_logit).exp())
+            .sum::<f32>()
+            .ln();
+        self.last_logits
+            .iter()
+            .map(|logit| logit - max_logit - log_sum)
+            .collect()
+    }
+
     pub fn feed_prompt<E: std::error::Error + 'static>(
         &mut self,
         model: &Model,
@@ -1272,6 +1495,18 @@ impl InferenceSession {
         let beginning_of_sentence = self.n_past == 0;
         let prompt_tokens = model.tokenize(tokenizer, prompt, beginning_of_sentence)?;
 
+        self.feed_tokens(model, tokenizer, params, &prompt_tokens, callback)
+    }
+
+    /// Same as `feed_prompt`, for a prompt that is already tokenized.
+    pub fn feed_tokens<E: std::error::Error + 'static>(
+        &mut self,
+        model: &Model,
+        tokenizer: &Tokenizer,
+        params: &InferenceParameters,
+        prompt_tokens: &[TokenId],
+        callback: impl Fn(OutputToken) -> Result<(), E>,
+    ) -> Result<(), InferenceError> {
         if self.n_past + prompt_tokens.len() >= model.hparams.max_seq_len as usize {
             return Err(InferenceError::ContextFull);
         }
@@ -1284,6 +1519,7 @@ impl InferenceSession {
                 if let Err(e) = callback(OutputToken::Token(
                     tokenizer.decode(vec![tk], true).unwrap(),
                     false,
+                    tk,
                 )) {
                     return Err(InferenceError::UserCallback(Box::new(e)));
                 }
@@ -1323,6 +1559,7 @@ impl InferenceSession {
             OutputToken::Token(
                 tokenizer.decode(vec![next_token], true).unwrap().to_owned(),
                 true,
+                next_token,
             )
         })
     }
@@ -1338,6 +1575,36 @@ impl InferenceSession {
         maximum_token_count: Option<usize>,
         rng: &mut impl rand::Rng,
         callback: impl Fn(OutputToken) -> Result<(), E>,
+    ) -> Result<InferenceStats, InferenceError> {
+        let prompt_tokens = if prompt.is_empty() {
+            vec![]
+        } else {
+            model.tokenize(tokenizer, prompt, self.n_past == 0)?
+        };
+
+        self.inference_with_tokens(
+            model,
+            tokenizer,
+            params,
+            &prompt_tokens,
+            maximum_token_count,
+            rng,
+            callback,
+        )
+    }
+
+    /// Same as `inference_with_prompt`, for a prompt that is already
+    /// tokenized.
+    #[allow(clippy::too_many_arguments)]
+    pub fn inference_with_tokens<E: std::error::Error + 'static>(
+        &mut self,
+        model: &Model,
+        tokenizer: &Tokenizer,
+        params: &InferenceParameters,
+        prompt_tokens: &[TokenId],
+        maximum_token_count: Option<usize>,
+        rng: &mut impl rand::Rng,
+        callback: impl Fn(OutputToken) -> Result<(), E>,
     ) -> Result<InferenceStats, InferenceError> {
         let mut stats = InferenceStats::default();
 
@@ -1345,8 +1612,8 @@ impl InferenceSession {
 
         // Feed the initial prompt through the transformer, to update its
         // context window with new data.
-        if prompt.len() > 0 {
-            self.feed_prompt(model, tokenizer, params, prompt, |tk| callback(tk))?;
+        if !prompt_tokens.is_empty() {
+            self.feed_tokens(model, tokenizer, params, prompt_tokens, &callback)?;
         }
         stats.feed_prompt_duration = start_at.elapsed().unwrap();
         stats.prompt_tokens = self.n_past;
@@ -1369,7 +1636,7 @@ impl InferenceSession {
             tokens_processed += 1;
 
             match tk {
-                OutputToken::Token(_, _) => {}
+                OutputToken::Token(..) => {}
                 OutputToken::EndOfText => break,
             }
         }
@@ -1379,62 +1646,72 @@ impl InferenceSession {
         Ok(stats)
     }
 
-    /// Obtains a serializable snapshot of the current inference status. This
-    /// can be used to cache the state of the model and store them into a file.
-    ///
-    /// # Safety
-    ///
-    /// This function provides raw access to the underlying memory owned by the
-    /// ggml context. While the provided `InferenceSnapshotRef` object is alive,
-    /// no other methods for this model object should be called.
-    pub unsafe fn get_snapshot(&mut self) -> InferenceSnapshotRef<'_> {
-        use core::slice;
-        let memory_k = unsafe {
-            slice::from_raw_parts(self.memory_k.data() as *mut u8, self.memory_k.nbytes())
-        };
-        let memory_v = unsafe {
-            slice::from_raw_parts(self.memory_v.data() as *mut u8, self.memory_v.nbytes())
+    /// Borrows the state of the session for writing a snapshot.
+    fn snapshot_ref(&self, compression: SnapshotCompression) -> InferenceSnapshotRef<'_> {
+        let k_layout = MemoryLayout::new(&self.hparams, &self.memory_k);
+        let v_layout = MemoryLayout::new(&self.hparams, &self.memory_v);
+        // SAFETY: The memory tensors are only written while evaluating the
+        // model, which requires `&mut self`. The returned value borrows `self`,
+        // so the borrow checker rules that out while it is alive.
+        // Only the positions up to `n_past` are in use, the rest of the memory
+        // is overwritten before it is read again.
+        let (memory_k, memory_v) = unsafe {
+            (
+                k_layout.read_prefix(&self.memory_k, self.n_past),
+                v_layout.read_prefix(&self.memory_v, self.n_past),
+            )
         };
 
         InferenceSnapshotRef {
+            header: SnapshotHeader {
+                model_hash: self.model_fingerprint,
+                hyperparameters: self.hparams.clone(),
+                memory_k_type: self.params.memory_k_type,
+                memory_v_type: self.params.memory_v_type,
+                n_past: self.n_past,
+                compression,
+            },
             npast: self.n_past,
             session_params: self.params,
-            memory_k,
-            memory_v,
-            last_n_tokens: self.last_n_tokens.clone(),
-            logits: self.last_logits.clone(),
+            memory_k: MemoryChunks(memory_k),
+            memory_v: MemoryChunks(memory_v),
+            last_n_tokens: &self.last_n_tokens,
+            logits: &self.last_logits,
         }
     }
-}
-
-impl<'a> InferenceSnapshotRef<'a> {
-    pub fn write(&self, writer: &mut impl std::io::Write) -> Result<(), SnapshotError> {
-        Ok(bincode::serialize_into(writer, &self)?)
-    }
 
-    pub fn write_to_disk(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
-        use std::fs::File;
-        use std::io::BufWriter;
-
-        let path = path.as_ref();
-        let mut writer = BufWriter::new(File::create(path)?);
-
-        self.write(&mut writer)
+    /// Copies the current inference state into a snapshot, which can be
+    /// restored with `Model::session_from_snapshot` or saved to a file.
+    pub fn snapshot(&self) -> InferenceSnapshot {
+        let snapshot = self.snapshot_ref(SnapshotCompression::None);
+        InferenceSnapshot {
+            header: Some(snapshot.header),
+            npast: snapshot.npast,
+            session_params: snapshot.session_params,
+            memory_k: snapshot.memory_k.0.concat(),
+            memory_v: snapshot.memory_v.0.concat(),
+            last_n_tokens: snapshot.last_n_tokens.clone(),
+            last_logits: snapshot.logits.to_vec(),
+        }
     }
-}
 
-impl InferenceSnapshot {
-    pub fn read(reader: &mut impl std::io::Read) -> Result<Self, SnapshotError> {
-        Ok(bincode::deserialize_from(reader)?)
+    /// Writes a snapshot of the current inference state, without copying the
+    /// model memory first. This can be used to cache the state of the model
+    /// in a file.
+    pub fn write_snapshot(
+        &self,
+        writer: impl Write,
+        compression: SnapshotCompression,
+    ) -> Result<(), SnapshotError> {
+        self.snapshot_ref(compression).write(writer)
     }
 
-    pub fn load_from_disk(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
-        use std::fs::File;
-        use std::io::BufReader;
-
-        let path = path.as_ref();
-        let mut reader = BufReader::new(File::open(path)?);
-
-        Self::read(&mut reader)
+    pub fn write_snapshot_to_disk(
+        &self,
+        path: impl AsRef<Path>,
+        compression: SnapshotCompression,
+    ) -> Result<(), SnapshotError> {
+        let writer = BufWriter::new(File::create(path)?);
+        self.write_snapshot(writer, compression)
     }
 }
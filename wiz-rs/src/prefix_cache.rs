@@ -0,0 +1,317 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{InferenceSession, InferenceSnapshot, Model, SnapshotError, TokenId};
+
+/// Keeps snapshots of sessions in memory, in a trie keyed by the tokens that
+/// were fed into them. New sessions start from the longest cached prefix of
+/// their prompt, so shared prompt prefixes and the earlier turns of a
+/// conversation are only evaluated once. Least recently used snapshots are
+/// dropped once the cache grows beyond `max_bytes`.
+pub struct PrefixCache {
+    trie: PrefixTrie<InferenceSnapshot>,
+}
+
+/// The bookkeeping of `PrefixCache`, independent of what is stored.
+struct PrefixTrie<T> {
+    root: Node<T>,
+    max_bytes: usize,
+    total_bytes: usize,
+    /// Incremented on every access, so entries can be ordered by their use.
+    clock: u64,
+    /// The path of every entry, by when it was last used.
+    lru: BTreeMap<u64, Vec<TokenId>>,
+}
+
+struct Node<T> {
+    children: HashMap<TokenId, Node<T>>,
+    entry: Option<Entry<T>>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self {
+            children: HashMap::new(),
+            entry: None,
+        }
+    }
+}
+
+struct Entry<T> {
+    value: T,
+    size: usize,
+    last_used: u64,
+}
+
+impl<T> Node<T> {
+    /// The path to the shallowest entry below this node, including this one.
+    fn nearest_entry(&self) -> Option<Vec<TokenId>> {
+        if self.entry.is_some() {
+            return Some(vec![]);
+        }
+        self.children
+            .iter()
+            .filter_map(|(&token, child)| {
+                let mut path = child.nearest_entry()?;
+                path.insert(0, token);
+                Some(path)
+            })
+            .min_by_key(|path| path.len())
+    }
+
+    /// Removes the entry at `path`, along with the nodes that become empty.
+    fn remove(&mut self, path: &[TokenId]) -> Option<Entry<T>> {
+        let Some((token, rest)) = path.split_first() else {
+            return self.entry.take();
+        };
+        let child = self.children.get_mut(token)?;
+        let entry = child.remove(rest);
+        if child.entry.is_none() && child.children.is_empty() {
+            self.children.remove(token);
+        }
+        entry
+    }
+
+    fn get(&self, path: &[TokenId]) -> Option<&Node<T>> {
+        match path.split_first() {
+            Some((token, rest)) => self.children.get(token)?.get(rest),
+            None => Some(self),
+        }
+    }
+
+    fn get_mut(&mut self, path: &[TokenId]) -> Option<&mut Node<T>> {
+        match path.split_first() {
+            Some((token, rest)) => self.children.get_mut(token)?.get_mut(rest),
+            None => Some(self),
+        }
+    }
+}
+
+impl<T> PrefixTrie<T> {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            root: Node::default(),
+            max_bytes,
+            total_bytes: 0,
+            clock: 0,
+            lru: BTreeMap::new(),
+        }
+    }
+
+    /// Stores `value` under `tokens`, replacing what was stored there. Values
+    /// larger than the whole budget are not stored.
+    fn insert(&mut self, tokens: &[TokenId], value: T, size: usize) {
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut node = &mut self.root;
+        for token in tokens {
+            node = node.children.entry(*token).or_default();
+        }
+        self.clock += 1;
+        let old = node.entry.replace(Entry {
+            value,
+            size,
+            last_used: self.clock,
+        });
+        if let Some(old) = &old {
+            self.lru.remove(&old.last_used);
+        }
+        self.lru.insert(self.clock, tokens.to_vec());
+        self.total_bytes += size;
+        self.total_bytes -= old.map_or(0, |old| old.size);
+
+        self.evict();
+    }
+
+    /// Finds the entry sharing the longest prefix with `tokens` and marks it
+    /// as used. Returns it with the length of the shared prefix.
+    fn longest_prefix(&mut self, tokens: &[TokenId]) -> Option<(&T, usize)> {
+        // Follow the tokens as far as the trie goes
+        let mut node = &self.root;
+        let mut depth = 0;
+        while let Some(child) = tokens.get(depth).and_then(|t| node.children.get(t)) {
+            node = child;
+            depth += 1;
+        }
+
+        // Every entry below the matched node contains the matched tokens. A
+        // deeper one can be truncated, but its logits belong to a later
+        // token, so it only helps if there is something left to feed.
+        let path = if node.entry.is_some() {
+            tokens[..depth].to_vec()
+        } else if depth > 0 && depth < tokens.len() {
+            let mut path = tokens[..depth].to_vec();
+            path.extend(
+                node.nearest_entry()
+                    .expect("nodes without entries are removed"),
+            );
+            path
+        } else if depth > 1 {
+            depth -= 1;
+            let mut path = tokens[..depth].to_vec();
+            path.extend(self.root.get(&path).unwrap().nearest_entry().unwrap());
+            path
+        } else {
+            return None;
+        };
+
+        self.clock += 1;
+        let entry = self
+            .root
+            .get_mut(&path)
+            .and_then(|node| node.entry.as_mut())
+            .unwrap();
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(self.clock, path);
+        entry.last_used = self.clock;
+
+        Some((&entry.value, depth))
+    }
+
+    /// Drops the least recently used entries until the trie fits into its
+    /// memory budget.
+    fn evict(&mut self) {
+        while self.total_bytes > self.max_bytes {
+            let Some(&last_used) = self.lru.keys().next() else {
+                break;
+            };
+            let path = self.lru.remove(&last_used).unwrap();
+            if let Some(entry) = self.root.remove(&path) {
+                self.total_bytes -= entry.size;
+            }
+        }
+    }
+}
+
+impl PrefixCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            trie: PrefixTrie::new(max_bytes),
+        }
+    }
+
+    /// Memory used by the cached snapshots, in bytes.
+    pub fn total_bytes(&self) -> usize {
+        self.trie.total_bytes
+    }
+
+    /// Stores the state of `session`, which must have been fed exactly
+    /// `tokens`. Nothing is stored otherwise.
+    pub fn insert(&mut self, tokens: &[TokenId], session: &InferenceSession) {
+        if session.n_past() != tokens.len() {
+            log::warn!(
+                "Not caching a session of {} tokens under a prefix of {} tokens",
+                session.n_past(),
+                tokens.len()
+            );
+            return;
+        }
+        if tokens.is_empty() {
+            return;
+        }
+
+        let snapshot = session.snapshot();
+        let size = snapshot.memory_k.len()
+            + snapshot.memory_v.len()
+            + (snapshot.last_logits.len() + snapshot.last_n_tokens.len()) * 4;
+        self.trie.insert(tokens, snapshot, size);
+    }
+
+    /// Starts a session from the longest cached prefix of `tokens`. Returns
+    /// the session and how many of the tokens are already in its memory, the
+    /// rest still has to be fed. If not all tokens are cached, the session
+    /// must be fed before sampling from it.
+    pub fn start_session(
+        &mut self,
+        model: &Model,
+        tokens: &[TokenId],
+    ) -> Result<Option<(InferenceSession, usize)>, SnapshotError> {
+        let Some((snapshot, depth)) = self.trie.longest_prefix(tokens) else {
+            return Ok(None);
+        };
+        let mut session = model.session_from_snapshot(snapshot)?;
+        session.truncate(depth);
+        Ok(Some((session, depth)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(
+        trie: &mut PrefixTrie<&'static str>,
+        tokens: &[TokenId],
+    ) -> Option<(&'static str, usize)> {
+        trie.longest_prefix(tokens)
+            .map(|(value, depth)| (*value, depth))
+    }
+
+    #[test]
+    fn longest_prefix() {
+        let mut trie = PrefixTrie::new(100);
+        trie.insert(&[1, 2], "12", 1);
+        trie.insert(&[1, 2, 3, 4], "1234", 1);
+
+        assert_eq!(lookup(&mut trie, &[1, 2, 3, 4, 5]), Some(("1234", 4)));
+        assert_eq!(lookup(&mut trie, &[1, 2, 3, 4]), Some(("1234", 4)));
+        assert_eq!(lookup(&mut trie, &[1, 2, 3, 9]), Some(("1234", 3)));
+        assert_eq!(lookup(&mut trie, &[1, 2, 9]), Some(("12", 2)));
+        assert_eq!(lookup(&mut trie, &[9]), None);
+        assert_eq!(lookup(&mut trie, &[]), None);
+    }
+
+    #[test]
+    fn deeper_entries_need_a_token_left_to_feed() {
+        let mut trie = PrefixTrie::new(100);
+        trie.insert(&[1, 2, 3], "123", 1);
+
+        // All of [1, 2] is matched, but the entry is one token further
+        assert_eq!(lookup(&mut trie, &[1, 2]), Some(("123", 1)));
+        assert_eq!(lookup(&mut trie, &[1]), None);
+    }
+
+    #[test]
+    fn insert_replaces_entries() {
+        let mut trie = PrefixTrie::new(100);
+        trie.insert(&[1, 2], "old", 10);
+        trie.insert(&[1, 2], "new", 4);
+
+        assert_eq!(trie.total_bytes, 4);
+        assert_eq!(trie.lru.len(), 1);
+        assert_eq!(lookup(&mut trie, &[1, 2]), Some(("new", 2)));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut trie = PrefixTrie::new(3);
+        trie.insert(&[1], "1", 1);
+        trie.insert(&[2], "2", 1);
+        trie.insert(&[3], "3", 1);
+        lookup(&mut trie, &[1, 5]);
+
+        trie.insert(&[4], "4", 1);
+        assert_eq!(trie.total_bytes, 3);
+        assert_eq!(lookup(&mut trie, &[2, 5]), None);
+        assert_eq!(lookup(&mut trie, &[1, 5]), Some(("1", 1)));
+
+        // Evicting an entry removes the nodes only it used
+        trie.insert(&[5, 6, 7], "567", 2);
+        assert_eq!(trie.total_bytes, 3);
+        assert!(trie.root.get(&[3]).is_none());
+        assert!(trie.root.get(&[4]).is_none());
+        assert_eq!(lookup(&mut trie, &[5, 6, 7]), Some(("567", 3)));
+    }
+
+    #[test]
+    fn skips_values_larger_than_the_budget() {
+        let mut trie = PrefixTrie::new(3);
+        trie.insert(&[1], "1", 1);
+        trie.insert(&[2], "2", 4);
+
+        assert_eq!(trie.total_bytes, 1);
+        assert_eq!(lookup(&mut trie, &[2, 5]), None);
+        assert_eq!(lookup(&mut trie, &[1, 5]), Some(("1", 1)));
+    }
+}
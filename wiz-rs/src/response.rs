@@ -0,0 +1,406 @@
+/// What a part of an answer is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartKind {
+    /// The contents of a code block.
+    Command,
+    /// A paragraph of prose.
+    Explanation,
+    /// A paragraph starting with a marker like `Warning:`.
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResponsePart {
+    pub kind: PartKind,
+    /// The language tag of a code block, e.g. `bash`.
+    pub language: Option<String>,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResponseEvent {
+    /// A new part begins, its text follows in `Text` events.
+    PartStart {
+        index: usize,
+        kind: PartKind,
+        language: Option<String>,
+    },
+    /// More text of the part `index`.
+    Text { index: usize, text: String },
+}
+
+/// Paragraphs starting with one of these, ignoring case, are warnings.
+const WARNING_MARKERS: &[&str] = &[
+    "warning:",
+    "warning!",
+    "caution:",
+    "danger:",
+    "**warning",
+    "**caution",
+    "**danger",
+    "⚠",
+];
+
+const FENCE: &str = "```";
+
+#[derive(Clone, Debug, PartialEq)]
+enum Mode {
+    Prose,
+    Code { language: Option<String> },
+}
+
+/// Splits generated text into commands, explanation paragraphs and warnings
+/// while it streams in. Text is passed on as soon as it is clear which part it
+/// belongs to: only the start of a line that could still become a code fence
+/// and the start of a paragraph that could still become a warning marker are
+/// held back, so backticks split across tokens are handled.
+#[derive(Clone, Debug)]
+pub struct ResponseParser {
+    mode: Mode,
+    /// The start of the current line while it may still be a fence.
+    line: String,
+    at_line_start: bool,
+    /// Newlines seen but not passed on yet. Trailing newlines of a part are
+    /// dropped, two or more in prose end a paragraph.
+    pending_newlines: usize,
+    /// The start of a paragraph while it may still be a warning marker.
+    paragraph: Option<String>,
+    /// Index of the part text currently goes to.
+    current: Option<usize>,
+    parts: Vec<ResponsePart>,
+    events: Vec<ResponseEvent>,
+}
+
+impl Default for ResponseParser {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Prose,
+            line: String::new(),
+            at_line_start: true,
+            pending_newlines: 0,
+            paragraph: None,
+            current: None,
+            parts: vec![],
+            events: vec![],
+        }
+    }
+}
+
+impl ResponseParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A parser for an answer that continues `prefix`, the end of the prompt.
+    /// If the prefix opens a code block, the answer starts with a command.
+    pub fn with_prefix(prefix: &str) -> Self {
+        let mut parser = Self::new();
+        parser.push(prefix);
+        Self {
+            mode: parser.mode,
+            ..Self::default()
+        }
+    }
+
+    /// Feeds generated text, returning what can be told about it so far.
+    pub fn push(&mut self, text: &str) -> Vec<ResponseEvent> {
+        for c in text.chars() {
+            self.push_char(c);
+        }
+        std::mem::take(&mut self.events)
+    }
+
+    /// Passes on the text that was held back, at the end of the answer.
+    pub fn finish(&mut self) -> Vec<ResponseEvent> {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            match line.strip_prefix(FENCE) {
+                // A fence without a newline at the very end
+                Some(_) => self.fence(&line),
+                None => line.chars().for_each(|c| self.content(c)),
+            }
+        }
+        self.resolve_paragraph();
+        self.pending_newlines = 0;
+        std::mem::take(&mut self.events)
+    }
+
+    /// All parts so far.
+    pub fn parts(&self) -> &[ResponsePart] {
+        &self.parts
+    }
+
+    /// The text of the first command.
+    pub fn command(&self) -> Option<&str> {
+        self.parts
+            .iter()
+            .find(|part| part.kind == PartKind::Command)
+            .map(|part| part.text.as_str())
+    }
+
+    /// Whether text currently goes into a code block.
+    pub fn in_command(&self) -> bool {
+        matches!(self.mode, Mode::Code { .. })
+    }
+
+    /// Whether a command was completed and followed by some other text.
+    pub fn answered(&self) -> bool {
+        self.parts
+            .iter()
+            .skip_while(|part| part.kind != PartKind::Command)
+            .any(|part| part.kind != PartKind::Command)
+    }
+
+    fn push_char(&mut self, c: char) {
+        if !self.at_line_start {
+            if c == '\n' {
+                self.newline();
+            } else {
+                self.content(c);
+            }
+            return;
+        }
+
+        if c == '\n' {
+            let line = std::mem::take(&mut self.line);
+            if line.starts_with(FENCE) {
+                self.fence(&line);
+            } else {
+                line.chars().for_each(|c| self.content(c));
+            }
+            self.newline();
+            return;
+        }
+
+        self.line.push(c);
+        if FENCE.starts_with(self.line.as_str()) {
+            // Could still become a fence
+            return;
+        }
+        if self.line.starts_with(FENCE) {
+            if let Mode::Code { .. } = self.mode {
+                // Closing fences have no tag, whatever follows is prose
+                let line = std::mem::take(&mut self.line);
+                self.fence(FENCE);
+                self.at_line_start = false;
+                line[FENCE.len()..].chars().for_each(|c| self.content(c));
+            }
+            // An opening fence needs the rest of the line for the language
+            return;
+        }
+
+        let line = std::mem::take(&mut self.line);
+        self.at_line_start = false;
+        line.chars().for_each(|c| self.content(c));
+    }
+
+    fn fence(&mut self, line: &str) {
+        self.resolve_paragraph();
+        self.mode = match self.mode {
+            Mode::Code { .. } => Mode::Prose,
+            Mode::Prose => {
+                let language = line[FENCE.len()..].trim();
+                Mode::Code {
+                    language: (!language.is_empty()).then(|| language.to_string()),
+                }
+            }
+        };
+        self.current = None;
+        self.pending_newlines = 0;
+    }
+
+    fn newline(&mut self) {
+        self.resolve_paragraph();
+        self.pending_newlines += 1;
+        self.at_line_start = true;
+    }
+
+    fn content(&mut self, c: char) {
+        if let Some(paragraph) = &mut self.paragraph {
+            paragraph.push(c);
+            self.check_paragraph();
+            return;
+        }
+
+        let new_part = match (&self.mode, self.current) {
+            (_, None) => true,
+            (Mode::Prose, Some(_)) => self.pending_newlines >= 2,
+            (Mode::Code { .. }, Some(_)) => false,
+        };
+        if new_part {
+            self.pending_newlines = 0;
+            match &self.mode {
+                Mode::Code { language } => {
+                    self.start_part(PartKind::Command, language.clone());
+                }
+                Mode::Prose => {
+                    self.current = None;
+                    self.paragraph = Some(c.to_string());
+                    self.check_paragraph();
+                    return;
+                }
+            }
+        }
+
+        let mut text = "\n".repeat(std::mem::take(&mut self.pending_newlines));
+        text.push(c);
+        self.text(text);
+    }
+
+    /// Starts the paragraph once it is clear whether it is a warning.
+    fn check_paragraph(&mut self) {
+        let Some(paragraph) = &self.paragraph else {
+            return;
+        };
+        let start = paragraph.to_lowercase();
+        let undecided = WARNING_MARKERS
+            .iter()
+            .any(|marker| marker.starts_with(&start) && marker.len() > start.len());
+        if !undecided {
+            self.resolve_paragraph();
+        }
+    }
+
+    fn resolve_paragraph(&mut self) {
+        let Some(paragraph) = self.paragraph.take() else {
+            return;
+        };
+        let start = paragraph.to_lowercase();
+        let kind = if WARNING_MARKERS
+            .iter()
+            .any(|marker| start.starts_with(marker))
+        {
+            PartKind::Warning
+        } else {
+            PartKind::Explanation
+        };
+        self.start_part(kind, None);
+        self.text(paragraph);
+    }
+
+    fn start_part(&mut self, kind: PartKind, language: Option<String>) {
+        let index = self.parts.len();
+        self.parts.push(ResponsePart {
+            kind,
+            language: language.clone(),
+            text: String::new(),
+        });
+        self.current = Some(index);
+        self.events.push(ResponseEvent::PartStart {
+            index,
+            kind,
+            language,
+        });
+    }
+
+    fn text(&mut self, text: String) {
+        let index = self.current.expect("a part was started");
+        self.parts[index].text += &text;
+        match self.events.last_mut() {
+            Some(ResponseEvent::Text {
+                index: last,
+                text: existing,
+            }) if *last == index => {
+                *existing += &text;
+            }
+            _ => self.events.push(ResponseEvent::Text { index, text }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_chars(text: &str) -> Vec<ResponsePart> {
+        let mut parser = ResponseParser::new();
+        for c in text.chars() {
+            parser.push(&c.to_string());
+        }
+        parser.finish();
+        parser.parts().to_vec()
+    }
+
+    fn parse(tokens: &[&str]) -> Vec<ResponsePart> {
+        let mut parser = ResponseParser::new();
+        for token in tokens {
+            parser.push(token);
+        }
+        parser.finish();
+        parser.parts().to_vec()
+    }
+
+    fn part(kind: PartKind, language: Option<&str>, text: &str) -> ResponsePart {
+        ResponsePart {
+            kind,
+            language: language.map(str::to_string),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn fence_split_by_character() {
+        let parts = parse_chars("```bash\nls -la\n```\nLists all files.\n");
+        assert_eq!(
+            parts,
+            vec![
+                part(PartKind::Command, Some("bash"), "ls -la"),
+                part(PartKind::Explanation, None, "Lists all files."),
+            ]
+        );
+    }
+
+    #[test]
+    fn backticks_split_across_tokens() {
+        let parts = parse(&["`", "``s", "h\nfind . -name", " '*.rs'\n`", "``", "\n"]);
+        assert_eq!(
+            parts,
+            vec![part(PartKind::Command, Some("sh"), "find . -name '*.rs'")]
+        );
+    }
+
+    #[test]
+    fn closing_fence_followed_by_prose() {
+        let parts = parse(&["```\ndf -h\n```This shows disk usage."]);
+        assert_eq!(
+            parts,
+            vec![
+                part(PartKind::Command, None, "df -h"),
+                part(PartKind::Explanation, None, "This shows disk usage."),
+            ]
+        );
+    }
+
+    #[test]
+    fn backticks_inside_prose_are_not_a_fence() {
+        let parts = parse_chars("Use `ls` here.\n");
+        assert_eq!(
+            parts,
+            vec![part(PartKind::Explanation, None, "Use `ls` here.")]
+        );
+    }
+
+    #[test]
+    fn warnings_and_paragraphs() {
+        let parts =
+            parse_chars("```\nrm -rf build\n```\nRemoves it.\n\nWarning: this cannot be undone.");
+        assert_eq!(
+            parts,
+            vec![
+                part(PartKind::Command, None, "rm -rf build"),
+                part(PartKind::Explanation, None, "Removes it."),
+                part(PartKind::Warning, None, "Warning: this cannot be undone."),
+            ]
+        );
+    }
+
+    #[test]
+    fn prefix_opening_a_code_block() {
+        let mut parser = ResponseParser::with_prefix("Answer:\n```bash\n");
+        assert!(parser.in_command());
+        parser.push("echo hi\n```\nPrints hi.");
+        parser.finish();
+        assert_eq!(parser.command(), Some("echo hi"));
+        assert!(parser.answered());
+    }
+}
@@ -0,0 +1,724 @@
+use std::path::{Path, PathBuf};
+
+use crate::shell::{self, Pipeline, RedirectKind, SimpleCommand, Span, Word};
+
+/// How much damage a command can do, ordered from harmless to destructive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RiskLevel {
+    /// Nothing suspicious was found.
+    None,
+    /// Changes something, but in a way that is easy to undo or expected.
+    Low,
+    /// Loses data that may be hard to get back.
+    Medium,
+    /// Loses data or runs untrusted code.
+    High,
+    /// Can destroy the system or whole disks.
+    Critical,
+}
+
+impl RiskLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RiskLevel::None => "none",
+            RiskLevel::Low => "low",
+            RiskLevel::Medium => "medium",
+            RiskLevel::High => "high",
+            RiskLevel::Critical => "critical",
+        }
+    }
+}
+
+impl std::fmt::Display for RiskLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Something a command does that makes it risky.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RiskReason {
+    pub level: RiskLevel,
+    pub message: String,
+    /// The part of the command the reason is about.
+    pub span: Span,
+}
+
+/// The outcome of `assess_risk`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Risk {
+    pub reasons: Vec<RiskReason>,
+}
+
+impl Risk {
+    /// The level of the worst reason.
+    pub fn level(&self) -> RiskLevel {
+        self.reasons
+            .iter()
+            .map(|reason| reason.level)
+            .max()
+            .unwrap_or(RiskLevel::None)
+    }
+
+    fn add(&mut self, level: RiskLevel, span: Span, message: impl Into<String>) {
+        self.reasons.push(RiskReason {
+            level,
+            message: message.into(),
+            span,
+        });
+    }
+}
+
+/// Commands that run the command given in their arguments.
+const WRAPPERS: &[&str] = &[
+    "sudo", "doas", "env", "nohup", "nice", "ionice", "time", "exec", "command", "xargs",
+    "timeout", "stdbuf", "watch",
+];
+
+/// Options of the `WRAPPERS` that take a separate value.
+const WRAPPER_OPTIONS: &[(&str, &[&str])] = &[
+    (
+        "sudo",
+        &["-u", "-g", "-C", "-h", "-p", "-U", "-D", "-r", "-t"],
+    ),
+    ("doas", &["-u", "-C"]),
+    ("env", &["-u", "-C", "-S"]),
+    ("nice", &["-n"]),
+    ("ionice", &["-c", "-n", "-p"]),
+    ("xargs", &["-I", "-n", "-P", "-L", "-d", "-E", "-s", "-a"]),
+    ("timeout", &["-s", "-k"]),
+    ("stdbuf", &["-i", "-o", "-e"]),
+    ("watch", &["-n", "-d"]),
+];
+
+/// Programs that download files.
+const DOWNLOADERS: &[&str] = &["curl", "wget", "fetch", "aria2c"];
+
+/// Programs that run a script read from their input or arguments.
+const INTERPRETERS: &[&str] = &[
+    "sh", "bash", "zsh", "dash", "ksh", "fish", "python", "python3", "perl", "ruby", "node",
+];
+
+/// Paths whose recursive deletion or permission change breaks the system or
+/// loses everything of the user.
+const CRITICAL_PATHS: &[&str] = &[
+    "/", "/*", "~", "~/", "~/*", "$HOME", "$HOME/", "$HOME/*", "${HOME}", "/bin", "/boot", "/dev",
+    "/etc", "/home", "/lib", "/lib64", "/opt", "/root", "/sbin", "/sys", "/usr", "/var",
+];
+
+/// Device files that are fine to write to.
+const HARMLESS_DEVICES: &[&str] = &["/dev/null", "/dev/stdout", "/dev/stderr", "/dev/tty"];
+
+/// Looks for destructive patterns in a command line: recursive deletion,
+/// writing to devices, formatting disks, world-writable permissions, running
+/// downloaded scripts, force pushes and redirects that overwrite files. `cwd`
+/// is where the command would run, relative redirect targets are only checked
+/// for existing files if it is known.
+pub fn assess_risk(command: &str, cwd: Option<&Path>) -> Risk {
+    let mut risk = Risk::default();
+    let script = match shell::parse(command) {
+        Ok(script) => script,
+        Err(err) => {
+            let span = Span {
+                start: err.offset,
+                end: command.len(),
+            };
+            risk.add(
+                RiskLevel::Low,
+                span,
+                format!("could not be analysed, {}", err.message),
+            );
+            return risk;
+        }
+    };
+
+    for (pipeline, _) in &script.pipelines {
+        check_pipeline(&mut risk, pipeline);
+    }
+    for command in script.commands() {
+        check_command(&mut risk, command, cwd);
+    }
+    risk
+}
+
+/// The file name of a command name, e.g. `rm` for `/bin/rm`.
+pub(crate) fn program(word: &Word) -> &str {
+    word.text.rsplit('/').next().unwrap_or_default()
+}
+
+/// The command a wrapper like `sudo` or `xargs` runs, and whether it runs as
+/// root.
+pub(crate) fn unwrap_command(words: &[Word]) -> (&[Word], bool) {
+    let mut words = words;
+    let mut root = false;
+    while let Some(name) = words.first() {
+        let name = program(name);
+        if !WRAPPERS.contains(&name) {
+            break;
+        }
+        root |= name == "sudo" || name == "doas";
+        let options = WRAPPER_OPTIONS
+            .iter()
+            .find(|(wrapper, _)| *wrapper == name)
+            .map_or(&[][..], |(_, options)| *options);
+
+        let mut i = 1 + options_length(&words[1..], options, name == "env");
+        if name == "timeout" {
+            // The duration
+            i += 1;
+        }
+        words = words.get(i..).unwrap_or_default();
+    }
+    (words, root)
+}
+
+/// How many of `args` are options of a wrapper, `options` being those that
+/// take a value. `env` also takes `NAME=value` assignments.
+fn options_length(args: &[Word], options: &[&str], assignments: bool) -> usize {
+    let mut i = 0;
+    while let Some(word) = args.get(i) {
+        if options.contains(&word.text.as_str()) {
+            i += 2;
+        } else if word.text == "--" {
+            return i + 1;
+        } else if word.text.starts_with('-') || (assignments && word.text.contains('=')) {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    i.min(args.len())
+}
+
+/// The options and operands of a command, with clustered short options like
+/// `-rf` split into single letters.
+struct Arguments<'a> {
+    short: Vec<char>,
+    long: Vec<&'a str>,
+    operands: Vec<&'a Word>,
+}
+
+impl<'a> Arguments<'a> {
+    fn new(args: &'a [Word]) -> Self {
+        let mut arguments = Arguments {
+            short: vec![],
+            long: vec![],
+            operands: vec![],
+        };
+        let mut options_done = false;
+        for word in args {
+            let text = word.text.as_str();
+            if options_done || word.quoted || text == "-" || !text.starts_with('-') {
+                arguments.operands.push(word);
+            } else if text == "--" {
+                options_done = true;
+            } else if let Some(long) = text.strip_prefix("--") {
+                arguments
+                    .long
+                    .push(long.split('=').next().unwrap_or_default());
+            } else {
+                arguments.short.extend(text[1..].chars());
+            }
+        }
+        arguments
+    }
+
+    fn has(&self, short: char, long: &str) -> bool {
+        self.short.contains(&short) || self.long.contains(&long)
+    }
+}
+
+fn check_pipeline(risk: &mut Risk, pipeline: &Pipeline) {
+    let downloads = pipeline.commands.iter().position(|command| {
+        let (words, _) = unwrap_command(&command.words);
+        words
+            .first()
+            .map_or(false, |name| DOWNLOADERS.contains(&program(name)))
+    });
+    let Some(downloads) = downloads else {
+        return;
+    };
+    for command in &pipeline.commands[downloads + 1..] {
+        let (words, _) = unwrap_command(&command.words);
+        if let Some(name) = words
+            .first()
+            .filter(|name| INTERPRETERS.contains(&program(name)))
+        {
+            risk.add(
+                RiskLevel::High,
+                pipeline.span,
+                format!(
+                    "pipes a download into `{}`, running code from the internet without review",
+                    name.text
+                ),
+            );
+        }
+    }
+}
+
+fn check_command(risk: &mut Risk, command: &SimpleCommand, cwd: Option<&Path>) {
+    for redirect in &command.redirects {
+        let target = &redirect.target.text;
+        let writes = match redirect.kind {
+            RedirectKind::Output | RedirectKind::Append => true,
+            // `>&file` writes to a file, `2>&1`, `>&2-` and `>&-` only to
+            // descriptors
+            RedirectKind::Duplicate => {
+                let fd = target.strip_suffix('-').unwrap_or(target);
+                !fd.chars().all(|c| c.is_ascii_digit())
+            }
+            RedirectKind::Input | RedirectKind::HereDoc | RedirectKind::HereString => false,
+        };
+        if !writes {
+            continue;
+        }
+        if target.starts_with("/dev/") {
+            if !HARMLESS_DEVICES.contains(&target.as_str()) && !target.starts_with("/dev/fd/") {
+                risk.add(
+                    RiskLevel::Critical,
+                    redirect.span,
+                    format!("writes directly to the device `{target}`"),
+                );
+            }
+        } else if let Some(path) = resolve(target, cwd).filter(|path| path.is_file()) {
+            match redirect.kind {
+                RedirectKind::Append => risk.add(
+                    RiskLevel::Low,
+                    redirect.span,
+                    format!("appends to the existing file `{}`", path.display()),
+                ),
+                _ => risk.add(
+                    RiskLevel::Medium,
+                    redirect.span,
+                    format!("overwrites the existing file `{}`", path.display()),
+                ),
+            }
+        }
+    }
+
+    let (words, root) = unwrap_command(&command.words);
+    let Some(name) = words.first() else {
+        return;
+    };
+    let span = command.span;
+    let args = &words[1..];
+    let arguments = Arguments::new(args);
+
+    match program(name) {
+        "rm" => {
+            let recursive = arguments.has('r', "recursive") || arguments.has('R', "recursive");
+            let critical = arguments
+                .operands
+                .iter()
+                .find(|word| is_critical_path(&word.text));
+            if arguments.long.contains(&"no-preserve-root") {
+                risk.add(
+                    RiskLevel::Critical,
+                    span,
+                    "disables the protection against deleting `/`",
+                );
+            }
+            match (recursive, critical) {
+                (true, Some(path)) => risk.add(
+                    RiskLevel::Critical,
+                    span,
+                    format!("recursively deletes `{}`", path.text),
+                ),
+                (true, None) => risk.add(
+                    RiskLevel::High,
+                    span,
+                    "recursively deletes directories and everything in them",
+                ),
+                (false, _) => risk.add(RiskLevel::Low, span, "deletes files"),
+            }
+        }
+        "shred" => risk.add(
+            RiskLevel::High,
+            span,
+            "overwrites files so they cannot be recovered",
+        ),
+        "dd" => {
+            let device = args
+                .iter()
+                .filter_map(|word| word.text.strip_prefix("of="))
+                .find(|path| path.starts_with("/dev/") && !HARMLESS_DEVICES.contains(path));
+            if let Some(device) = device {
+                risk.add(
+                    RiskLevel::Critical,
+                    span,
+                    format!("writes directly to the device `{device}`"),
+                );
+            }
+        }
+        name if name.starts_with("mkfs") || matches!(name, "mke2fs" | "mkswap" | "wipefs") => risk
+            .add(
+                RiskLevel::Critical,
+                span,
+                "formats a device, erasing everything on it",
+            ),
+        "fdisk" | "sfdisk" | "cfdisk" | "gdisk" | "sgdisk" | "parted" => {
+            risk.add(RiskLevel::High, span, "changes a partition table")
+        }
+        "chmod" | "chown" | "chgrp" => {
+            let recursive = arguments.has('R', "recursive");
+            let operands = &arguments.operands;
+            if recursive {
+                if let Some(path) = operands
+                    .iter()
+                    .skip(1)
+                    .find(|word| is_critical_path(&word.text))
+                {
+                    risk.add(
+                        RiskLevel::Critical,
+                        span,
+                        format!(
+                            "recursively changes the ownership or permissions of `{}`",
+                            path.text
+                        ),
+                    );
+                }
+            }
+            let world_writable = program(name) == "chmod"
+                && operands
+                    .first()
+                    .map_or(false, |mode| is_world_writable(&mode.text));
+            match (world_writable, recursive) {
+                (true, true) => risk.add(
+                    RiskLevel::High,
+                    span,
+                    "recursively makes files writable by every user",
+                ),
+                (true, false) => risk.add(
+                    RiskLevel::Medium,
+                    span,
+                    "makes files writable by every user",
+                ),
+                _ => {}
+            }
+        }
+        "git" => check_git(risk, span, args),
+        "docker" | "podman" => {
+            let operands: Vec<&str> = arguments
+                .operands
+                .iter()
+                .map(|word| word.text.as_str())
+                .collect();
+            if operands.contains(&"prune") {
+                risk.add(
+                    RiskLevel::Medium,
+                    span,
+                    "deletes unused containers, images, networks or volumes",
+                );
+            } else if operands.starts_with(&["volume", "rm"]) {
+                risk.add(
+                    RiskLevel::Medium,
+                    span,
+                    "deletes volumes and the data in them",
+                );
+            }
+        }
+        "kubectl"
+            if arguments
+                .operands
+                .first()
+                .map_or(false, |word| word.text == "delete") =>
+        {
+            risk.add(RiskLevel::Medium, span, "deletes cluster resources")
+        }
+        "find" => {
+            let deletes = args.iter().enumerate().any(|(i, word)| {
+                word.text == "-delete"
+                    || (matches!(word.text.as_str(), "-exec" | "-execdir" | "-ok")
+                        && args.get(i + 1).map_or(false, |name| program(name) == "rm"))
+            });
+            if deletes {
+                risk.add(RiskLevel::High, span, "deletes every file it finds");
+            }
+        }
+        "mv" if arguments
+            .operands
+            .last()
+            .map_or(false, |word| word.text == "/dev/null") =>
+        {
+            risk.add(
+                RiskLevel::High,
+                span,
+                "moves files to `/dev/null`, deleting them",
+            )
+        }
+        "truncate" => risk.add(
+            RiskLevel::Medium,
+            span,
+            "truncates files, losing their contents",
+        ),
+        "shutdown" | "reboot" | "halt" | "poweroff" => risk.add(
+            RiskLevel::Medium,
+            span,
+            "shuts down or restarts the machine",
+        ),
+        shell if INTERPRETERS.contains(&shell) => {
+            // `bash <(curl ...)` and `sh -c "$(curl ...)"`
+            let downloads = args.iter().any(|word| {
+                word.substitutions.iter().any(|script| {
+                    script.commands().iter().any(|command| {
+                        let (words, _) = unwrap_command(&command.words);
+                        words
+                            .first()
+                            .map_or(false, |name| DOWNLOADERS.contains(&program(name)))
+                    })
+                })
+            });
+            if downloads {
+                risk.add(
+                    RiskLevel::High,
+                    span,
+                    format!(
+                        "runs a download with `{}`, running code from the internet without review",
+                        name.text
+                    ),
+                );
+            }
+        }
+        _ => {}
+    }
+
+    if root {
+        risk.add(RiskLevel::Low, span, "runs as root");
+    }
+}
+
+fn check_git(risk: &mut Risk, span: Span, args: &[Word]) {
+    // Skip global options, `-C <path>` and `-c <name>=<value>` take a value
+    let mut i = 0;
+    while let Some(word) = args.get(i) {
+        match word.text.as_str() {
+            "-C" | "-c" => i += 2,
+            text if text.starts_with('-') => i += 1,
+            _ => break,
+        }
+    }
+    let Some(subcommand) = args.get(i) else {
+        return;
+    };
+    let rest = &args[i + 1..];
+    let arguments = Arguments::new(rest);
+
+    match subcommand.text.as_str() {
+        "push" => {
+            if arguments.has('f', "force")
+                || arguments
+                    .operands
+                    .iter()
+                    .any(|word| word.text.starts_with('+'))
+            {
+                risk.add(
+                    RiskLevel::High,
+                    span,
+                    "force pushes, which can overwrite commits on the remote",
+                );
+            } else if arguments
+                .long
+                .iter()
+                .any(|long| long.starts_with("force-with-lease"))
+            {
+                risk.add(
+                    RiskLevel::Medium,
+                    span,
+                    "force pushes, overwriting the remote branch if nobody else pushed to it",
+                );
+            }
+            if arguments.has('d', "delete")
+                || arguments
+                    .operands
+                    .iter()
+                    .any(|word| word.text.starts_with(':'))
+            {
+                risk.add(RiskLevel::Medium, span, "deletes a branch on the remote");
+            }
+        }
+        "reset" if arguments.long.contains(&"hard") => {
+            risk.add(RiskLevel::Medium, span, "discards all uncommitted changes")
+        }
+        "clean" if arguments.has('f', "force") => {
+            risk.add(RiskLevel::Medium, span, "deletes untracked files")
+        }
+        "branch" if arguments.short.contains(&'D') => risk.add(
+            RiskLevel::Low,
+            span,
+            "deletes branches even if they are not merged",
+        ),
+        _ => {}
+    }
+}
+
+fn is_critical_path(path: &str) -> bool {
+    let trimmed = if path.len() > 1 {
+        path.trim_end_matches('/')
+    } else {
+        path
+    };
+    CRITICAL_PATHS.contains(&path) || CRITICAL_PATHS.contains(&trimmed)
+}
+
+/// Whether a `chmod` mode gives every user write access, e.g. `777` or `o+w`.
+fn is_world_writable(mode: &str) -> bool {
+    if mode.chars().all(|c| c.is_ascii_digit()) {
+        return mode
+            .chars()
+            .last()
+            .and_then(|others| others.to_digit(8))
+            .map_or(false, |others| others & 2 != 0);
+    }
+    mode.split(',').any(|clause| {
+        let (who, permissions) = match clause.find(['+', '=']) {
+            Some(i) => clause.split_at(i),
+            None => return false,
+        };
+        (who.is_empty() || who.contains(['o', 'a'])) && permissions.contains('w')
+    })
+}
+
+/// The file a redirect target refers to, if it can be known without running
+/// the command.
+fn resolve(target: &str, cwd: Option<&Path>) -> Option<PathBuf> {
+    if target.contains(['$', '`', '*', '?']) {
+        return None;
+    }
+    if let Some(rest) = target.strip_prefix("~/") {
+        return std::env::var_os("HOME").map(|home| Path::new(&home).join(rest));
+    }
+    let path = Path::new(target);
+    if path.is_absolute() {
+        Some(path.to_path_buf())
+    } else {
+        cwd.map(|cwd| cwd.join(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(command: &str) -> RiskLevel {
+        assess_risk(command, None).level()
+    }
+
+    #[test]
+    fn harmless_commands() {
+        assert_eq!(level("ls -la | grep foo > /dev/null 2>&1"), RiskLevel::None);
+        assert_eq!(level("echo hi >&2"), RiskLevel::None);
+        assert_eq!(level("git push origin main"), RiskLevel::None);
+    }
+
+    #[test]
+    fn recursive_deletion() {
+        assert_eq!(level("sudo rm -rf /"), RiskLevel::Critical);
+        assert_eq!(level("rm -r -f ~/"), RiskLevel::Critical);
+        assert_eq!(level("rm --recursive --force /usr/"), RiskLevel::Critical);
+        assert_eq!(level("rm -rf build"), RiskLevel::High);
+        assert_eq!(level("rm notes.txt"), RiskLevel::Low);
+        // A quoted `-rf` is a file name
+        assert_eq!(level("rm '-rf' /"), RiskLevel::Low);
+    }
+
+    #[test]
+    fn sudo_runs_as_root() {
+        let risk = assess_risk("sudo -u root rm -rf /", None);
+        assert!(risk
+            .reasons
+            .iter()
+            .any(|reason| reason.message == "recursively deletes `/`"));
+        assert!(risk
+            .reasons
+            .iter()
+            .any(|reason| reason.message == "runs as root"));
+    }
+
+    #[test]
+    fn piped_download() {
+        assert_eq!(
+            level("curl -fsSL https://example.com/install.sh | sh"),
+            RiskLevel::High
+        );
+        assert_eq!(
+            level("wget -qO- https://example.com/x | sudo bash -s"),
+            RiskLevel::High
+        );
+        assert_eq!(
+            level("curl https://example.com/data.json | jq ."),
+            RiskLevel::None
+        );
+    }
+
+    #[test]
+    fn substituted_download() {
+        assert_eq!(
+            level("bash <(curl -s https://example.com/install.sh)"),
+            RiskLevel::High
+        );
+        assert_eq!(
+            level("sh -c \"$(wget -qO- https://example.com/install.sh)\""),
+            RiskLevel::High
+        );
+    }
+
+    #[test]
+    fn writing_to_devices() {
+        assert_eq!(
+            level("dd if=ubuntu.iso of=/dev/sda bs=4M"),
+            RiskLevel::Critical
+        );
+        assert_eq!(level("dd if=/dev/zero of=/dev/null"), RiskLevel::None);
+        assert_eq!(level("echo 1 > /dev/sda"), RiskLevel::Critical);
+        assert_eq!(level("cat image >> /dev/sdb"), RiskLevel::Critical);
+        assert_eq!(level("cat image >&/dev/sdc"), RiskLevel::Critical);
+        assert_eq!(level("mkfs.ext4 /dev/sdb1"), RiskLevel::Critical);
+    }
+
+    #[test]
+    fn permissions() {
+        assert_eq!(level("chmod -R 777 /"), RiskLevel::Critical);
+        assert_eq!(level("chmod -R 777 ./public"), RiskLevel::High);
+        assert_eq!(level("chmod o+w file"), RiskLevel::Medium);
+        assert_eq!(level("chmod 755 script.sh"), RiskLevel::None);
+        assert_eq!(level("sudo chown -R user /etc"), RiskLevel::Critical);
+    }
+
+    #[test]
+    fn git() {
+        assert_eq!(level("git push --force origin main"), RiskLevel::High);
+        assert_eq!(level("git push origin +main"), RiskLevel::High);
+        assert_eq!(
+            level("git push --force-with-lease origin main"),
+            RiskLevel::Medium
+        );
+        assert_eq!(level("git -C repo reset --hard HEAD~1"), RiskLevel::Medium);
+        assert_eq!(level("git clean -fdx"), RiskLevel::Medium);
+    }
+
+    #[test]
+    fn redirects_to_existing_files() {
+        let dir = std::env::temp_dir().join(format!("wiz-safety-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+
+        let level = |command| assess_risk(command, Some(&dir)).level();
+        assert_eq!(level("echo hi > notes.txt"), RiskLevel::Medium);
+        assert_eq!(level("echo hi >> notes.txt"), RiskLevel::Low);
+        assert_eq!(level("echo hi > new.txt"), RiskLevel::None);
+        // Without a working directory relative targets are not checked
+        assert_eq!(
+            assess_risk("echo hi > notes.txt", None).level(),
+            RiskLevel::None
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unparsable_command() {
+        let risk = assess_risk("echo 'unterminated", None);
+        assert_eq!(risk.level(), RiskLevel::Low);
+        assert_eq!(risk.reasons[0].span.start, 5);
+    }
+}
@@ -0,0 +1,752 @@
+use thiserror::Error;
+
+/// A range of bytes in the parsed source.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Word {
+    /// The word with quotes and escapes removed. Expansions like `$HOME` and
+    /// command substitutions are kept as written.
+    pub text: String,
+    pub span: Span,
+    /// Whether any part of the word was quoted.
+    pub quoted: bool,
+    /// The commands of `$(...)`, backtick and `<(...)` substitutions in the
+    /// word.
+    pub substitutions: Vec<Script>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedirectKind {
+    /// `<`
+    Input,
+    /// `>`, `>|` and `&>`
+    Output,
+    /// `>>` and `&>>`
+    Append,
+    /// `<<` and `<<-`, the target is the delimiter
+    HereDoc,
+    /// `<<<`
+    HereString,
+    /// `<&`, `>&` and `<>`
+    Duplicate,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Redirect {
+    /// The file descriptor written before the operator, like the 2 in `2>`.
+    pub fd: Option<u32>,
+    pub kind: RedirectKind,
+    pub target: Word,
+    pub span: Span,
+}
+
+/// A command with its arguments, e.g. `FOO=1 ls -la > out`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SimpleCommand {
+    /// `NAME=value` words in front of the command.
+    pub assignments: Vec<Word>,
+    /// The command name followed by its arguments.
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+    pub span: Span,
+}
+
+impl SimpleCommand {
+    pub fn name(&self) -> Option<&Word> {
+        self.words.first()
+    }
+
+    pub fn args(&self) -> &[Word] {
+        self.words.get(1..).unwrap_or_default()
+    }
+}
+
+/// Commands connected with `|`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Pipeline {
+    pub commands: Vec<SimpleCommand>,
+    /// Whether the pipeline starts with `!`.
+    pub negated: bool,
+    pub span: Span,
+}
+
+/// What follows a pipeline in a list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Separator {
+    /// `&&`
+    And,
+    /// `||`
+    Or,
+    /// `;` or a newline
+    Sequence,
+    /// `&`
+    Background,
+}
+
+/// A list of pipelines. Compound commands are flattened: keywords like `if`,
+/// `do` and `done` are dropped and the commands inside them are listed in
+/// order, subshells likewise.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Script {
+    pub pipelines: Vec<(Pipeline, Option<Separator>)>,
+}
+
+impl Script {
+    /// Every simple command in the script, including those in command
+    /// substitutions, in order of appearance.
+    pub fn commands(&self) -> Vec<&SimpleCommand> {
+        let mut commands = vec![];
+        for (pipeline, _) in &self.pipelines {
+            for command in &pipeline.commands {
+                commands.push(command);
+                let words = command
+                    .assignments
+                    .iter()
+                    .chain(&command.words)
+                    .chain(command.redirects.iter().map(|redirect| &redirect.target));
+                for word in words {
+                    for script in &word.substitutions {
+                        commands.extend(script.commands());
+                    }
+                }
+            }
+        }
+        commands
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{message} at byte {offset}")]
+pub struct ParseError {
+    pub message: String,
+    pub offset: usize,
+}
+
+/// Reserved words that open or close compound commands. They are skipped at
+/// the start of a command.
+const KEYWORDS: &[&str] = &[
+    "if", "then", "else", "elif", "fi", "do", "done", "while", "until", "{", "}", "time", "esac",
+];
+
+/// Parses a command line. Supports quoting, escapes, expansions, command
+/// substitutions, redirects, pipelines, lists and here documents, which is
+/// enough to analyse generated commands, but is not a complete shell parser.
+pub fn parse(source: &str) -> Result<Script, ParseError> {
+    parse_at(source, 0)
+}
+
+fn parse_at(source: &str, base: usize) -> Result<Script, ParseError> {
+    let mut lexer = Lexer {
+        source,
+        pos: 0,
+        base,
+        pending_heredocs: vec![],
+    };
+    let mut parser = Parser::default();
+    while let Some(token) = lexer.next_token()? {
+        parser.token(token, &mut lexer)?;
+    }
+    parser.end_pipeline(None);
+    Ok(parser.script)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    And,
+    Or,
+    Pipe,
+    PipeAll,
+    Semicolon,
+    DoubleSemicolon,
+    Background,
+    Open,
+    Close,
+    Redirect(Option<u32>, RedirectKind),
+}
+
+#[derive(Debug)]
+enum Token {
+    Word(Word),
+    Operator(Operator, Span),
+    Newline,
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    pos: usize,
+    /// Offset of `source` in the outermost source, for the spans.
+    base: usize,
+    /// Delimiters of here documents whose body starts after the next newline.
+    pending_heredocs: Vec<(String, bool)>,
+}
+
+const OPERATORS: &[(&str, Operator)] = &[
+    ("&>>", Operator::Redirect(None, RedirectKind::Append)),
+    ("<<<", Operator::Redirect(None, RedirectKind::HereString)),
+    ("<<-", Operator::Redirect(None, RedirectKind::HereDoc)),
+    ("&&", Operator::And),
+    ("||", Operator::Or),
+    (";;", Operator::DoubleSemicolon),
+    ("|&", Operator::PipeAll),
+    ("&>", Operator::Redirect(None, RedirectKind::Output)),
+    ("<<", Operator::Redirect(None, RedirectKind::HereDoc)),
+    (">>", Operator::Redirect(None, RedirectKind::Append)),
+    (">|", Operator::Redirect(None, RedirectKind::Output)),
+    ("<&", Operator::Redirect(None, RedirectKind::Duplicate)),
+    (">&", Operator::Redirect(None, RedirectKind::Duplicate)),
+    ("<>", Operator::Redirect(None, RedirectKind::Duplicate)),
+    ("|", Operator::Pipe),
+    ("&", Operator::Background),
+    (";", Operator::Semicolon),
+    ("(", Operator::Open),
+    (")", Operator::Close),
+    ("<", Operator::Redirect(None, RedirectKind::Input)),
+    (">", Operator::Redirect(None, RedirectKind::Output)),
+];
+
+fn is_metachar(c: char) -> bool {
+    matches!(
+        c,
+        ' ' | '\t' | '\n' | '|' | '&' | ';' | '(' | ')' | '<' | '>'
+    )
+}
+
+impl<'a> Lexer<'a> {
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn error(&self, message: &str, pos: usize) -> ParseError {
+        ParseError {
+            message: message.to_string(),
+            offset: self.base + pos,
+        }
+    }
+
+    fn span(&self, start: usize, end: usize) -> Span {
+        Span {
+            start: self.base + start,
+            end: self.base + end,
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>, ParseError> {
+        // Blanks, line continuations and comments
+        loop {
+            let rest = self.rest();
+            if rest.starts_with([' ', '\t']) {
+                self.pos += 1;
+            } else if rest.starts_with("\\\n") {
+                self.pos += 2;
+            } else if rest.starts_with('#') {
+                self.pos += rest.find('\n').unwrap_or(rest.len());
+            } else {
+                break;
+            }
+        }
+
+        let Some(c) = self.peek() else {
+            return Ok(None);
+        };
+        let start = self.pos;
+
+        if c == '\n' {
+            self.pos += 1;
+            self.skip_heredocs()?;
+            return Ok(Some(Token::Newline));
+        }
+
+        // Process substitutions are words
+        if self.rest().starts_with("<(") || self.rest().starts_with(">(") {
+            return self.word().map(|word| Some(Token::Word(word)));
+        }
+
+        // A file descriptor directly in front of a redirect
+        let digits = self.rest().len()
+            - self
+                .rest()
+                .trim_start_matches(|c: char| c.is_ascii_digit())
+                .len();
+        let fd = if digits > 0 && self.rest()[digits..].starts_with(['<', '>']) {
+            self.pos += digits;
+            self.source[start..start + digits].parse().ok()
+        } else {
+            None
+        };
+
+        for (text, operator) in OPERATORS {
+            if self.rest().starts_with(text) {
+                self.pos += text.len();
+                let operator = match operator {
+                    Operator::Redirect(_, kind) => Operator::Redirect(fd, *kind),
+                    operator => *operator,
+                };
+                if let Operator::Redirect(_, RedirectKind::HereDoc) = operator {
+                    self.heredoc_delimiter(*text == "<<-")?;
+                }
+                return Ok(Some(Token::Operator(operator, self.span(start, self.pos))));
+            }
+        }
+
+        self.pos = start;
+        self.word().map(|word| Some(Token::Word(word)))
+    }
+
+    /// Remembers the delimiter of a here document, without consuming it.
+    fn heredoc_delimiter(&mut self, strip_tabs: bool) -> Result<(), ParseError> {
+        let pos = self.pos;
+        while self.rest().starts_with([' ', '\t']) {
+            self.pos += 1;
+        }
+        let word = self.word()?;
+        self.pos = pos;
+        self.pending_heredocs.push((word.text, strip_tabs));
+        Ok(())
+    }
+
+    /// Skips the bodies of here documents started on the previous line.
+    fn skip_heredocs(&mut self) -> Result<(), ParseError> {
+        for (delimiter, strip_tabs) in std::mem::take(&mut self.pending_heredocs) {
+            loop {
+                if self.pos >= self.source.len() {
+                    return Err(self.error("unterminated here document", self.pos));
+                }
+                let line_end = self
+                    .rest()
+                    .find('\n')
+                    .map_or(self.source.len(), |i| self.pos + i);
+                let line = &self.source[self.pos..line_end];
+                self.pos = (line_end + 1).min(self.source.len());
+                let line = if strip_tabs {
+                    line.trim_start_matches('\t')
+                } else {
+                    line
+                };
+                if line == delimiter {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn word(&mut self) -> Result<Word, ParseError> {
+        let start = self.pos;
+        let mut word = Word {
+            text: String::new(),
+            span: Span::default(),
+            quoted: false,
+            substitutions: vec![],
+        };
+
+        while let Some(c) = self.peek() {
+            match c {
+                '<' | '>' if self.rest()[1..].starts_with('(') => {
+                    // Process substitution
+                    let end = self.matching_paren(self.pos + 1)?;
+                    self.substitution(&mut word, self.pos + 2, end, end + 1);
+                }
+                c if is_metachar(c) => break,
+                '\\' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('\n') => self.pos += 1,
+                        Some(c) => {
+                            word.text.push(c);
+                            word.quoted = true;
+                            self.pos += c.len_utf8();
+                        }
+                        None => word.text.push('\\'),
+                    }
+                }
+                '\'' => {
+                    let end = self.rest()[1..]
+                        .find('\'')
+                        .ok_or_else(|| self.error("unterminated single quote", self.pos))?;
+                    word.text += &self.rest()[1..end + 1];
+                    word.quoted = true;
+                    self.pos += end + 2;
+                }
+                '"' => {
+                    word.quoted = true;
+                    self.double_quoted(&mut word)?;
+                }
+                '$' | '`' => self.expansion(&mut word)?,
+                c => {
+                    word.text.push(c);
+                    self.pos += c.len_utf8();
+                }
+            }
+        }
+
+        word.span = self.span(start, self.pos);
+        Ok(word)
+    }
+
+    fn double_quoted(&mut self, word: &mut Word) -> Result<(), ParseError> {
+        let open = self.pos;
+        self.pos += 1;
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated double quote", open)),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(c @ ('"' | '\\' | '$' | '`')) => {
+                            word.text.push(c);
+                            self.pos += 1;
+                        }
+                        Some('\n') => self.pos += 1,
+                        _ => word.text.push('\\'),
+                    }
+                }
+                Some('$' | '`') => self.expansion(word)?,
+                Some(c) => {
+                    word.text.push(c);
+                    self.pos += c.len_utf8();
+                }
+            }
+        }
+    }
+
+    /// Parameter expansions, arithmetic and command substitutions, which are
+    /// kept as written.
+    fn expansion(&mut self, word: &mut Word) -> Result<(), ParseError> {
+        let start = self.pos;
+        let rest = self.rest();
+        if rest.starts_with("$((") {
+            let end = self.matching_paren(start + 1)?;
+            word.text += &self.source[start..end + 1];
+            self.pos = end + 1;
+        } else if rest.starts_with("$(") {
+            let end = self.matching_paren(start + 1)?;
+            self.substitution(word, start + 2, end, end + 1);
+        } else if rest.starts_with("${") {
+            let end = rest
+                .find('}')
+                .ok_or_else(|| self.error("unterminated parameter expansion", start))?;
+            word.text += &rest[..end + 1];
+            self.pos += end + 1;
+        } else if rest.starts_with('`') {
+            let mut end = start + 1;
+            loop {
+                match self.source[end..].find(['`', '\\']) {
+                    None => return Err(self.error("unterminated backtick", start)),
+                    Some(i) if self.source[end + i..].starts_with('\\') => end += i + 2,
+                    Some(i) => {
+                        end += i;
+                        break;
+                    }
+                }
+            }
+            self.substitution(word, start + 1, end, end + 1);
+        } else {
+            word.text.push('$');
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    /// Parses the command of a substitution between `inner_start` and
+    /// `inner_end` into `word`, continuing after it at `next`.
+    fn substitution(&mut self, word: &mut Word, inner_start: usize, inner_end: usize, next: usize) {
+        word.text += &self.source[self.pos..next];
+        // A substitution that does not parse is still a valid word, it just
+        // cannot be analysed
+        if let Ok(script) = parse_at(
+            &self.source[inner_start..inner_end],
+            self.base + inner_start,
+        ) {
+            word.substitutions.push(script);
+        }
+        self.pos = next;
+    }
+
+    /// The position of the `)` closing the `(` at `open`.
+    fn matching_paren(&self, open: usize) -> Result<usize, ParseError> {
+        let bytes = self.source.as_bytes();
+        let mut depth = 0;
+        let mut i = open;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\\' => i += 1,
+                b'\'' => {
+                    i += self.source[i + 1..]
+                        .find('\'')
+                        .ok_or_else(|| self.error("unterminated single quote", i))?
+                        + 1;
+                }
+                b'"' => {
+                    i += 1;
+                    while i < bytes.len() && bytes[i] != b'"' {
+                        i += if bytes[i] == b'\\' { 2 } else { 1 };
+                    }
+                }
+                b'(' => depth += 1,
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(i);
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        Err(self.error("unterminated substitution", open))
+    }
+}
+
+#[derive(Default)]
+struct Parser {
+    script: Script,
+    pipeline: Pipeline,
+    command: SimpleCommand,
+    /// Skip words until the end of the command, for `for` and `case` headers.
+    skip_command: bool,
+}
+
+impl Parser {
+    fn token(&mut self, token: Token, lexer: &mut Lexer) -> Result<(), ParseError> {
+        match token {
+            Token::Word(word) => self.word(word),
+            Token::Newline => self.end_pipeline(Some(Separator::Sequence)),
+            Token::Operator(Operator::Redirect(fd, kind), span) => {
+                let target = match lexer.next_token()? {
+                    Some(Token::Word(word)) => word,
+                    _ => {
+                        return Err(lexer
+                            .error("expected a file name after redirect", span.end - lexer.base))
+                    }
+                };
+                let span = span.to(target.span);
+                self.extend_command(span);
+                self.command.redirects.push(Redirect {
+                    fd,
+                    kind,
+                    target,
+                    span,
+                });
+            }
+            Token::Operator(Operator::Pipe | Operator::PipeAll, _) => self.end_command(),
+            Token::Operator(Operator::And, _) => self.end_pipeline(Some(Separator::And)),
+            Token::Operator(Operator::Or, _) => self.end_pipeline(Some(Separator::Or)),
+            Token::Operator(Operator::Background, _) => {
+                self.end_pipeline(Some(Separator::Background))
+            }
+            Token::Operator(
+                Operator::Semicolon | Operator::DoubleSemicolon | Operator::Open | Operator::Close,
+                _,
+            ) => self.end_pipeline(Some(Separator::Sequence)),
+        }
+        Ok(())
+    }
+
+    fn word(&mut self, word: Word) {
+        if self.skip_command {
+            return;
+        }
+        let at_start = self.command.words.is_empty();
+        if at_start && !word.quoted {
+            if word.text == "!" && self.command.assignments.is_empty() {
+                self.pipeline.negated = true;
+                return;
+            }
+            if KEYWORDS.contains(&word.text.as_str()) {
+                return;
+            }
+            if matches!(word.text.as_str(), "for" | "select" | "case" | "function") {
+                self.skip_command = true;
+                return;
+            }
+            if is_assignment(&word.text) {
+                self.extend_command(word.span);
+                self.command.assignments.push(word);
+                return;
+            }
+        }
+        self.extend_command(word.span);
+        self.command.words.push(word);
+    }
+
+    fn extend_command(&mut self, span: Span) {
+        let empty = self.command.words.is_empty()
+            && self.command.assignments.is_empty()
+            && self.command.redirects.is_empty();
+        self.command.span = if empty {
+            span
+        } else {
+            self.command.span.to(span)
+        };
+    }
+
+    fn end_command(&mut self) {
+        self.skip_command = false;
+        let command = std::mem::take(&mut self.command);
+        if command.words.is_empty()
+            && command.assignments.is_empty()
+            && command.redirects.is_empty()
+        {
+            return;
+        }
+        self.pipeline.span = match self.pipeline.commands.first() {
+            Some(first) => first.span.to(command.span),
+            None => command.span,
+        };
+        self.pipeline.commands.push(command);
+    }
+
+    fn end_pipeline(&mut self, separator: Option<Separator>) {
+        self.end_command();
+        let pipeline = std::mem::take(&mut self.pipeline);
+        if pipeline.commands.is_empty() {
+            return;
+        }
+        self.script.pipelines.push((pipeline, separator));
+    }
+}
+
+fn is_assignment(word: &str) -> bool {
+    match word.split_once('=') {
+        Some((name, _)) => {
+            !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(script: &Script) -> Vec<&str> {
+        script
+            .commands()
+            .iter()
+            .filter_map(|command| command.name())
+            .map(|word| word.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn quotes_and_escapes() {
+        let script = parse(r#"echo "a b" 'c d' e\ f g"#).unwrap();
+        let command = script.commands()[0];
+        let args: Vec<_> = command.args().iter().map(|word| &word.text).collect();
+        assert_eq!(args, ["a b", "c d", "e f", "g"]);
+        assert!(command.args()[..3].iter().all(|word| word.quoted));
+        assert!(!command.args()[3].quoted);
+    }
+
+    #[test]
+    fn here_document() {
+        let script = parse("cat <<EOF > out.txt\nrm -rf /\n$(reboot)\nEOF\necho done").unwrap();
+        assert_eq!(names(&script), ["cat", "echo"]);
+        let redirects = &script.commands()[0].redirects;
+        assert_eq!(redirects[0].kind, RedirectKind::HereDoc);
+        assert_eq!(redirects[0].target.text, "EOF");
+        assert_eq!(redirects[1].kind, RedirectKind::Output);
+        assert_eq!(redirects[1].target.text, "out.txt");
+    }
+
+    #[test]
+    fn here_document_stripping_tabs() {
+        let script = parse("cat <<-END\n\tbody\n\tEND\nls").unwrap();
+        assert_eq!(names(&script), ["cat", "ls"]);
+    }
+
+    #[test]
+    fn unterminated_here_document() {
+        assert!(parse("cat <<EOF\nbody\n").is_err());
+    }
+
+    #[test]
+    fn command_substitution() {
+        let script = parse("rm -rf \"$(find . -name '*.tmp')\" `which foo`").unwrap();
+        assert_eq!(names(&script), ["rm", "find", "which"]);
+        let arg = &script.commands()[0].args()[1];
+        assert_eq!(arg.substitutions.len(), 1);
+        assert!(arg.quoted);
+    }
+
+    #[test]
+    fn arithmetic_is_not_a_substitution() {
+        let script = parse("echo $((1 + 2))").unwrap();
+        assert_eq!(names(&script), ["echo"]);
+    }
+
+    #[test]
+    fn process_substitution() {
+        let script = parse("bash <(curl -s https://example.com/install.sh)").unwrap();
+        assert_eq!(names(&script), ["bash", "curl"]);
+    }
+
+    #[test]
+    fn duplicate_redirect() {
+        let script = parse("make 2>&1 | tee build.log").unwrap();
+        let make = &script.pipelines[0].0.commands[0];
+        assert_eq!(make.words.len(), 1);
+        assert_eq!(make.redirects.len(), 1);
+        assert_eq!(make.redirects[0].fd, Some(2));
+        assert_eq!(make.redirects[0].kind, RedirectKind::Duplicate);
+        assert_eq!(make.redirects[0].target.text, "1");
+        assert_eq!(names(&script), ["make", "tee"]);
+    }
+
+    #[test]
+    fn lists_and_pipelines() {
+        let script = parse("cd /tmp && ls | wc -l; echo ok &").unwrap();
+        let separators: Vec<_> = script.pipelines.iter().map(|(_, sep)| *sep).collect();
+        assert_eq!(
+            separators,
+            [
+                Some(Separator::And),
+                Some(Separator::Sequence),
+                Some(Separator::Background)
+            ]
+        );
+        assert_eq!(script.pipelines[1].0.commands.len(), 2);
+    }
+
+    #[test]
+    fn assignments_and_keywords() {
+        let script = parse("for f in *.txt; do FOO=1 mv \"$f\" done/; done").unwrap();
+        let mv = script
+            .commands()
+            .into_iter()
+            .find(|command| command.name().is_some_and(|name| name.text == "mv"))
+            .unwrap();
+        assert_eq!(mv.assignments[0].text, "FOO=1");
+        assert_eq!(mv.args()[0].text, "$f");
+    }
+
+    #[test]
+    fn unterminated_quote() {
+        let error = parse("echo 'oops").unwrap_err();
+        assert_eq!(error.offset, 5);
+    }
+}
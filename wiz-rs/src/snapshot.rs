@@ -0,0 +1,660 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    io::{Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use bincode::Options;
+
+use crate::{
+    Hyperparameters, InferenceSessionParameters, ModelKVMemoryType, SnapshotError, TokenId,
+};
+
+/// Marks a file as a wiz inference snapshot. Files written before the format
+/// was versioned start directly with the payload instead.
+const MAGIC: [u8; 8] = *b"WIZSNAP\0";
+
+/// Bumped whenever the layout of the header or the payload changes.
+///
+/// Version 2 stores only the used part of the K/V memory and supports
+/// compression. Version 1 snapshots can still be read.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 2;
+
+/// The zstd level used for snapshots. Higher levels barely shrink the memory
+/// tensors any further, but are much slower.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The header can be read without touching the (large) payload, so tools can
+/// inspect snapshots cheaply. It is bounded to this size when reading.
+const MAX_HEADER_SIZE: u64 = 64 * 1024;
+
+/// How the payload of a snapshot is compressed.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SnapshotCompression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl FromStr for SnapshotCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            "lz4" => Ok(Self::Lz4),
+            _ => Err(format!(
+                "unknown compression `{s}`, expected one of none, zstd, lz4"
+            )),
+        }
+    }
+}
+
+impl Display for SnapshotCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Zstd => write!(f, "zstd"),
+            Self::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+/// Describes which model and session layout a snapshot belongs to.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct SnapshotHeader {
+    /// See `Model::fingerprint`.
+    pub model_hash: [u8; 32],
+    pub hyperparameters: Hyperparameters,
+    pub memory_k_type: ModelKVMemoryType,
+    pub memory_v_type: ModelKVMemoryType,
+    /// How many tokens have been stored in the memory.
+    pub n_past: usize,
+    pub compression: SnapshotCompression,
+}
+
+/// The header of version 1 snapshots, which were never compressed.
+#[derive(serde::Deserialize)]
+struct SnapshotHeaderV1 {
+    model_hash: [u8; 32],
+    hyperparameters: Hyperparameters,
+    memory_k_type: ModelKVMemoryType,
+    memory_v_type: ModelKVMemoryType,
+    n_past: usize,
+}
+
+impl From<SnapshotHeaderV1> for SnapshotHeader {
+    fn from(header: SnapshotHeaderV1) -> Self {
+        Self {
+            model_hash: header.model_hash,
+            hyperparameters: header.hyperparameters,
+            memory_k_type: header.memory_k_type,
+            memory_v_type: header.memory_v_type,
+            n_past: header.n_past,
+            compression: SnapshotCompression::None,
+        }
+    }
+}
+
+/// The K/V memory of a session, written like a `Vec<u8>` of all chunks one
+/// after another. Lets the used part of every layer be written without
+/// copying it first.
+pub(crate) struct MemoryChunks<'a>(pub Vec<&'a [u8]>);
+
+impl MemoryChunks<'_> {
+    /// Writes the chunks with the length prefix bincode puts in front of a
+    /// `Vec<u8>`, straight from memory.
+    fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let len: usize = self.0.iter().map(|chunk| chunk.len()).sum();
+        writer.write_all(&(len as u64).to_le_bytes())?;
+        for chunk in &self.0 {
+            writer.write_all(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Borrows everything that goes into a snapshot, so it can be written without
+/// cloning the memory tensors. Written exactly like `InferenceSnapshot` is
+/// serialized.
+pub(crate) struct InferenceSnapshotRef<'a> {
+    /// Written in front of the payload, not part of it.
+    pub header: SnapshotHeader,
+    pub npast: usize,
+    pub session_params: InferenceSessionParameters,
+    pub memory_k: MemoryChunks<'a>,
+    pub memory_v: MemoryChunks<'a>,
+    pub last_n_tokens: &'a VecDeque<TokenId>,
+    pub logits: &'a [f32],
+}
+
+/// A serializable snapshot of the inference process. Obtained from
+/// `InferenceSession::snapshot` and restored by calling
+/// `Model::session_from_snapshot`. Useful for prompt caching.
+#[derive(serde::Deserialize, Clone)]
+pub struct InferenceSnapshot {
+    /// The header the snapshot was stored with. `None` for snapshots in the
+    /// original, unversioned layout.
+    #[serde(skip)]
+    pub header: Option<SnapshotHeader>,
+    /// How many tokens have been stored in the memory so far.
+    pub npast: usize,
+    // Parameters associated with the saved inference session.
+    pub session_params: InferenceSessionParameters,
+    /// The first `npast` positions of every layer of the 'key' memory
+    /// tensor. Snapshots written before format version 2 contain the whole
+    /// tensor instead.
+    pub memory_k: Vec<u8>,
+    /// Same as `memory_k`, for the 'value' memory tensor
+    pub memory_v: Vec<u8>,
+    /// The last n tokens that were predicted during generation
+    pub last_n_tokens: VecDeque<TokenId>,
+    /// The vector of logits that was produced after the last inference
+    pub last_logits: Vec<f32>,
+}
+
+/// Same encoding as `bincode::serialize`, but refuses to allocate more than
+/// `limit` bytes so a corrupted length prefix cannot exhaust the memory.
+fn bincode_options(limit: u64) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+}
+
+/// The largest decoded payload a snapshot with this header can have: both
+/// memory tensors in full (as in version 1), the logits, and the last tokens.
+/// Those are the window of the session plus every position fed to it, the
+/// window is not part of the header and may be as large as the context.
+fn payload_limit(header: &SnapshotHeader) -> u64 {
+    fn element_size(memory_type: ModelKVMemoryType) -> u64 {
+        match memory_type {
+            ModelKVMemoryType::Float16 => 2,
+            ModelKVMemoryType::Float32 => 4,
+        }
+    }
+
+    let hparams = &header.hyperparameters;
+    let [n_ctx, n_layers, d_model, n_vocab] = [
+        hparams.max_seq_len,
+        hparams.n_layers,
+        hparams.d_model,
+        hparams.n_vocab,
+    ]
+    .map(|n| n.max(0) as u64);
+    let memory = n_ctx.saturating_mul(n_layers).saturating_mul(d_model);
+    let tokens = n_ctx.saturating_add(header.n_past as u64);
+
+    memory
+        .saturating_mul(element_size(header.memory_k_type) + element_size(header.memory_v_type))
+        .saturating_add(n_vocab.saturating_mul(std::mem::size_of::<f32>() as u64))
+        .saturating_add(tokens.saturating_mul(std::mem::size_of::<TokenId>() as u64))
+        .saturating_add(MAX_HEADER_SIZE)
+}
+
+/// Counts and checksums everything written to it, without storing it.
+struct ChecksumWriter {
+    hasher: crc32fast::Hasher,
+    len: u64,
+}
+
+impl Write for ChecksumWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.hasher.update(buf);
+        self.len += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> InferenceSnapshotRef<'a> {
+    /// Writes the uncompressed payload, in the layout `bincode::serialize`
+    /// gives an `InferenceSnapshot`.
+    fn write_payload(&self, mut writer: impl Write) -> Result<(), SnapshotError> {
+        bincode::serialize_into(&mut writer, &self.npast)?;
+        bincode::serialize_into(&mut writer, &self.session_params)?;
+        self.memory_k.write_to(&mut writer)?;
+        self.memory_v.write_to(&mut writer)?;
+        bincode::serialize_into(&mut writer, self.last_n_tokens)?;
+        bincode::serialize_into(&mut writer, self.logits)?;
+        Ok(())
+    }
+
+    /// Compresses the payload as requested in the header.
+    fn compress_payload(&self) -> Result<Vec<u8>, SnapshotError> {
+        Ok(match self.header.compression {
+            SnapshotCompression::None => unreachable!("uncompressed payloads are not buffered"),
+            SnapshotCompression::Zstd => {
+                let mut encoder = zstd::Encoder::new(Vec::new(), ZSTD_LEVEL)?;
+                self.write_payload(&mut encoder)?;
+                encoder.finish()?
+            }
+            SnapshotCompression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                self.write_payload(&mut encoder)?;
+                encoder.finish().map_err(std::io::Error::from)?
+            }
+        })
+    }
+
+    /// Writes the snapshot as
+    /// `magic | version | header length | header | payload length | payload crc32 | payload`,
+    /// all integers little endian. The length and checksum are those of the
+    /// stored, possibly compressed, payload.
+    pub fn write(&self, mut writer: impl Write) -> Result<(), SnapshotError> {
+        let header = bincode::serialize(&self.header)?;
+
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&SNAPSHOT_FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&(header.len() as u32).to_le_bytes())?;
+        writer.write_all(&header)?;
+
+        if self.header.compression == SnapshotCompression::None {
+            // The length and checksum come first, so the payload is hashed
+            // before it is written instead of being buffered. Apart from a few
+            // small fields, both passes go straight over the memory tensors.
+            let mut checksum = ChecksumWriter {
+                hasher: crc32fast::Hasher::new(),
+                len: 0,
+            };
+            self.write_payload(&mut checksum)?;
+
+            writer.write_all(&checksum.len.to_le_bytes())?;
+            writer.write_all(&checksum.hasher.finalize().to_le_bytes())?;
+            self.write_payload(&mut writer)?;
+        } else {
+            let payload = self.compress_payload()?;
+            writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+            writer.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+            writer.write_all(&payload)?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, SnapshotError> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, SnapshotError> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Reads the magic and the header. If the data is not in the versioned format,
+/// the bytes consumed while looking for the magic are returned instead.
+fn read_header(
+    reader: &mut impl Read,
+) -> Result<Result<SnapshotHeader, [u8; MAGIC.len()]>, SnapshotError> {
+    let mut magic = [0u8; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Ok(Err(magic));
+    }
+
+    let version = read_u32(reader)?;
+    if version == 0 || version > SNAPSHOT_FORMAT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion { version });
+    }
+
+    let header_len = read_u32(reader)? as u64;
+    if header_len > MAX_HEADER_SIZE {
+        return Err(SnapshotError::InvalidHeader);
+    }
+    if version == 1 {
+        let header: SnapshotHeaderV1 =
+            bincode_options(header_len).deserialize_from(reader.by_ref().take(header_len))?;
+        return Ok(Ok(header.into()));
+    }
+    let header = bincode_options(header_len).deserialize_from(reader.by_ref().take(header_len))?;
+    Ok(Ok(header))
+}
+
+impl InferenceSnapshot {
+    /// Borrows the snapshot for writing. Fails for snapshots in the original
+    /// layout, restore those and take a new snapshot of the session instead.
+    fn as_ref(
+        &self,
+        compression: SnapshotCompression,
+    ) -> Result<InferenceSnapshotRef<'_>, SnapshotError> {
+        let header = self.header.as_ref().ok_or(SnapshotError::InvalidHeader)?;
+        Ok(InferenceSnapshotRef {
+            header: SnapshotHeader {
+                compression,
+                ..header.clone()
+            },
+            npast: self.npast,
+            session_params: self.session_params,
+            memory_k: MemoryChunks(vec![&self.memory_k]),
+            memory_v: MemoryChunks(vec![&self.memory_v]),
+            last_n_tokens: &self.last_n_tokens,
+            logits: &self.last_logits,
+        })
+    }
+
+    /// Writes the snapshot in the current format, see `InferenceSession::write_snapshot`.
+    pub fn write(
+        &self,
+        writer: impl Write,
+        compression: SnapshotCompression,
+    ) -> Result<(), SnapshotError> {
+        self.as_ref(compression)?.write(writer)
+    }
+
+    pub fn write_to_disk(
+        &self,
+        path: impl AsRef<Path>,
+        compression: SnapshotCompression,
+    ) -> Result<(), SnapshotError> {
+        let writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write(writer, compression)
+    }
+
+    /// Reads a snapshot in the current format. Snapshots in the original,
+    /// unversioned layout are still accepted; they have no header, so the
+    /// model they belong to cannot be verified. To migrate one, restore it with
+    /// `Model::session_from_snapshot` and write a new snapshot of the session.
+    ///
+    /// `size_limit` is the most bytes the snapshot may take up in `reader`,
+    /// e.g. the size of the file it is read from.
+    pub fn read(reader: &mut impl Read, size_limit: u64) -> Result<Self, SnapshotError> {
+        let header = match read_header(reader)? {
+            Ok(header) => header,
+            Err(magic) => {
+                let mut legacy = std::io::Cursor::new(magic).chain(reader);
+                return Ok(bincode_options(size_limit).deserialize_from(&mut legacy)?);
+            }
+        };
+
+        let payload_len = read_u64(reader)?;
+        let expected_checksum = read_u32(reader)?;
+        if payload_len > size_limit {
+            return Err(SnapshotError::InvalidHeader);
+        }
+
+        // Read incrementally, so a wrong length fails with an I/O error instead
+        // of a huge allocation.
+        let mut payload = Vec::new();
+        reader
+            .by_ref()
+            .take(payload_len)
+            .read_to_end(&mut payload)?;
+        if payload.len() as u64 != payload_len {
+            return Err(SnapshotError::IO(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        if crc32fast::hash(&payload) != expected_checksum {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+
+        // A matching checksum does not make the payload trustworthy, whoever
+        // wrote the file also wrote the checksum. Decompression is bounded by
+        // the largest payload the header allows.
+        let limit = payload_limit(&header);
+        let mut snapshot: Self = match header.compression {
+            SnapshotCompression::None => {
+                bincode_options(payload_len.min(limit)).deserialize(&payload)?
+            }
+            SnapshotCompression::Zstd => bincode_options(limit)
+                .deserialize_from(zstd::Decoder::new(payload.as_slice())?.take(limit))?,
+            SnapshotCompression::Lz4 => bincode_options(limit).deserialize_from(
+                lz4_flex::frame::FrameDecoder::new(payload.as_slice()).take(limit),
+            )?,
+        };
+        if snapshot.npast != header.n_past
+            || snapshot.session_params.memory_k_type != header.memory_k_type
+            || snapshot.session_params.memory_v_type != header.memory_v_type
+        {
+            return Err(SnapshotError::InvalidHeader);
+        }
+        snapshot.header = Some(header);
+
+        Ok(snapshot)
+    }
+
+    /// Reads only the header of a snapshot file. Returns `None` for snapshots
+    /// in the original, unversioned layout.
+    pub fn read_header(path: impl AsRef<Path>) -> Result<Option<SnapshotHeader>, SnapshotError> {
+        let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        Ok(read_header(&mut reader)?.ok())
+    }
+
+    pub fn load_from_disk(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        use std::fs::File;
+        use std::io::BufReader;
+
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let size_limit = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        Self::read(&mut reader, size_limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const N_PAST: usize = 3;
+
+    fn header(compression: SnapshotCompression) -> SnapshotHeader {
+        SnapshotHeader {
+            model_hash: [7; 32],
+            hyperparameters: Hyperparameters {
+                d_model: 4,
+                max_seq_len: 8,
+                n_layers: 2,
+                n_vocab: 3,
+                ..Default::default()
+            },
+            memory_k_type: ModelKVMemoryType::Float16,
+            memory_v_type: ModelKVMemoryType::Float32,
+            n_past: N_PAST,
+            compression,
+        }
+    }
+
+    fn snapshot() -> InferenceSnapshot {
+        InferenceSnapshot {
+            header: Some(header(SnapshotCompression::None)),
+            npast: N_PAST,
+            session_params: InferenceSessionParameters {
+                last_n_size: 4,
+                memory_k_type: ModelKVMemoryType::Float16,
+                memory_v_type: ModelKVMemoryType::Float32,
+            },
+            memory_k: (0..48).collect(),
+            memory_v: vec![0; 96],
+            last_n_tokens: VecDeque::from([0, 1, 2, 3]),
+            last_logits: vec![0.5, -1.0, 2.25],
+        }
+    }
+
+    fn write(compression: SnapshotCompression) -> Vec<u8> {
+        let mut bytes = vec![];
+        snapshot().write(&mut bytes, compression).unwrap();
+        bytes
+    }
+
+    /// Reads like `load_from_disk`, with the size of the data as the limit.
+    fn read(bytes: &[u8]) -> Result<InferenceSnapshot, SnapshotError> {
+        InferenceSnapshot::read(&mut &*bytes, bytes.len() as u64)
+    }
+
+    fn assert_payload(actual: &InferenceSnapshot) {
+        let expected = snapshot();
+        assert_eq!(actual.npast, expected.npast);
+        assert_eq!(actual.session_params, expected.session_params);
+        assert_eq!(actual.memory_k, expected.memory_k);
+        assert_eq!(actual.memory_v, expected.memory_v);
+        assert_eq!(actual.last_n_tokens, expected.last_n_tokens);
+        assert_eq!(actual.last_logits, expected.last_logits);
+    }
+
+    /// The payload as serde serializes it, which is how the original layout
+    /// was written and what `write_payload` has to match.
+    fn serialized_payload() -> Vec<u8> {
+        let s = snapshot();
+        bincode::serialize(&(
+            s.npast,
+            s.session_params,
+            s.memory_k,
+            s.memory_v,
+            s.last_n_tokens,
+            s.last_logits,
+        ))
+        .unwrap()
+    }
+
+    /// Where the payload starts, after the header and its length and checksum.
+    fn payload_offset(bytes: &[u8]) -> usize {
+        let header_len = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+        16 + header_len + 8 + 4
+    }
+
+    const COMPRESSIONS: [SnapshotCompression; 3] = [
+        SnapshotCompression::None,
+        SnapshotCompression::Zstd,
+        SnapshotCompression::Lz4,
+    ];
+
+    #[test]
+    fn round_trip() {
+        for compression in COMPRESSIONS {
+            let snapshot = read(&write(compression)).unwrap();
+            assert_eq!(snapshot.header, Some(header(compression)));
+            assert_payload(&snapshot);
+        }
+    }
+
+    #[test]
+    fn header_without_payload() {
+        let bytes = write(SnapshotCompression::Zstd);
+        let mut reader = &bytes[..payload_offset(&bytes)];
+        let parsed = read_header(&mut reader).unwrap();
+        assert_eq!(parsed.ok(), Some(header(SnapshotCompression::Zstd)));
+    }
+
+    #[test]
+    fn compression_parses_case_insensitively() {
+        for compression in COMPRESSIONS {
+            let name = compression.to_string().to_uppercase();
+            assert_eq!(name.parse::<SnapshotCompression>(), Ok(compression));
+        }
+        assert!("gzip".parse::<SnapshotCompression>().is_err());
+    }
+
+    #[test]
+    fn writes_the_serialized_layout() {
+        let bytes = write(SnapshotCompression::None);
+        assert_eq!(bytes[payload_offset(&bytes)..], serialized_payload());
+    }
+
+    #[test]
+    fn reads_unversioned_layout() {
+        let snapshot = read(&serialized_payload()).unwrap();
+        assert_eq!(snapshot.header, None);
+        assert_payload(&snapshot);
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let header = header(SnapshotCompression::None);
+        let header_v1 = bincode::serialize(&(
+            header.model_hash,
+            &header.hyperparameters,
+            header.memory_k_type,
+            header.memory_v_type,
+            header.n_past,
+        ))
+        .unwrap();
+        let payload = serialized_payload();
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend((header_v1.len() as u32).to_le_bytes());
+        bytes.extend(&header_v1);
+        bytes.extend((payload.len() as u64).to_le_bytes());
+        bytes.extend(crc32fast::hash(&payload).to_le_bytes());
+        bytes.extend(&payload);
+
+        let snapshot = read(&bytes).unwrap();
+        assert_eq!(snapshot.header, Some(header));
+        assert_payload(&snapshot);
+    }
+
+    #[test]
+    fn rejects_corrupted_payload() {
+        for compression in COMPRESSIONS {
+            let mut bytes = write(compression);
+            let offset = payload_offset(&bytes);
+            bytes[offset + 1] ^= 0x40;
+            assert!(matches!(read(&bytes), Err(SnapshotError::ChecksumMismatch)));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_payload() {
+        let bytes = write(SnapshotCompression::None);
+        let truncated = &bytes[..bytes.len() - 10];
+        assert!(matches!(
+            InferenceSnapshot::read(&mut &*truncated, bytes.len() as u64),
+            Err(SnapshotError::IO(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_payload_larger_than_the_limit() {
+        let bytes = write(SnapshotCompression::None);
+        assert!(matches!(
+            InferenceSnapshot::read(&mut &*bytes, 64),
+            Err(SnapshotError::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn rejects_decompressed_payload_larger_than_the_header_allows() {
+        let mut snapshot = snapshot();
+        let limit = payload_limit(snapshot.header.as_ref().unwrap()) as usize;
+        snapshot.memory_k = vec![0; limit];
+        for compression in [SnapshotCompression::Zstd, SnapshotCompression::Lz4] {
+            let mut bytes = vec![];
+            snapshot.write(&mut bytes, compression).unwrap();
+            assert!(bytes.len() < limit / 10);
+            assert!(matches!(read(&bytes), Err(SnapshotError::Serialization(_))));
+        }
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        let mut bytes = write(SnapshotCompression::None);
+        bytes[8..12].copy_from_slice(&(SNAPSHOT_FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            read(&bytes),
+            Err(SnapshotError::UnsupportedVersion { version }) if version == SNAPSHOT_FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_header_not_matching_payload() {
+        let mut snapshot = snapshot();
+        snapshot.header.as_mut().unwrap().n_past = N_PAST + 1;
+        let mut bytes = vec![];
+        snapshot
+            .write(&mut bytes, SnapshotCompression::Lz4)
+            .unwrap();
+        assert!(matches!(read(&bytes), Err(SnapshotError::InvalidHeader)));
+    }
+}
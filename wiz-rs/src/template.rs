@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+/// Describes how prompts for a model are laid out. `{input}` in `user` is
+/// replaced with the text of the turn, `{output}` in `assistant` with the
+/// answer of the model and `{context}` in `context` with the rendered
+/// `ShellContext`. `example` uses both `{input}` and `{output}`.
+///
+/// Templates can be stored as TOML files with one key per field; missing
+/// fields are empty.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct PromptTemplate {
+    /// Placed in front of the first turn, e.g. instructions for the model.
+    /// The same for every prompt, so its model state can be cached.
+    pub system: String,
+    /// Describes the user's environment, placed in front of the first turn.
+    /// Left out if empty or if there is no context.
+    pub context: String,
+    /// A demonstration of a query and its command, repeated for every few-shot
+    /// example in front of the first turn. Examples are left out if empty.
+    pub example: String,
+    /// A turn of the user.
+    pub user: String,
+    /// A previous answer of the model.
+    pub assistant: String,
+    /// Placed between an answer and the next turn of the user.
+    pub separator: String,
+    /// Starts the answer of the model, which continues from here.
+    pub response_prefix: String,
+}
+
+impl PromptTemplate {
+    fn user_turn(&self, input: &str) -> String {
+        self.user.replace("{input}", input) + &self.response_prefix
+    }
+
+    fn context_block(&self, context: Option<&str>) -> String {
+        match context {
+            Some(context) => self.context.replace("{context}", context),
+            None => String::new(),
+        }
+    }
+
+    fn examples_block(&self, examples: &[(&str, &str)]) -> String {
+        examples
+            .iter()
+            .map(|(input, output)| {
+                self.example
+                    .replace("{input}", input)
+                    .replace("{output}", output)
+            })
+            .collect()
+    }
+
+    /// How a turn of the user starts, e.g. `### Input:`. A model that goes on
+    /// to write the next turn itself is done with its answer when it writes
+    /// this.
+    pub fn turn_start(&self) -> Option<&str> {
+        let start = self.user.split("{input}").next()?.trim();
+        (!start.is_empty()).then_some(start)
+    }
+
+    /// Everything after `system` for the first turn of a conversation, with
+    /// the given `(query, command)` pairs as few-shot examples.
+    pub fn first_turn(
+        &self,
+        input: &str,
+        context: Option<&str>,
+        examples: &[(&str, &str)],
+    ) -> String {
+        self.context_block(context) + &self.examples_block(examples) + &self.user_turn(input)
+    }
+
+    /// Continues a conversation right after the last answer of the model.
+    pub fn follow_up(&self, input: &str) -> String {
+        self.separator.clone() + &self.user_turn(input)
+    }
+
+    /// The complete prompt for `input`, after the given earlier
+    /// `(input, answer)` turns.
+    pub fn render(
+        &self,
+        history: &[(&str, &str)],
+        input: &str,
+        context: Option<&str>,
+        examples: &[(&str, &str)],
+    ) -> String {
+        let mut prompt =
+            self.system.clone() + &self.context_block(context) + &self.examples_block(examples);
+        for (previous_input, answer) in history {
+            prompt += &self.user_turn(previous_input);
+            prompt += &self.assistant.replace("{output}", answer);
+            prompt += &self.separator;
+        }
+        prompt + &self.user_turn(input)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error("could not read template {path:?}")]
+    IO {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("invalid template {path:?}")]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+    #[error("unknown prompt template `{name}`")]
+    Unknown { name: String },
+}
+
+/// Named prompt templates. Starts out with the built-in templates, which are
+/// overridden by files of the same name loaded with `load_dir`.
+#[derive(Clone, Debug)]
+pub struct PromptTemplates {
+    templates: HashMap<String, PromptTemplate>,
+}
+
+impl Default for PromptTemplates {
+    fn default() -> Self {
+        let builtin = |system: &str,
+                       context: &str,
+                       example: &str,
+                       user: &str,
+                       separator: &str,
+                       response_prefix: &str| {
+            PromptTemplate {
+                system: system.to_string(),
+                context: context.to_string(),
+                example: example.to_string(),
+                user: user.to_string(),
+                assistant: "{output}".to_string(),
+                separator: separator.to_string(),
+                response_prefix: response_prefix.to_string(),
+            }
+        };
+
+        let templates = HashMap::from([
+            (
+                "wiz".to_string(),
+                builtin(
+                    "### Instruction:\nConvert to bash command, provide detailed explanation in a second paragraph\n\n",
+                    "### Context:\n{context}\n\n",
+                    "### Example:\n{input}\n```bash\n{output}\n```\n\n",
+                    "### Input:\n{input}\n\n",
+                    "\n\n",
+                    "### Response:\n```bash\n",
+                ),
+            ),
+            (
+                "alpaca".to_string(),
+                builtin(
+                    "Below is an instruction that describes a task. Write a response that appropriately completes the request.\n\n",
+                    "### Environment:\n{context}\n\n",
+                    "### Example:\n{input}\n{output}\n\n",
+                    "### Instruction:\n{input}\n\n",
+                    "\n\n",
+                    "### Response:\n",
+                ),
+            ),
+            (
+                "explain".to_string(),
+                builtin(
+                    "### Instruction:\nExplain the bash command. Start with one sentence on what it does as a whole, then explain every numbered part on its own line, with the same number\n\n",
+                    "",
+                    "",
+                    "### Input:\n{input}\n",
+                    "\n\n",
+                    "### Response:\n",
+                ),
+            ),
+            (
+                "chat".to_string(),
+                builtin(
+                    "",
+                    "<|SYSTEM|>The user's environment:\n{context}\n",
+                    "<|USER|>{input}<|ASSISTANT|>{output}",
+                    "<|USER|>{input}",
+                    "",
+                    "<|ASSISTANT|>",
+                ),
+            ),
+        ]);
+        Self { templates }
+    }
+}
+
+impl PromptTemplates {
+    /// Loads every `<name>.toml` file in `dir`. A missing directory is not an
+    /// error.
+    pub fn load_dir(&mut self, dir: impl AsRef<Path>) -> Result<(), TemplateError> {
+        let dir = dir.as_ref();
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(source) => {
+                return Err(TemplateError::IO {
+                    path: dir.to_owned(),
+                    source,
+                })
+            }
+        };
+
+        for entry in entries {
+            let path = entry
+                .map_err(|source| TemplateError::IO {
+                    path: dir.to_owned(),
+                    source,
+                })?
+                .path();
+            if path.extension().map_or(true, |ext| ext != "toml") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+
+            let text = std::fs::read_to_string(&path).map_err(|source| TemplateError::IO {
+                path: path.clone(),
+                source,
+            })?;
+            let template = toml::from_str(&text).map_err(|source| TemplateError::Parse {
+                path: path.clone(),
+                source,
+            })?;
+            self.templates.insert(name.to_string(), template);
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<&PromptTemplate, TemplateError> {
+        self.templates
+            .get(name)
+            .ok_or_else(|| TemplateError::Unknown {
+                name: name.to_string(),
+            })
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, template: PromptTemplate) {
+        self.templates.insert(name.into(), template);
+    }
+
+    /// The names of all templates, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.templates.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    /// The template to use for the model at `model_path`: the one named like
+    /// the model file (`model.toml` for `model.bin`) if it exists, otherwise
+    /// `default`.
+    pub fn name_for_model<'a>(&'a self, model_path: &Path, default: &'a str) -> &'a str {
+        model_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| self.templates.get_key_value(stem))
+            .map_or(default, |(name, _)| name.as_str())
+    }
+}
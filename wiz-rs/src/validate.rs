@@ -0,0 +1,556 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::Read,
+    path::Path,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    find_executable,
+    safety::{program, unwrap_command},
+    shell::{self, SimpleCommand, Span},
+};
+
+/// Shell builtins and keywords, which are never found on `PATH`.
+const BUILTINS: &[&str] = &[
+    ".",
+    ":",
+    "[",
+    "[[",
+    "alias",
+    "bg",
+    "bind",
+    "break",
+    "builtin",
+    "caller",
+    "cd",
+    "command",
+    "compgen",
+    "complete",
+    "continue",
+    "declare",
+    "dirs",
+    "disown",
+    "echo",
+    "enable",
+    "eval",
+    "exec",
+    "exit",
+    "export",
+    "false",
+    "fc",
+    "fg",
+    "getopts",
+    "hash",
+    "help",
+    "history",
+    "jobs",
+    "kill",
+    "let",
+    "local",
+    "logout",
+    "mapfile",
+    "popd",
+    "printf",
+    "pushd",
+    "pwd",
+    "read",
+    "readarray",
+    "readonly",
+    "return",
+    "set",
+    "shift",
+    "shopt",
+    "source",
+    "suspend",
+    "test",
+    "times",
+    "trap",
+    "true",
+    "type",
+    "typeset",
+    "ulimit",
+    "umask",
+    "unalias",
+    "unset",
+    "wait",
+];
+
+/// Programs whose arguments look like options but are not, e.g. `chmod -x`.
+const FREEFORM_ARGUMENTS: &[&str] = &["chmod", "expr", "printf", "test", "["];
+
+/// How long looking up a help text or checking the syntax may take.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IssueKind {
+    /// The command does not parse.
+    Syntax,
+    /// A program is not installed.
+    MissingExecutable,
+    /// An option is not mentioned in the help text of its program.
+    UnknownFlag,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidationIssue {
+    pub kind: IssueKind,
+    pub message: String,
+    /// The part of the command the issue is about, if known.
+    pub span: Option<Span>,
+}
+
+/// The outcome of `CommandValidator::validate`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Validation {
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn add(&mut self, kind: IssueKind, span: Option<Span>, message: String) {
+        self.issues.push(ValidationIssue {
+            kind,
+            message,
+            span,
+        });
+    }
+}
+
+/// Checks commands against the local system: whether the shell syntax parses
+/// with `bash -n`, whether every program is on `PATH` and whether the options
+/// used appear in the man page of their program. Help texts are cached, create
+/// one validator and keep it around.
+#[derive(Default)]
+pub struct CommandValidator {
+    /// Help texts by program and subcommand, `None` if there is none.
+    help: RefCell<HashMap<String, Option<String>>>,
+    /// Programs that may be run with `--help` when they have no man page.
+    run_help: Vec<String>,
+}
+
+impl CommandValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets the validator run `<program> --help` and `<program> -h` for these
+    /// programs when they have no man page. Generated commands name arbitrary
+    /// programs, so nothing else is ever run, and these only when they are
+    /// found by name in an absolute directory on `PATH`.
+    pub fn run_help_of(mut self, programs: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.run_help = programs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Validates `command`. `cwd` is where it would run, relative paths to
+    /// programs are only checked if it is known. The options of programs
+    /// given by path are not checked.
+    pub fn validate(&self, command: &str, cwd: Option<&Path>) -> Validation {
+        let mut validation = Validation::default();
+
+        let script = match shell::parse(command) {
+            Ok(script) => script,
+            Err(err) => {
+                let span = Span {
+                    start: err.offset,
+                    end: command.len(),
+                };
+                validation.add(IssueKind::Syntax, Some(span), err.message);
+                return validation;
+            }
+        };
+        if let Some(message) = bash_syntax_error(command) {
+            validation.add(IssueKind::Syntax, None, message);
+            return validation;
+        }
+
+        for command in script.commands() {
+            self.check_command(&mut validation, command, cwd);
+        }
+        validation
+    }
+
+    fn check_command(
+        &self,
+        validation: &mut Validation,
+        command: &SimpleCommand,
+        cwd: Option<&Path>,
+    ) {
+        let (words, _) = unwrap_command(&command.words);
+        let Some(name) = words.first() else {
+            return;
+        };
+        if name.text.contains(['$', '`']) || BUILTINS.contains(&name.text.as_str()) {
+            return;
+        }
+
+        if name.text.contains('/') {
+            let path = Path::new(&name.text);
+            let exists = match cwd {
+                _ if path.is_absolute() => path.is_file(),
+                Some(cwd) => cwd.join(path).is_file(),
+                None => return,
+            };
+            if !exists {
+                let message = format!("`{}` does not exist", name.text);
+                validation.add(IssueKind::MissingExecutable, Some(name.span), message);
+            }
+            // Whatever the path points to, it is not what the man pages are
+            // about
+            return;
+        }
+        let Some(path) = find_executable(&name.text) else {
+            let message = format!("`{}` is not installed", name.text);
+            validation.add(IssueKind::MissingExecutable, Some(name.span), message);
+            return;
+        };
+
+        let program = program(name);
+        if FREEFORM_ARGUMENTS.contains(&program) || program.starts_with('-') {
+            return;
+        }
+        let args = &words[1..];
+        let Some(help) = self.help_text(program, &path, None) else {
+            return;
+        };
+        // Options of tools like `git commit` are documented by the subcommand
+        let subcommand = args
+            .first()
+            .filter(|word| {
+                !word.quoted
+                    && word
+                        .text
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+                    && !word.text.starts_with('-')
+                    && mentions(&help, &word.text)
+            })
+            .map(|word| self.help_text(program, &path, Some(&word.text)));
+        // Without the help of the subcommand its options cannot be checked
+        if let Some(None) = subcommand {
+            return;
+        }
+        let subcommand = subcommand.flatten();
+
+        for word in args {
+            let text = word.text.as_str();
+            if text == "--" {
+                break;
+            }
+            if word.quoted || !text.starts_with('-') || text == "-" || text.contains(['$', '`']) {
+                continue;
+            }
+            let known = |flag: &str| {
+                mentions(&help, flag)
+                    || subcommand
+                        .as_ref()
+                        .map_or(false, |help| mentions(help, flag))
+            };
+
+            let unknown: Vec<String> = if let Some(long) = text.strip_prefix("--") {
+                let flag = format!("--{}", long.split('=').next().unwrap_or_default());
+                if known(&flag) {
+                    continue;
+                }
+                vec![flag]
+            } else if known(text) {
+                // Single dash long options like `find -name`
+                continue;
+            } else if text[1..].chars().all(|c| c.is_ascii_alphabetic()) {
+                // Clustered short options like `-la`. Clusters with digits or
+                // punctuation usually end in the value of an option, like
+                // `-n5`, and are not checked
+                text[1..]
+                    .chars()
+                    .map(|c| format!("-{c}"))
+                    .filter(|flag| !known(flag))
+                    .collect()
+            } else {
+                continue;
+            };
+
+            for flag in unknown {
+                validation.add(
+                    IssueKind::UnknownFlag,
+                    Some(word.span),
+                    format!("`{program}` has no option `{flag}`"),
+                );
+            }
+        }
+    }
+
+    /// The man page of a program, or else its `--help` output if it may be
+    /// run, see `run_help_of`.
+    fn help_text(&self, program: &str, path: &Path, subcommand: Option<&str>) -> Option<String> {
+        let key = match subcommand {
+            Some(subcommand) => format!("{program} {subcommand}"),
+            None => program.to_string(),
+        };
+        if let Some(help) = self.help.borrow().get(&key) {
+            return help.clone();
+        }
+
+        let page = match subcommand {
+            Some(subcommand) => format!("{program}-{subcommand}"),
+            None => program.to_string(),
+        };
+        let man = find_executable("man").and_then(|man| {
+            let mut command = Command::new(man);
+            command.args(["-P", "cat", "--", &page]);
+            run_with_timeout(command).filter(|(success, _)| *success)
+        });
+        // The usage subcommands print is a summary, only their man pages list
+        // every option. Programs often exit with an error after printing their
+        // usage, or print an error for one of the flags, so the longest output
+        // wins. Very short output is an error message rather than a help text
+        let run_help = subcommand.is_none()
+            && path.is_absolute()
+            && self.run_help.iter().any(|allowed| allowed == program);
+        let help = match man {
+            Some((_, man)) => Some(man),
+            None if !run_help => None,
+            None => ["--help", "-h"]
+                .into_iter()
+                .filter_map(|flag| {
+                    let mut command = Command::new(path);
+                    command.arg(flag);
+                    run_with_timeout(command).map(|(_, help)| help)
+                })
+                .max_by_key(String::len),
+        };
+        let help = help
+            .map(|help| strip_overstrike(&help))
+            .filter(|help| help.len() > 80 && !help.contains("not the full help"));
+
+        self.help.borrow_mut().insert(key, help.clone());
+        help
+    }
+}
+
+/// The syntax error `bash -n` reports, if any. `None` as well if bash is not
+/// available.
+fn bash_syntax_error(command: &str) -> Option<String> {
+    let bash = find_executable("bash")?;
+    let output = Command::new(bash)
+        .args(["-n", "-c", command])
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    if output.status.success() {
+        return None;
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let message = stderr
+        .lines()
+        .map(|line| line.split_once("-c: ").map_or(line, |(_, message)| message))
+        .collect::<Vec<_>>()
+        .join("; ");
+    Some(message)
+}
+
+/// Runs `command` with the C locale, returning whether it succeeded and its
+/// output, stdout followed by stderr. `None` if it did not finish within
+/// `TIMEOUT`, it is killed along with everything it started then.
+fn run_with_timeout(mut command: Command) -> Option<(bool, String)> {
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = command
+        .env("LC_ALL", "C")
+        .env("MANWIDTH", "120")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+
+    // Read in the background so a long output cannot block the child
+    let read = |mut pipe: Box<dyn Read + Send>| {
+        std::thread::spawn(move || {
+            let mut output = vec![];
+            pipe.read_to_end(&mut output).map(|_| output)
+        })
+    };
+    let stdout = read(Box::new(child.stdout.take()?));
+    let stderr = read(Box::new(child.stderr.take()?));
+
+    let deadline = Instant::now() + TIMEOUT;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+            _ => {
+                kill_process_group(&mut child);
+                _ = child.wait();
+                return None;
+            }
+        }
+    };
+
+    let mut output = stdout.join().ok()?.ok()?;
+    output.extend(stderr.join().ok()?.ok()?);
+    Some((
+        status.success(),
+        String::from_utf8_lossy(&output).into_owned(),
+    ))
+}
+
+/// Kills `child` and the processes it started, which are in its own process
+/// group.
+#[cfg(unix)]
+fn kill_process_group(child: &mut Child) {
+    // SAFETY: Only sends a signal, the group id is the pid of our child,
+    // which has not been waited for yet
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(child: &mut Child) {
+    _ = child.kill();
+}
+
+/// Removes the backspace sequences man uses for bold and underlined text.
+fn strip_overstrike(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\u{8}' {
+            stripped.pop();
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Whether `text` contains `word` on its own, not as part of a longer word or
+/// option.
+fn mentions(text: &str, word: &str) -> bool {
+    let is_word_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    text.match_indices(word).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + word.len()..].chars().next();
+        !before.map_or(false, is_word_char) && !after.map_or(false, is_word_char)
+    })
+}
+
+/// Describes the problems of a command so the model can correct it.
+pub fn validation_feedback(command: &str, validation: &Validation) -> String {
+    let issues: Vec<&str> = validation
+        .issues
+        .iter()
+        .map(|issue| issue.message.as_str())
+        .collect();
+    format!(
+        "The command `{}` does not work: {}. Answer again with a corrected command.",
+        command.trim(),
+        issues.join("; ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A validator that knows `help` as the help text of `ls`, instead of
+    /// depending on the man pages installed.
+    fn validator(help: &str) -> CommandValidator {
+        let validator = CommandValidator::new();
+        validator
+            .help
+            .borrow_mut()
+            .insert("ls".to_string(), Some(help.to_string()));
+        validator
+    }
+
+    const LS_HELP: &str = "Usage: ls [OPTION]... [FILE]...
+  -a, --all                  do not ignore entries starting with .
+  -l                         use a long listing format
+  -h, --human-readable       with -l, print sizes like 1K 234M 2G
+      --color[=WHEN]         color the output WHEN; more info below
+      --sort=WORD            sort by WORD instead of name";
+
+    fn unknown_flags(command: &str) -> Vec<String> {
+        validator(LS_HELP)
+            .validate(command, None)
+            .issues
+            .into_iter()
+            .map(|issue| {
+                assert_eq!(issue.kind, IssueKind::UnknownFlag);
+                issue.message
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mentions_whole_words() {
+        assert!(mentions("  -l, --long  use a long format", "-l"));
+        assert!(mentions("see --color[=WHEN]", "--color"));
+        assert!(mentions("sort by WORD (--sort=WORD)", "--sort"));
+        assert!(!mentions("  --long-format", "--long"));
+        assert!(!mentions("  --all", "-a"));
+        assert!(!mentions("the -la flags", "-l"));
+        assert!(!mentions("", "-l"));
+    }
+
+    #[test]
+    fn clustered_short_options() {
+        assert!(unknown_flags("ls -lah").is_empty());
+        assert_eq!(unknown_flags("ls -laZ"), ["`ls` has no option `-Z`"]);
+        // Clusters with digits usually end in a value and are not checked
+        assert!(unknown_flags("ls -w80").is_empty());
+    }
+
+    #[test]
+    fn long_options_with_values() {
+        assert!(unknown_flags("ls --sort=size --color=auto --all").is_empty());
+        assert_eq!(
+            unknown_flags("ls --order=size"),
+            ["`ls` has no option `--order`"]
+        );
+    }
+
+    #[test]
+    fn operands_are_not_checked() {
+        assert!(unknown_flags("ls -- -Z '-Q' \"$FLAGS\" -").is_empty());
+    }
+
+    #[test]
+    fn options_of_paths_are_not_checked() {
+        let validator = validator(LS_HELP);
+        assert!(validator.validate("/bin/ls -Z", None).is_valid());
+        let cwd = std::env::temp_dir();
+        let validation = validator.validate("./wiz-no-such-program/ls -Z", Some(&cwd));
+        assert_eq!(validation.issues.len(), 1);
+        assert_eq!(validation.issues[0].kind, IssueKind::MissingExecutable);
+    }
+
+    #[test]
+    fn missing_executable() {
+        let validation =
+            CommandValidator::new().validate("wiz-no-such-program --version | cat", None);
+        assert_eq!(validation.issues.len(), 1);
+        let issue = &validation.issues[0];
+        assert_eq!(issue.kind, IssueKind::MissingExecutable);
+        assert_eq!(issue.span, Some(Span { start: 0, end: 19 }));
+    }
+
+    #[test]
+    fn syntax_error() {
+        let validation = CommandValidator::new().validate("echo \"unterminated", None);
+        assert!(!validation.is_valid());
+        assert_eq!(validation.issues[0].kind, IssueKind::Syntax);
+    }
+
+    #[test]
+    fn strips_overstrike() {
+        assert_eq!(strip_overstrike("N\u{8}NA\u{8}AM\u{8}ME\u{8}E"), "NAME");
+        assert_eq!(strip_overstrike("_\u{8}f_\u{8}i_\u{8}l_\u{8}e"), "file");
+    }
+}
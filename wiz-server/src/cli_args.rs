@@ -0,0 +1,116 @@
+use clap::{Parser, ValueEnum};
+use once_cell::sync::Lazy;
+use std::path::PathBuf;
+use wiz_rs::SnapshotCompression;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum IdleAction {
+    /// Shut the server down. The client starts it again when needed.
+    Exit,
+    /// Free the model memory but keep listening. The next request reloads it.
+    Unload,
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// The port to listen on. Ignored when a socket is passed in by the
+    /// service manager (systemd socket activation).
+    #[arg(long, short = 'p', default_value_t = 8085)]
+    pub port: u16,
+
+    /// Seconds without any inference request after which the server goes
+    /// idle, e.g. 1800 when started on demand by socket activation. 0, the
+    /// default, keeps the model loaded forever.
+    #[arg(long, default_value_t = 0)]
+    pub idle_timeout: u64,
+
+    /// What to do once the idle timeout is reached.
+    #[arg(long, value_enum, default_value_t = IdleAction::Exit)]
+    pub idle_action: IdleAction,
+
+    /// Seconds that running generations may take to finish when the server
+    /// is shutting down, before they are cancelled.
+    #[arg(long, default_value_t = 5)]
+    pub shutdown_grace_period: u64,
+
+    /// Maximum size of the prompt snapshot cache in `~/.wiz/snapshots`, in
+    /// megabytes. Least recently used snapshots are removed first.
+    #[arg(long, default_value_t = 2048)]
+    pub snapshot_cache_size: u64,
+
+    /// How to compress the cached prompt snapshots: none, zstd or lz4.
+    #[arg(long, default_value_t = SnapshotCompression::None)]
+    pub snapshot_compression: SnapshotCompression,
+
+    /// Memory for the model states of recent prompts, in megabytes. New
+    /// prompts continue from the longest shared prefix instead of evaluating
+    /// it again.
+    #[arg(long, default_value_t = 512)]
+    pub prefix_cache_size: usize,
+
+    /// How many conversations are kept. The least recently used ones are
+    /// dropped first.
+    #[arg(long, default_value_t = 32)]
+    pub max_conversations: usize,
+
+    /// The prompt template used when a request does not name one. Defaults to
+    /// the template named like the model file if there is one, else `wiz`.
+    #[arg(long)]
+    pub template: Option<String>,
+
+    /// Directory with additional prompt templates as `<name>.toml` files.
+    /// Defaults to `~/.wiz/templates`.
+    #[arg(long)]
+    pub template_dir: Option<PathBuf>,
+
+    /// Do not tell the model about the machine (OS, shell, installed tools,
+    /// working directory), neither the server's nor what clients send.
+    #[arg(long, default_value_t = false)]
+    pub no_context: bool,
+
+    /// File with few-shot examples in the `examples.txt` format. The most
+    /// similar ones are put into each prompt, except those under `# eval`.
+    /// Defaults to `~/.wiz/examples.txt` if it exists.
+    #[arg(long)]
+    pub examples: Option<PathBuf>,
+
+    /// How many few-shot examples to put into each prompt, 0 disables them.
+    #[arg(long, default_value_t = 3)]
+    pub num_examples: usize,
+
+    /// Do not check generated commands against this machine: whether their
+    /// programs are installed, their options exist and `bash -n` accepts them.
+    #[arg(long, default_value_t = false)]
+    pub no_validation: bool,
+
+    /// Programs without a man page whose `--help` output is used to check
+    /// options, e.g. `git,docker`. Validation runs no other program; these
+    /// only when found by name on `PATH`.
+    #[arg(long, value_delimiter = ',')]
+    pub validate_help_of: Vec<String>,
+
+    /// How often to generate a new answer when the commands of the previous
+    /// one fail validation, with the problems pointed out to the model. Only
+    /// for single queries, conversations just report the problems.
+    #[arg(long, default_value_t = 0)]
+    pub validation_retries: usize,
+
+    /// Do not record answered queries in `~/.wiz/journal.jsonl`. See
+    /// `wiz-cli journal` for reading it.
+    #[arg(long, default_value_t = false)]
+    pub no_journal: bool,
+
+    /// Days after which queries are removed from the journal. Set to 0 to
+    /// keep them forever.
+    #[arg(long, default_value_t = 90)]
+    pub journal_retention_days: u64,
+
+    /// Do not load the model on startup, only when the first request arrives.
+    #[arg(long, default_value_t = false)]
+    pub lazy: bool,
+}
+
+/// CLI args are stored in a lazy static variable so they're accessible from
+/// everywhere. Arguments are parsed on first access.
+pub static CLI_ARGS: Lazy<Args> = Lazy::new(Args::parse);
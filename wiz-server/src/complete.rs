@@ -0,0 +1,352 @@
+use axum::{http::StatusCode, Extension, Json};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+use tokenizers::Tokenizer;
+use wiz_rs::{
+    ConstantTokenBias, InferenceError, InferenceParameters, InferenceSession, PrefixCache,
+    ShellContext, TokenId, EOD_TOKEN_ID,
+};
+
+use crate::{lifecycle, session_params, AppState, InferenceRequest};
+
+/// Put in front of every command line, so the model continues it like a line
+/// of a shell script. The same for every request, which lets the prefix cache
+/// keep its state.
+const SCRIPT_HEADER: &str = "#!/usr/bin/env bash\n";
+
+/// At most this many completions are returned.
+const MAX_COMPLETIONS: usize = 5;
+
+/// At most this many tokens are generated per completion.
+const MAX_TOKENS: usize = 32;
+
+/// How many of the most likely first tokens are looked at to find the ones
+/// that continue a partially typed word.
+const MAX_HEALING_CANDIDATES: usize = 2000;
+
+#[derive(Deserialize)]
+pub struct CompleteRequest {
+    /// The command line typed so far.
+    input: String,
+    /// Byte offset of the cursor in `input`, the end if not given. Text after
+    /// the cursor is not rewritten, completions stop where it begins.
+    #[serde(default)]
+    cursor: Option<usize>,
+    /// How many completions to return.
+    #[serde(default)]
+    n: Option<usize>,
+    /// How many tokens to generate per completion.
+    #[serde(default)]
+    max_tokens: Option<usize>,
+    /// The client's environment, put into the script as comments.
+    #[serde(default)]
+    context: Option<ShellContext>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Completion {
+    /// Text to insert at the cursor.
+    text: String,
+    /// Log-probability of the completion, higher is more likely.
+    score: f32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CompleteResponse {
+    /// Ranked, most likely first.
+    completions: Vec<Completion>,
+    prompt_tokens: usize,
+    /// How many of the prompt tokens were restored from the prefix cache.
+    cached_tokens: usize,
+    ms: u64,
+}
+
+/// A request for the inference worker to complete a command line.
+pub struct CompleteJob {
+    /// Everything the completions continue, including `SCRIPT_HEADER`.
+    prompt: String,
+    /// Completions stop where this begins, e.g. the rest of the line after
+    /// the cursor. Empty if there is nothing after the cursor.
+    stop: String,
+    n: usize,
+    max_tokens: usize,
+    pub response_sender: flume::Sender<Result<CompleteResponse, String>>,
+}
+
+fn inference_parameters() -> InferenceParameters {
+    // Prompts are short and typing sends many requests, so batches are small
+    // and nothing is sampled: every completion is the greedy continuation of
+    // one of the most likely first tokens
+    InferenceParameters {
+        n_threads: 4,
+        n_batch: 8,
+        top_k: 1,
+        top_p: 1.0,
+        repeat_penalty: 1.0,
+        temp: 1.0,
+        bias_tokens: Box::new(ConstantTokenBias::default()),
+    }
+}
+
+/// Starts a session that has been fed `tokens`, from the longest cached prefix
+/// of them. Returns the session and how many tokens were cached.
+fn prompt_session(
+    model: &wiz_rs::Model,
+    vocab: &Tokenizer,
+    prefix_cache: &mut PrefixCache,
+    params: &InferenceParameters,
+    tokens: &[TokenId],
+) -> Result<(InferenceSession, usize), InferenceError> {
+    let (mut session, cached) = match prefix_cache.start_session(model, tokens) {
+        Ok(Some(cached)) => cached,
+        result => {
+            if let Err(err) = result {
+                log::warn!("Could not restore a cached prefix: {err}");
+            }
+            (model.start_session(session_params()), 0)
+        }
+    };
+    if cached < tokens.len() {
+        session.feed_tokens::<std::convert::Infallible>(
+            model,
+            vocab,
+            params,
+            &tokens[cached..],
+            |_| Ok(()),
+        )?;
+        prefix_cache.insert(tokens, &session);
+    }
+    Ok((session, cached))
+}
+
+/// The most likely first tokens, at most `n`, whose text starts with `healed`.
+fn first_tokens(
+    vocab: &Tokenizer,
+    logprobs: &[f32],
+    healed: &str,
+    n: usize,
+) -> Vec<(TokenId, String, f32)> {
+    let mut ranked: Vec<(usize, f32)> = logprobs.iter().copied().enumerate().collect();
+    let considered = if healed.is_empty() {
+        n
+    } else {
+        MAX_HEALING_CANDIDATES
+    }
+    .min(ranked.len());
+    if considered == 0 {
+        return vec![];
+    }
+    ranked.select_nth_unstable_by(considered - 1, |a, b| b.1.total_cmp(&a.1));
+    ranked.truncate(considered);
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    ranked
+        .into_iter()
+        .filter(|(token, _)| *token as TokenId != EOD_TOKEN_ID)
+        .filter_map(|(token, logprob)| {
+            let text = vocab.decode(vec![token as u32], true).ok()?;
+            // A token shorter than the healed text has to be followed by the
+            // rest of it, which the greedy continuation does not guarantee
+            text.starts_with(healed)
+                .then_some((token as TokenId, text, logprob))
+        })
+        .take(n)
+        .collect()
+}
+
+/// Where a completion ends: at the end of the line or where the text after
+/// the cursor begins.
+fn cut_off(text: &str, stop: &str) -> Option<usize> {
+    let newline = text.find('\n');
+    let stop = (!stop.is_empty()).then(|| text.find(stop)).flatten();
+    newline.into_iter().chain(stop).min()
+}
+
+/// Runs a `CompleteJob` on the inference worker.
+pub fn run_complete(
+    model: &wiz_rs::Model,
+    vocab: &Tokenizer,
+    prefix_cache: &mut PrefixCache,
+    job: CompleteJob,
+    cancel: &AtomicBool,
+) {
+    let started = Instant::now();
+    let params = inference_parameters();
+    let result = complete(model, vocab, prefix_cache, &params, &job, cancel).map(
+        |(completions, prompt_tokens, cached_tokens)| CompleteResponse {
+            completions,
+            prompt_tokens,
+            cached_tokens,
+            ms: started.elapsed().as_millis() as u64,
+        },
+    );
+    _ = job.response_sender.send(result);
+}
+
+fn complete(
+    model: &wiz_rs::Model,
+    vocab: &Tokenizer,
+    prefix_cache: &mut PrefixCache,
+    params: &InferenceParameters,
+    job: &CompleteJob,
+    cancel: &AtomicBool,
+) -> Result<(Vec<Completion>, usize, usize), String> {
+    let context_full = |err: InferenceError| match err {
+        InferenceError::ContextFull => "Context is not large enough to fit the input.".to_string(),
+        err => err.to_string(),
+    };
+    let mut tokens = model
+        .tokenize(vocab, &job.prompt, true)
+        .map_err(|err| err.to_string())?;
+    let prompt_tokens = tokens.len();
+
+    // Token healing: the last token of a partially typed word is most likely
+    // not the one the model would use for the whole word, e.g. `ch` in
+    // `git ch`. It is taken off the prompt and the first generated token has
+    // to start with its text instead
+    let mut healed = String::new();
+    if !job.prompt.ends_with(char::is_whitespace) && tokens.len() > 1 {
+        let last = tokens.pop().expect("checked above");
+        healed = vocab
+            .decode(vec![last], true)
+            .map_err(|err| err.to_string())?;
+    }
+
+    let (session, cached) =
+        prompt_session(model, vocab, prefix_cache, params, &tokens).map_err(context_full)?;
+    let mut candidates = first_tokens(vocab, &session.next_token_logprobs(), &healed, job.n);
+    if candidates.is_empty() && !healed.is_empty() {
+        log::debug!("No token continues `{healed}`, completing without healing");
+        candidates = first_tokens(vocab, &session.next_token_logprobs(), "", job.n);
+        healed.clear();
+    }
+    drop(session);
+
+    let mut completions: Vec<Completion> = vec![];
+    for (token, text, logprob) in candidates {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(lifecycle::SHUTDOWN_MESSAGE.to_string());
+        }
+
+        // Every candidate continues from the prompt, restored from the cache
+        let (mut session, _) =
+            prompt_session(model, vocab, prefix_cache, params, &tokens).map_err(context_full)?;
+        let mut completion = text[healed.len()..].to_string();
+        let mut score = logprob;
+        let mut next = token;
+        for _ in 1..job.max_tokens {
+            if cut_off(&completion, &job.stop).is_some() {
+                break;
+            }
+            match session.feed_tokens::<std::convert::Infallible>(
+                model,
+                vocab,
+                params,
+                &[next],
+                |_| Ok(()),
+            ) {
+                Ok(()) => {}
+                Err(InferenceError::ContextFull) => break,
+                Err(err) => return Err(err.to_string()),
+            }
+
+            let logprobs = session.next_token_logprobs();
+            let (best, logprob) = logprobs
+                .iter()
+                .copied()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .expect("the vocabulary is not empty");
+            score += logprob;
+            if best as TokenId == EOD_TOKEN_ID {
+                break;
+            }
+            next = best as TokenId;
+            completion += &vocab
+                .decode(vec![next], true)
+                .map_err(|err| err.to_string())?;
+        }
+
+        if let Some(end) = cut_off(&completion, &job.stop) {
+            completion.truncate(end);
+        }
+        let completion = completion.trim_end().to_string();
+        if completion.is_empty() || completions.iter().any(|c| c.text == completion) {
+            continue;
+        }
+        completions.push(Completion {
+            text: completion,
+            score,
+        });
+    }
+    completions.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    Ok((completions, prompt_tokens, cached))
+}
+
+/// Completes a partially typed command line: `POST /api/complete`.
+pub async fn complete_handler(
+    Extension(state): Extension<Arc<Mutex<AppState>>>,
+    Json(payload): Json<CompleteRequest>,
+) -> Result<Json<CompleteResponse>, (StatusCode, String)> {
+    let cursor = payload.cursor.unwrap_or(payload.input.len());
+    if !payload.input.is_char_boundary(cursor) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "`cursor` is not a character boundary of `input`".to_string(),
+        ));
+    }
+    let (before, after) = payload.input.split_at(cursor);
+    let n = payload.n.unwrap_or(3);
+    if n == 0 || n > MAX_COMPLETIONS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("`n` must be between 1 and {MAX_COMPLETIONS}"),
+        ));
+    }
+
+    let context = state.lock().unwrap().context(payload.context);
+    let comments: String = context
+        .iter()
+        .flat_map(|context| context.lines())
+        .map(|line| format!("# {line}\n"))
+        .collect();
+    let (rx, job) = {
+        let (tx, rx) = flume::bounded(1);
+        let job = CompleteJob {
+            prompt: format!("{SCRIPT_HEADER}{comments}{before}"),
+            stop: after.lines().next().unwrap_or_default().trim().to_string(),
+            n,
+            max_tokens: payload.max_tokens.unwrap_or(16).clamp(1, MAX_TOKENS),
+            response_sender: tx,
+        };
+        (rx, job)
+    };
+
+    if state
+        .lock()
+        .unwrap()
+        .submit(InferenceRequest::Complete(job))
+        .is_err()
+    {
+        log::error!("Could not send inference request");
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "inference worker is not running".to_string(),
+        ));
+    }
+    match rx.recv_async().await {
+        Ok(Ok(response)) => Ok(Json(response)),
+        Ok(Err(message)) => Err((StatusCode::BAD_REQUEST, message)),
+        Err(_) => Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "inference worker stopped".to_string(),
+        )),
+    }
+}
@@ -0,0 +1,342 @@
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension, Json};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use wiz_protocol::ErrorCode;
+use wiz_rs::{InferenceSnapshot, PromptTemplate, TokenId, EOD_TOKEN_ID};
+
+use crate::{
+    journal::QueryRecorder, protocol_stream, request_template, start_prompt_session, stream_answer,
+    tokenization_failed, tokenize_query, AppState, CompletionRequest, InferenceRequest,
+    InferenceResult, LoadedModel, PromptExtras,
+};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Turn {
+    role: Role,
+    text: String,
+}
+
+/// A conversation with the model. Its state is kept between turns, so follow-up
+/// queries only evaluate the new turn.
+pub struct Conversation {
+    id: String,
+    created: u64,
+    updated: u64,
+    turns: Vec<Turn>,
+    /// Name of the prompt template, fixed for the whole conversation.
+    template_name: String,
+    template: PromptTemplate,
+    /// The tokens in the model memory after the last turn.
+    tokens: Vec<TokenId>,
+    /// The model state after the last turn, `None` before the first one.
+    /// Shared, so that it is restored and replaced without holding the lock
+    /// on the `AppState`.
+    snapshot: Option<Arc<InferenceSnapshot>>,
+}
+
+#[derive(Serialize)]
+struct ConversationSummary {
+    id: String,
+    created: u64,
+    updated: u64,
+    template: String,
+    turns: usize,
+    tokens: usize,
+}
+
+#[derive(Serialize)]
+struct ConversationDetails<'a> {
+    id: &'a str,
+    created: u64,
+    updated: u64,
+    template: &'a str,
+    turns: &'a [Turn],
+}
+
+impl Conversation {
+    fn summary(&self) -> ConversationSummary {
+        ConversationSummary {
+            id: self.id.clone(),
+            created: self.created,
+            updated: self.updated,
+            template: self.template_name.clone(),
+            turns: self.turns.len(),
+            tokens: self.tokens.len(),
+        }
+    }
+
+    fn details(&self) -> ConversationDetails<'_> {
+        ConversationDetails {
+            id: &self.id,
+            created: self.created,
+            updated: self.updated,
+            template: &self.template_name,
+            turns: &self.turns,
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The conversations of all clients, kept in memory. Once more than
+/// `max_conversations` exist, the least recently updated ones are dropped.
+pub struct ConversationStore {
+    conversations: HashMap<String, Conversation>,
+    max_conversations: usize,
+}
+
+impl ConversationStore {
+    pub fn new(max_conversations: usize) -> Self {
+        Self {
+            conversations: HashMap::new(),
+            max_conversations,
+        }
+    }
+
+    fn create(&mut self, template_name: String, template: PromptTemplate) -> &Conversation {
+        while self.conversations.len() >= self.max_conversations.max(1) {
+            let oldest = self
+                .conversations
+                .values()
+                .min_by_key(|conversation| conversation.updated)
+                .map(|conversation| conversation.id.clone())
+                .unwrap();
+            log::info!("Dropping conversation {oldest}");
+            self.conversations.remove(&oldest);
+        }
+
+        let id = format!("conv-{:016x}", rand::thread_rng().gen::<u64>());
+        let now = unix_time();
+        self.conversations
+            .entry(id.clone())
+            .or_insert(Conversation {
+                id,
+                created: now,
+                updated: now,
+                turns: vec![],
+                template_name,
+                template,
+                tokens: vec![],
+                snapshot: None,
+            })
+    }
+}
+
+/// Runs on the inference worker: answers `query` in the conversation `id` and
+/// stores the new state.
+pub fn answer_turn(
+    loaded: &mut LoadedModel,
+    state: &Mutex<AppState>,
+    id: String,
+    query: String,
+    extras: PromptExtras,
+    response_sender: flume::Sender<InferenceResult>,
+    cancel: &AtomicBool,
+) {
+    let (template, previous) = {
+        let state = state.lock().unwrap();
+        let Some(conversation) = state.conversations.conversations.get(&id) else {
+            _ = response_sender.send(InferenceResult::Error {
+                code: ErrorCode::NotFound,
+                message: format!("Conversation {id} does not exist"),
+            });
+            return;
+        };
+        let previous = conversation
+            .snapshot
+            .clone()
+            .map(|snapshot| (snapshot, conversation.tokens.clone()));
+        (conversation.template.clone(), previous)
+    };
+    let restored =
+        previous.map(|(snapshot, tokens)| (loaded.model.session_from_snapshot(&snapshot), tokens));
+
+    let (mut session, prompt_tokens, cached) = match restored {
+        Some((Ok(session), tokens)) => {
+            let prompt = template.follow_up(&query);
+            let Ok(turn_tokens) = loaded.model.tokenize(&loaded.vocab, &prompt, false) else {
+                tokenization_failed(&response_sender);
+                return;
+            };
+            let cached = tokens.len();
+            (session, [tokens, turn_tokens].concat(), cached)
+        }
+        Some((Err(err), _)) => {
+            log::error!("Could not restore conversation {id}: {err}");
+            _ = response_sender.send(InferenceResult::Error {
+                code: ErrorCode::Unavailable,
+                message: "The conversation state could not be restored.".to_string(),
+            });
+            return;
+        }
+        None => {
+            let Ok((system_len, prompt_tokens)) =
+                tokenize_query(&loaded.model, &loaded.vocab, &template, &query, &extras)
+            else {
+                tokenization_failed(&response_sender);
+                return;
+            };
+            let (session, cached) =
+                start_prompt_session(loaded, &template, &prompt_tokens, system_len);
+            (session, prompt_tokens, cached)
+        }
+    };
+
+    log::info!("Continuing conversation {id} with query: {query}");
+
+    let Some(answer) = stream_answer(
+        loaded,
+        &mut session,
+        &prompt_tokens[cached..],
+        &template.response_prefix,
+        extras.cwd.as_deref(),
+        &response_sender,
+        cancel,
+    ) else {
+        return;
+    };
+
+    let generated = answer.tokens;
+    let answer: Vec<TokenId> = generated
+        .iter()
+        .copied()
+        .filter(|&token| token != EOD_TOKEN_ID)
+        .collect();
+    let answer = loaded.vocab.decode(answer, true).unwrap_or_default();
+    let snapshot = Arc::new(session.snapshot());
+
+    let mut state = state.lock().unwrap();
+    // The conversation may have been deleted in the meantime
+    if let Some(conversation) = state.conversations.conversations.get_mut(&id) {
+        conversation.turns.push(Turn {
+            role: Role::User,
+            text: query,
+        });
+        conversation.turns.push(Turn {
+            role: Role::Assistant,
+            text: answer,
+        });
+        conversation.tokens = [prompt_tokens, generated].concat();
+        conversation.snapshot = Some(snapshot);
+        conversation.updated = unix_time();
+    }
+}
+
+// ========
+// Handlers
+// ========
+
+#[derive(Deserialize, Default)]
+pub struct CreateRequest {
+    /// Name of the prompt template, the server default if not set.
+    #[serde(default)]
+    template: Option<String>,
+}
+
+/// Starts a conversation. The body is optional.
+pub async fn create_handler(
+    Extension(state): Extension<Arc<Mutex<AppState>>>,
+    payload: Option<Json<CreateRequest>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let Json(payload) = payload.unwrap_or_default();
+    let template = request_template(&state, payload.template.as_deref())?;
+    let template_name = payload
+        .template
+        .unwrap_or_else(|| state.lock().unwrap().default_template.clone());
+
+    let mut state = state.lock().unwrap();
+    let conversation = state.conversations.create(template_name, template);
+    let details = serde_json::to_value(conversation.details()).unwrap();
+    Ok((StatusCode::CREATED, Json(details)))
+}
+
+pub async fn list_handler(Extension(state): Extension<Arc<Mutex<AppState>>>) -> impl IntoResponse {
+    let state = state.lock().unwrap();
+    let mut conversations: Vec<_> = state
+        .conversations
+        .conversations
+        .values()
+        .map(Conversation::summary)
+        .collect();
+    conversations.sort_by_key(|conversation| conversation.created);
+    Json(conversations)
+}
+
+pub async fn get_handler(
+    Extension(state): Extension<Arc<Mutex<AppState>>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let state = state.lock().unwrap();
+    let conversation = state
+        .conversations
+        .conversations
+        .get(&id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serde_json::to_value(conversation.details()).unwrap()))
+}
+
+pub async fn delete_handler(
+    Extension(state): Extension<Arc<Mutex<AppState>>>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    match state
+        .lock()
+        .unwrap()
+        .conversations
+        .conversations
+        .remove(&id)
+    {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+/// Appends a user turn and streams the assistant's answer as
+/// `wiz_protocol::StreamEvent`s, like `/api/v1/completions`.
+pub async fn turn_handler(
+    Extension(state): Extension<Arc<Mutex<AppState>>>,
+    Path(id): Path<String>,
+    Json(payload): Json<CompletionRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let Some(template_name) = state
+        .lock()
+        .unwrap()
+        .conversations
+        .conversations
+        .get(&id)
+        .map(|conversation| conversation.template_name.clone())
+    else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let extras = state
+        .lock()
+        .unwrap()
+        .prompt_extras(&payload.query, payload.context);
+    let recorder = QueryRecorder::new(&state, "turn", &payload.query, Some(&template_name));
+    Ok(protocol_stream(state, recorder, move |response_sender| {
+        InferenceRequest::Turn {
+            conversation: id,
+            query: payload.query,
+            extras: Box::new(extras),
+            response_sender,
+        }
+    }))
+}
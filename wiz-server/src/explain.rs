@@ -0,0 +1,120 @@
+use axum::{http::StatusCode, Extension, Json};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use wiz_rs::CommandBreakdown;
+
+use crate::{
+    openai::{CompletionEvent, CompletionJob, GenerationSettings},
+    request_template, AppState, InferenceRequest,
+};
+
+/// Tokens allowed for the summary, and for every segment of the command.
+const SUMMARY_TOKENS: usize = 64;
+const SEGMENT_TOKENS: usize = 48;
+
+/// At most this many tokens are generated per explanation.
+const MAX_TOKENS: usize = 1024;
+
+#[derive(Deserialize)]
+pub struct ExplainRequest {
+    /// The shell command to explain.
+    command: String,
+    /// Name of the prompt template to use instead of `explain`.
+    #[serde(default)]
+    template: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExplainResponse {
+    #[serde(flatten)]
+    breakdown: CommandBreakdown,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    ms: u64,
+}
+
+/// Explains a shell command piece by piece: `POST /api/v1/explain`. Every
+/// command, flag and redirect of it comes back with its byte offsets in the
+/// command and what it does.
+pub async fn explain_handler(
+    Extension(state): Extension<Arc<Mutex<AppState>>>,
+    Json(payload): Json<ExplainRequest>,
+) -> Result<Json<ExplainResponse>, (StatusCode, String)> {
+    let started = Instant::now();
+    if payload.command.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "`command` is empty".to_string()));
+    }
+    let mut breakdown = CommandBreakdown::new(&payload.command).map_err(|err| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("could not parse `command`: {err}"),
+        )
+    })?;
+    let template = request_template(
+        &state,
+        Some(payload.template.as_deref().unwrap_or("explain")),
+    )?;
+
+    let (tx, rx) = flume::unbounded();
+    let job = CompletionJob {
+        prompts: vec![template.render(&[], &breakdown.query(), None, &[])],
+        n: 1,
+        settings: GenerationSettings {
+            max_tokens: Some(
+                (SUMMARY_TOKENS + SEGMENT_TOKENS * breakdown.segments.len()).min(MAX_TOKENS),
+            ),
+            temperature: 0.0,
+            top_p: 1.0,
+            // Models trained on a template tend to go on with the next input
+            stop: template
+                .turn_start()
+                .map(str::to_string)
+                .into_iter()
+                .collect(),
+            logprobs: None,
+        },
+        response_sender: tx,
+    };
+    if state
+        .lock()
+        .unwrap()
+        .submit(InferenceRequest::Completion(job))
+        .is_err()
+    {
+        log::error!("Could not send inference request");
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "inference worker is not running".to_string(),
+        ));
+    }
+
+    let mut answer = String::new();
+    let (prompt_tokens, completion_tokens) = loop {
+        match rx.recv_async().await {
+            Ok(CompletionEvent::Token { text, .. }) => answer += &text,
+            Ok(CompletionEvent::Finished {
+                prompt_tokens,
+                completion_tokens,
+                ..
+            }) => break (prompt_tokens, completion_tokens),
+            Ok(CompletionEvent::Error { status, message }) => return Err((status, message)),
+            Err(_) => {
+                return Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "inference worker stopped".to_string(),
+                ))
+            }
+        }
+    };
+    breakdown.apply_answer(&answer);
+
+    Ok(Json(ExplainResponse {
+        breakdown,
+        prompt_tokens,
+        completion_tokens,
+        ms: started.elapsed().as_millis() as u64,
+    }))
+}
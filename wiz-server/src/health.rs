@@ -0,0 +1,88 @@
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use wiz_rs::Hyperparameters;
+
+use crate::AppState;
+
+/// Lifecycle of the server, from startup until it can answer queries.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerStatus {
+    LoadingModel,
+    PreparingSnapshot,
+    Ready,
+    /// The model was unloaded after being idle, or is loaded lazily.
+    Unloaded,
+    /// The model could not be loaded, the next request tries again.
+    Failed,
+}
+
+/// Static information about the loaded model, reported on `/info`.
+#[derive(Serialize, Clone, Debug)]
+pub struct ModelInfo {
+    pub hyperparameters: Hyperparameters,
+    pub ftype: &'static str,
+    pub context_size: usize,
+}
+
+impl ModelInfo {
+    pub fn new(model: &wiz_rs::Model) -> Self {
+        let hyperparameters = model.hyperparameters().clone();
+        let ftype = match hyperparameters.ftype {
+            0 => "f32",
+            1 => "f16",
+            2 => "q4_0",
+            3 => "q4_1",
+            _ => "unknown",
+        };
+        Self {
+            context_size: hyperparameters.max_seq_len as usize,
+            ftype,
+            hyperparameters,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReadyResponse {
+    ready: bool,
+    status: ServerStatus,
+}
+
+#[derive(Serialize)]
+struct InfoResponse {
+    version: &'static str,
+    status: ServerStatus,
+    uptime_secs: u64,
+    model: Option<ModelInfo>,
+}
+
+/// Liveness: answers as soon as the HTTP server is up, even while the model
+/// is still loading.
+pub async fn health_handler() -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness: the model is loaded and the prompt snapshot is prepared, or
+/// the model is unloaded and is loaded again by the next request.
+pub async fn ready_handler(Extension(state): Extension<Arc<Mutex<AppState>>>) -> impl IntoResponse {
+    let status = state.lock().unwrap().status;
+    let ready = matches!(status, ServerStatus::Ready | ServerStatus::Unloaded);
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(ReadyResponse { ready, status }))
+}
+
+pub async fn info_handler(Extension(state): Extension<Arc<Mutex<AppState>>>) -> impl IntoResponse {
+    let state = state.lock().unwrap();
+    Json(InfoResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        status: state.status,
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        model: state.model_info.clone(),
+    })
+}
@@ -0,0 +1,202 @@
+use axum::{http::StatusCode, Extension, Json};
+use serde::Deserialize;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use wiz_rs::{Candidate, Journal, JournalEvent, Outcome, PartKind, QueryRecord, ResponseEvent};
+
+use crate::{cli_args::CLI_ARGS, AppState, InferenceResult};
+
+/// How much of the end of a command's output is kept, in bytes.
+const MAX_OUTPUT: usize = 4096;
+
+/// How often events older than `--journal-retention-days` are removed.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+/// The journal in `~/.wiz`, `None` with `--no-journal`.
+pub fn open() -> Option<Journal> {
+    if CLI_ARGS.no_journal {
+        return None;
+    }
+    Some(Journal::in_dir(dirs::home_dir()?.join(".wiz")))
+}
+
+/// Collects what happens while a query is answered and adds it to the
+/// journal when dropped, which is also when the client disconnects early.
+pub struct QueryRecorder {
+    journal: Option<Journal>,
+    record: QueryRecord,
+    started: Instant,
+    /// For every part of the current candidate, the index of its command in
+    /// `Candidate::commands` if it is one.
+    parts: Vec<Option<usize>>,
+}
+
+impl QueryRecorder {
+    /// `kind` and `query` are stored as they are, see `QueryRecord`.
+    pub fn new(state: &Mutex<AppState>, kind: &str, query: &str, template: Option<&str>) -> Self {
+        let state = state.lock().unwrap();
+        Self {
+            journal: state.journal.clone(),
+            record: QueryRecord {
+                time: now(),
+                kind: kind.to_string(),
+                query: query.to_string(),
+                template: template.unwrap_or(&state.default_template).to_string(),
+                candidates: vec![Candidate::default()],
+                ..Default::default()
+            },
+            started: Instant::now(),
+            parts: vec![],
+        }
+    }
+
+    /// Sets the id of the answer, which clients report outcomes for.
+    pub fn set_id(&mut self, id: &str) {
+        self.record.id = id.to_string();
+    }
+
+    pub fn observe(&mut self, result: &InferenceResult) {
+        let Some(candidate) = self.record.candidates.last_mut() else {
+            return;
+        };
+        match result {
+            InferenceResult::Token { text, .. } => candidate.answer += text,
+            InferenceResult::Part(ResponseEvent::PartStart { kind, .. }) => {
+                let command = (*kind == PartKind::Command).then(|| {
+                    candidate.commands.push(String::new());
+                    candidate.commands.len() - 1
+                });
+                self.parts.push(command);
+            }
+            InferenceResult::Part(ResponseEvent::Text { index, text }) => {
+                if let Some(Some(command)) = self.parts.get(*index) {
+                    candidate.commands[*command] += text;
+                }
+            }
+            InferenceResult::Candidate(_) => {
+                self.record.candidates.push(Candidate::default());
+                self.parts.clear();
+            }
+            InferenceResult::Usage(usage) => {
+                candidate.prompt_tokens = usage.prompt_tokens;
+                candidate.completion_tokens = usage.completion_tokens;
+                candidate.prompt_ms = usage.prompt_ms;
+                candidate.completion_ms = usage.completion_ms;
+            }
+            InferenceResult::Error { message, .. } => self.record.error = Some(message.clone()),
+            InferenceResult::Risk { .. }
+            | InferenceResult::Validation { .. }
+            | InferenceResult::Truncated => {}
+        }
+    }
+}
+
+impl Drop for QueryRecorder {
+    fn drop(&mut self) {
+        let Some(journal) = self.journal.take() else {
+            return;
+        };
+        let mut record = std::mem::take(&mut self.record);
+        record.ms = self.started.elapsed().as_millis() as u64;
+        let append = move || {
+            if let Err(err) = journal.append(&JournalEvent::Query(record)) {
+                log::warn!("Could not write to {}: {err}", journal.path().display());
+            }
+        };
+        // Appending waits for the journal lock, which must not block the
+        // stream that drops the recorder
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(append)),
+            Err(_) => append(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OutcomeRequest {
+    /// The id of the answer, from its `start` event.
+    id: String,
+    /// The command the user chose.
+    command: String,
+    #[serde(default)]
+    exit_code: Option<i32>,
+    #[serde(default)]
+    signal: Option<i32>,
+    #[serde(default)]
+    duration_ms: Option<u64>,
+    #[serde(default)]
+    cwd: Option<String>,
+    /// The end of what the command printed, if it was run.
+    #[serde(default)]
+    output: Option<String>,
+}
+
+/// The last `MAX_OUTPUT` bytes of `output`, at most.
+fn output_tail(mut output: String) -> String {
+    let mut start = output.len().saturating_sub(MAX_OUTPUT);
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    output.drain(..start);
+    output
+}
+
+/// Records which command of an answer the user chose and how running it
+/// went: `POST /api/v1/outcome`.
+pub async fn outcome_handler(
+    Extension(state): Extension<Arc<Mutex<AppState>>>,
+    Json(payload): Json<OutcomeRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if payload.id.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "`id` is empty".to_string()));
+    }
+    let Some(journal) = state.lock().unwrap().journal.clone() else {
+        return Ok(StatusCode::NO_CONTENT);
+    };
+    let event = JournalEvent::Outcome(Outcome {
+        id: payload.id,
+        time: now(),
+        command: payload.command,
+        exit_code: payload.exit_code,
+        signal: payload.signal,
+        duration_ms: payload.duration_ms,
+        cwd: payload.cwd,
+        output: payload.output.map(output_tail),
+    });
+    match tokio::task::spawn_blocking(move || journal.append(&event)).await {
+        Ok(Ok(())) => Ok(StatusCode::NO_CONTENT),
+        Ok(Err(err)) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Removes the events older than `--journal-retention-days` from the journal,
+/// on startup and then once a day.
+pub async fn enforce_retention(journal: Journal) {
+    if CLI_ARGS.journal_retention_days == 0 {
+        return;
+    }
+    let retention = CLI_ARGS.journal_retention_days.saturating_mul(24 * 60 * 60);
+    let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let before = now().saturating_sub(retention);
+        let purge = journal.clone();
+        match tokio::task::spawn_blocking(move || purge.purge(Some(before))).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(removed)) => log::info!("Removed {removed} old queries from the journal"),
+            Ok(Err(err)) => log::warn!("Could not purge {}: {err}", journal.path().display()),
+            Err(err) => log::warn!("Could not purge the journal: {err}"),
+        }
+    }
+}
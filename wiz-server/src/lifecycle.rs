@@ -0,0 +1,213 @@
+use fs2::FileExt;
+use std::{
+    error::Error,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::watch;
+
+use crate::{
+    cli_args::{IdleAction, CLI_ARGS},
+    get_wiz_home_dir,
+    health::ServerStatus,
+    AppState, InferenceRequest,
+};
+
+/// Sent as the terminal error of generations that are cut off by a shutdown.
+pub const SHUTDOWN_MESSAGE: &str = "The server is shutting down.";
+
+/// Returned from inference callbacks to abort a generation during shutdown.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "generation cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// An exclusively locked `~/.wiz/server.pid`. Only one server can hold the
+/// lock at a time; the file is removed again when the guard is dropped.
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+/// How often `PidFile::acquire` tries again when the file it locked was
+/// removed by a server that was just stopping.
+const PID_FILE_ATTEMPTS: usize = 3;
+
+impl PidFile {
+    pub fn acquire() -> Result<Self, Box<dyn Error>> {
+        let path = get_wiz_home_dir()?.join("server.pid");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+
+        for _ in 0..PID_FILE_ATTEMPTS {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+
+            if file.try_lock_exclusive().is_err() {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                return Err(format!("wiz-server is already running (pid {})", pid.trim()).into());
+            }
+
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            write!(file, "{}", std::process::id())?;
+            file.flush()?;
+
+            // A stopping server removes the file before unlocking it. If it
+            // did so after we opened it, we hold the lock of a removed file
+            if holds_our_pid(&path) {
+                return Ok(Self { path, file });
+            }
+            _ = file.unlock();
+        }
+        Err("could not lock the pidfile, another server is starting or stopping".into())
+    }
+}
+
+/// Whether the file at `path` holds the PID of this process.
+fn holds_our_pid(path: &Path) -> bool {
+    std::fs::read_to_string(path).is_ok_and(|pid| pid.trim() == std::process::id().to_string())
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Removed while still locked, so a server starting meanwhile either
+        // fails to lock this file or creates a new one
+        if holds_our_pid(&self.path) {
+            _ = std::fs::remove_file(&self.path);
+        }
+        _ = self.file.unlock();
+    }
+}
+
+/// Returns the listening socket handed over by the service manager, if the
+/// server was started through socket activation (`LISTEN_FDS`/`LISTEN_PID`).
+#[cfg(unix)]
+pub fn inherited_listener() -> Option<std::net::TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    // The first passed file descriptor is always 3, see sd_listen_fds(3)
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let fds: u32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if pid != std::process::id() || fds == 0 {
+        return None;
+    }
+    if fds > 1 {
+        log::warn!("Received {fds} sockets, only the first one is used");
+    }
+
+    // SAFETY: The service manager guarantees that the descriptor is an open
+    // socket owned by this process.
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    listener.set_nonblocking(true).ok()?;
+    Some(listener)
+}
+
+#[cfg(not(unix))]
+pub fn inherited_listener() -> Option<std::net::TcpListener> {
+    None
+}
+
+/// Watches for inactivity and either shuts the server down or unloads the
+/// model, depending on `--idle-action`.
+pub async fn idle_watchdog(state: Arc<Mutex<AppState>>, shutdown: Arc<watch::Sender<bool>>) {
+    if CLI_ARGS.idle_timeout == 0 {
+        return;
+    }
+    let timeout = Duration::from_secs(CLI_ARGS.idle_timeout);
+    let mut interval = tokio::time::interval((timeout / 4).min(Duration::from_secs(30)));
+
+    loop {
+        interval.tick().await;
+
+        let mut state = state.lock().unwrap();
+        let loading = matches!(
+            state.status,
+            ServerStatus::LoadingModel | ServerStatus::PreparingSnapshot
+        );
+        let idle = !loading
+            && !state.busy
+            && state.inference_tx.is_empty()
+            && state.last_activity.elapsed() >= timeout;
+        if !idle {
+            continue;
+        }
+
+        match CLI_ARGS.idle_action {
+            IdleAction::Exit => {
+                log::info!("Idle for {}s, shutting down", timeout.as_secs());
+                _ = shutdown.send(true);
+                return;
+            }
+            // Nothing to unload
+            IdleAction::Unload
+                if matches!(state.status, ServerStatus::Unloaded | ServerStatus::Failed) => {}
+            IdleAction::Unload => {
+                log::info!("Idle for {}s, unloading the model", timeout.as_secs());
+                state.status = ServerStatus::Unloaded;
+                _ = state.inference_tx.send(InferenceRequest::Unload);
+            }
+        }
+    }
+}
+
+/// Starts the shutdown on SIGINT or SIGTERM.
+pub async fn signal_handler(shutdown: Arc<watch::Sender<bool>>) {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                log::warn!("Could not listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate => {},
+    }
+
+    log::info!("Received termination signal, shutting down");
+    _ = shutdown.send(true);
+}
+
+/// Once the shutdown started, gives running generations
+/// `--shutdown-grace-period` seconds to finish before cancelling them.
+pub async fn cancel_after_grace_period(
+    state: Arc<Mutex<AppState>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    if shutdown.wait_for(|shutdown| *shutdown).await.is_err() {
+        return;
+    }
+    tokio::time::sleep(Duration::from_secs(CLI_ARGS.shutdown_grace_period)).await;
+
+    let state = state.lock().unwrap();
+    if state.busy || !state.inference_tx.is_empty() {
+        log::info!("Cancelling unfinished generations");
+    }
+    state.cancel.store(true, Ordering::Relaxed);
+}
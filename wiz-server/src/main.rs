@@ -1,31 +1,127 @@
 use axum::{
+    http::StatusCode,
     response::{sse::Event, Sse},
-    routing::post,
+    routing::{get, post},
     Extension, Json, Router,
 };
 use futures_core::stream::Stream;
-use rand::rngs::ThreadRng;
+use rand::{rngs::ThreadRng, Rng};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 use std::{
     cell::RefCell,
+    collections::HashMap,
     convert::Infallible,
     error::Error,
-    io,
     net::SocketAddr,
-    path::PathBuf,
+    path::{Path, PathBuf},
     rc::Rc,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
 };
 use tokenizers::Tokenizer;
 use tokio::task::spawn_blocking;
+use wiz_protocol::{ErrorCode, FinishReason, Phase, StreamEvent, Usage, PROTOCOL_VERSION};
 use wiz_rs::{
-    InferenceError, InferenceParameters, InferenceSessionParameters, InferenceSnapshot,
-    OutputToken, TokenBias, EOD_TOKEN_ID,
+    CommandValidator, ExampleIndex, FailedCommand, InferenceError, InferenceSession,
+    InferenceSessionParameters, InferenceSnapshot, IssueKind, Journal, OutputToken, PartKind,
+    PrefixCache, PromptTemplate, PromptTemplates, ResponseEvent, ResponseParser, ResponsePart,
+    Risk, RiskLevel, ShellContext, TemplateError, TokenId, Validation, EOD_TOKEN_ID,
 };
 
+mod cli_args;
+mod complete;
+mod conversations;
+mod explain;
+mod health;
+mod journal;
+mod lifecycle;
+mod openai;
+mod snapshot_cache;
+
+use cli_args::CLI_ARGS;
+use conversations::ConversationStore;
+use health::{ModelInfo, ServerStatus};
+use journal::QueryRecorder;
+use snapshot_cache::SnapshotCache;
+
 struct AppState {
     inference_tx: flume::Sender<InferenceRequest>,
+    status: ServerStatus,
+    started_at: Instant,
+    model_info: Option<ModelInfo>,
+    /// When the last inference request was submitted or finished.
+    last_activity: Instant,
+    /// Whether the inference worker is currently processing a request.
+    busy: bool,
+    /// Set during shutdown to abort running and queued generations.
+    cancel: Arc<AtomicBool>,
+    conversations: ConversationStore,
+    templates: PromptTemplates,
+    /// Used by requests that do not name a template.
+    default_template: String,
+    /// The server's own context, filling in what requests do not send. `None`
+    /// with `--no-context`.
+    context: Option<ShellContext>,
+    /// Few-shot examples for the prompts, `None` if there are none.
+    examples: Option<ExampleIndex>,
+    /// Where answered queries are recorded, `None` with `--no-journal`.
+    journal: Option<Journal>,
+}
+
+impl AppState {
+    fn default_template(&self) -> &PromptTemplate {
+        self.templates
+            .get(&self.default_template)
+            .expect("checked on startup")
+    }
+
+    /// The context to put into the prompt of a request, rendered.
+    fn context(&self, requested: Option<ShellContext>) -> Option<String> {
+        if CLI_ARGS.no_context {
+            return None;
+        }
+        let own = self.context.clone().unwrap_or_default();
+        requested
+            .map_or(own.clone(), |requested| requested.or(own))
+            .render()
+    }
+
+    /// Gathers the context and few-shot examples for the prompt of `query`.
+    fn prompt_extras(&self, query: &str, requested: Option<ShellContext>) -> PromptExtras {
+        let examples = match &self.examples {
+            Some(index) => index
+                .top_k(query, CLI_ARGS.num_examples)
+                .into_iter()
+                .map(|(example, _)| (example.query.clone(), example.commands[0].clone()))
+                .collect(),
+            None => vec![],
+        };
+        PromptExtras {
+            cwd: requested
+                .as_ref()
+                .and_then(|context| context.cwd.as_ref())
+                .map(PathBuf::from),
+            context: self.context(requested),
+            examples,
+        }
+    }
+
+    /// Looks up the template named in a request, the default one if `None`.
+    fn template(&self, name: Option<&str>) -> Result<PromptTemplate, TemplateError> {
+        match name {
+            Some(name) => self.templates.get(name).cloned(),
+            None => Ok(self.default_template().clone()),
+        }
+    }
+
+    /// Queues a request for the inference worker.
+    fn submit(&mut self, req: InferenceRequest) -> Result<(), flume::SendError<InferenceRequest>> {
+        self.last_activity = Instant::now();
+        self.inference_tx.send(req)
+    }
 }
 
 // Resolve to ~/.wiz
@@ -35,9 +131,13 @@ fn get_wiz_home_dir() -> Result<PathBuf, Box<dyn Error>> {
     Ok(home_dir)
 }
 
+fn get_model_path() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(get_wiz_home_dir()?.join("model.bin"))
+}
+
 fn load_model() -> Result<(wiz_rs::Model, Tokenizer), Box<dyn Error>> {
-    let model_path = get_wiz_home_dir()?.join("model.bin");
-    let (model, vocab) = wiz_rs::Model::load(&model_path, 512 as i32, |progress| {
+    let model_path = get_model_path()?;
+    let (model, vocab) = wiz_rs::Model::load(&model_path, 512, |progress| {
         use wiz_rs::LoadProgress;
         match progress {
             LoadProgress::HyperparametersLoaded(hparams) => {
@@ -87,8 +187,7 @@ fn load_model() -> Result<(wiz_rs::Model, Tokenizer), Box<dyn Error>> {
                 );
             }
         }
-    })
-    .expect("Could not load model");
+    })?;
 
     log::info!("Model fully loaded!");
 
@@ -97,214 +196,672 @@ fn load_model() -> Result<(wiz_rs::Model, Tokenizer), Box<dyn Error>> {
 
 #[derive(Debug)]
 enum InferenceResult {
-    Token(String),
-    Error(String),
+    Token {
+        text: String,
+        token_id: TokenId,
+        phase: Phase,
+    },
+    /// The answer split into parts, see `ResponseParser`.
+    Part(ResponseEvent),
+    /// The assessment of the complete command part `index`.
+    Risk {
+        index: usize,
+        risk: Risk,
+    },
+    /// Whether the complete command part `index` works on this machine.
+    Validation {
+        index: usize,
+        validation: Validation,
+    },
+    /// A new answer follows, because the commands of the previous one failed
+    /// validation.
+    Candidate(usize),
+    Usage(Usage),
+    /// The context window filled up while generating.
+    Truncated,
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
 }
 
-struct InferenceRequest {
-    query: String,
-    response_sender: flume::Sender<InferenceResult>,
+enum InferenceRequest {
+    Query {
+        query: String,
+        template: Box<PromptTemplate>,
+        extras: PromptExtras,
+        response_sender: flume::Sender<InferenceResult>,
+    },
+    Completion(openai::CompletionJob),
+    Complete(complete::CompleteJob),
+    /// The next user turn of a conversation.
+    Turn {
+        conversation: String,
+        query: String,
+        /// The context and examples are only used for the first turn.
+        extras: Box<PromptExtras>,
+        response_sender: flume::Sender<InferenceResult>,
+    },
+    /// Frees the model. It is loaded again on the next request.
+    Unload,
+    /// Stops the worker once all previously queued requests are handled.
+    Shutdown,
 }
 
-const PROMPT_PREFIX: &str = "### Instruction:\nConvert to bash command, provide detailed explanation in a second paragraph\n\n### Input:\n";
+impl InferenceRequest {
+    /// Answers the request with an error instead of running it.
+    fn reject(self, code: ErrorCode, message: &str) {
+        match self {
+            InferenceRequest::Query {
+                response_sender, ..
+            }
+            | InferenceRequest::Turn {
+                response_sender, ..
+            } => {
+                _ = response_sender.send(InferenceResult::Error {
+                    code,
+                    message: message.to_string(),
+                });
+            }
+            InferenceRequest::Completion(job) => {
+                _ = job.response_sender.send(openai::CompletionEvent::Error {
+                    status: StatusCode::SERVICE_UNAVAILABLE,
+                    message: message.to_string(),
+                });
+            }
+            InferenceRequest::Complete(job) => {
+                _ = job.response_sender.send(Err(message.to_string()));
+            }
+            InferenceRequest::Unload | InferenceRequest::Shutdown => {}
+        }
+    }
+}
+
+fn session_params() -> InferenceSessionParameters {
+    InferenceSessionParameters {
+        memory_k_type: wiz_rs::ModelKVMemoryType::Float16,
+        memory_v_type: wiz_rs::ModelKVMemoryType::Float16,
+        ..Default::default()
+    }
+}
 
-const PROMPT_TEMPLATE: &str = "{input}\n\n### Response:\n```bash\n";
+/// What goes into the first turn of a prompt besides the query.
+#[derive(Default)]
+struct PromptExtras {
+    /// The rendered `ShellContext`.
+    context: Option<String>,
+    /// Few-shot `(query, command)` examples.
+    examples: Vec<(String, String)>,
+    /// Where the answer will be run, to tell which files it overwrites.
+    cwd: Option<PathBuf>,
+}
 
-fn generate_prompt(input: &str) -> String {
-    PROMPT_TEMPLATE.replace("{input}", input)
+impl PromptExtras {
+    fn first_turn(&self, template: &PromptTemplate, query: &str) -> String {
+        let examples: Vec<(&str, &str)> = self
+            .examples
+            .iter()
+            .map(|(query, command)| (query.as_str(), command.as_str()))
+            .collect();
+        template.first_turn(query, self.context.as_deref(), &examples)
+    }
 }
 
-fn load_prompt_snapshot(
-    prompt_prefix: &str,
+/// Tokenizes the prompt for a new query, with the system prompt of the
+/// template tokenized on its own like when its snapshot was created. Returns
+/// the number of system prompt tokens along with the tokens of the whole
+/// prompt.
+fn tokenize_query(
     model: &wiz_rs::Model,
     vocab: &Tokenizer,
-) -> Result<InferenceSnapshot, Box<dyn Error>> {
-    // Check if prompt snapshot exists at ~/.wiz/snapshots/{hash}.bin
-    let mut prompt_hasher = Sha256::new();
-    prompt_hasher.update(prompt_prefix.as_bytes());
-    let prompt_hash = prompt_hasher.finalize();
-    let prompt_hash_hex = &format!("{:x}", prompt_hash)[0..8];
-
-    let path = get_wiz_home_dir()?
-        .join("snapshots")
-        .join(format!("{}.bin", prompt_hash_hex));
-
-    if path.exists() {
-        return Ok(InferenceSnapshot::load_from_disk(path).map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Could not load prompt snapshot: {err}"),
-            )
-        })?);
-    }
+    template: &PromptTemplate,
+    query: &str,
+    extras: &PromptExtras,
+) -> Result<(usize, Vec<TokenId>), InferenceError> {
+    let system_tokens = if template.system.is_empty() {
+        vec![]
+    } else {
+        model.tokenize(vocab, &template.system, true)?
+    };
+    let query_tokens = model.tokenize(
+        vocab,
+        &extras.first_turn(template, query),
+        system_tokens.is_empty(),
+    )?;
+    Ok((
+        system_tokens.len(),
+        [system_tokens.as_slice(), &query_tokens].concat(),
+    ))
+}
 
-    // If not, generate it
-    let mut session = model.start_session(InferenceSessionParameters {
-        memory_k_type: wiz_rs::ModelKVMemoryType::Float16,
-        memory_v_type: wiz_rs::ModelKVMemoryType::Float16,
-        ..Default::default()
+/// Starts a session for `prompt_tokens` from the longest cached prefix, or
+/// from the snapshot of the system prompt if the cache does not cover it.
+/// Returns the session and how many of the tokens are already fed.
+fn start_prompt_session(
+    loaded: &mut LoadedModel,
+    template: &PromptTemplate,
+    prompt_tokens: &[TokenId],
+    system_len: usize,
+) -> (InferenceSession, usize) {
+    let cached_prefix = loaded
+        .prefix_cache
+        .start_session(&loaded.model, prompt_tokens);
+    let (session, cached) = match cached_prefix {
+        Ok(Some((session, cached))) if cached >= system_len => (session, cached),
+        result => {
+            if let Err(err) = result {
+                log::warn!("Could not restore a cached prefix: {err}");
+            }
+            let system = &template.system;
+            let restored = loaded.prepare_system_snapshot(system).then(|| {
+                loaded
+                    .model
+                    .session_from_snapshot(&loaded.snapshots[system])
+            });
+            match restored {
+                Some(Ok(session)) => (session, system_len),
+                None => (loaded.model.start_session(session_params()), 0),
+                Some(Err(err)) => {
+                    log::error!("Could not restore the prompt snapshot: {err}");
+                    (loaded.model.start_session(session_params()), 0)
+                }
+            }
+        }
+    };
+    log::info!(
+        "Reusing {cached} of {} prompt tokens from the cache",
+        prompt_tokens.len()
+    );
+    (session, cached)
+}
+
+fn tokenization_failed(response_sender: &flume::Sender<InferenceResult>) {
+    log::error!("Failed to tokenize initial prompt.");
+
+    _ = response_sender.send(InferenceResult::Error {
+        code: ErrorCode::TokenizationFailed,
+        message: "Failed to tokenize initial prompt.".to_string(),
     });
+}
 
-    let res = session.feed_prompt::<Infallible>(
-        model,
-        vocab,
-        &InferenceParameters {
-            ..Default::default()
+/// An answer that was neither cancelled nor failed.
+struct Answer {
+    /// The generated tokens, which the session holds afterwards.
+    tokens: Vec<TokenId>,
+    /// Feedback on the commands that failed validation, see
+    /// `wiz_rs::validation_feedback`.
+    problems: Vec<String>,
+}
+
+/// Feeds `prompt_tokens` into the session and streams the generated answer,
+/// both as tokens and split into parts, followed by the checks of its
+/// commands. `response_prefix` is the end of the prompt the answer continues,
+/// `cwd` where its commands would run.
+fn stream_answer(
+    loaded: &LoadedModel,
+    session: &mut InferenceSession,
+    prompt_tokens: &[TokenId],
+    response_prefix: &str,
+    cwd: Option<&Path>,
+    response_sender: &flume::Sender<InferenceResult>,
+    cancel: &AtomicBool,
+) -> Option<Answer> {
+    let parser = Rc::new(RefCell::new(ResponseParser::with_prefix(response_prefix)));
+    let checks = RefCell::new(CommandChecks::new(cwd, loaded.validator.as_ref()));
+    let inference_params = wiz_rs::answer_parameters(parser.clone());
+
+    let mut rng = ThreadRng::default();
+
+    let generated = RefCell::new(Vec::new());
+    let res = session.inference_with_tokens::<lifecycle::Cancelled>(
+        &loaded.model,
+        &loaded.vocab,
+        &inference_params,
+        prompt_tokens,
+        None,
+        &mut rng,
+        |t| {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(lifecycle::Cancelled);
+            }
+
+            let (text, token_id) = match t {
+                OutputToken::Token(text, true, token_id) => (text, token_id),
+                OutputToken::Token(..) => return Ok(()),
+                OutputToken::EndOfText => {
+                    generated.borrow_mut().push(EOD_TOKEN_ID);
+                    return Ok(());
+                }
+            };
+            generated.borrow_mut().push(token_id);
+
+            let mut parser = parser.borrow_mut();
+            let events = parser.push(&text);
+            let phase = if parser.in_command() {
+                Phase::Command
+            } else {
+                Phase::Explanation
+            };
+            _ = response_sender.send(InferenceResult::Token {
+                text,
+                token_id,
+                phase,
+            });
+            for event in events {
+                _ = response_sender.send(InferenceResult::Part(event));
+            }
+            let complete = if parser.in_command() {
+                parser.parts().len().saturating_sub(1)
+            } else {
+                parser.parts().len()
+            };
+            checks
+                .borrow_mut()
+                .check(&parser.parts()[..complete], response_sender);
+
+            Ok(())
         },
-        prompt_prefix,
-        |_| Ok(()),
     );
 
-    if res.err().is_some() {
-        eprintln!("Could not generate prompt snapshot");
-        std::process::exit(1);
+    let mut parser = parser.borrow_mut();
+    for event in parser.finish() {
+        _ = response_sender.send(InferenceResult::Part(event));
     }
+    checks.borrow_mut().check(parser.parts(), response_sender);
 
-    // Create parent directories if they don't exist
-    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let generated = generated.into_inner();
+    let answer = |tokens| {
+        Some(Answer {
+            tokens,
+            problems: checks.into_inner().problems,
+        })
+    };
+    match res {
+        Ok(stats) => {
+            log::info!("Inference completed successfully");
 
-    unsafe {
-        let snapshot_ref = session.get_snapshot();
-        match snapshot_ref.write_to_disk(&path) {
-            Ok(_) => {
-                log::info!(
-                    "Successfully written prompt cache to {}",
-                    path.to_string_lossy()
-                );
-            }
-            Err(err) => {
-                eprintln!("Could not restore prompt. Error: {err}");
-                std::process::exit(1);
-            }
+            _ = response_sender.send(InferenceResult::Usage(Usage {
+                prompt_tokens: stats.prompt_tokens,
+                completion_tokens: stats.predict_tokens - stats.prompt_tokens,
+                prompt_ms: stats.feed_prompt_duration.as_millis() as u64,
+                completion_ms: (stats.predict_duration - stats.feed_prompt_duration).as_millis()
+                    as u64,
+            }));
+            answer(generated)
+        }
+        Err(InferenceError::ContextFull) if !generated.is_empty() => {
+            log::warn!("Context window full, stopping inference.");
+
+            _ = response_sender.send(InferenceResult::Truncated);
+            answer(generated)
+        }
+        Err(InferenceError::ContextFull) => {
+            log::warn!("Context is not large enough to fit the prompt.");
+
+            _ = response_sender.send(InferenceResult::Error {
+                code: ErrorCode::ContextFull,
+                message: "Context is not large enough to fit the prompt.".to_string(),
+            });
+            None
+        }
+        Err(wiz_rs::InferenceError::TokenizationFailed) => {
+            tokenization_failed(response_sender);
+            None
+        }
+
+        Err(wiz_rs::InferenceError::UserCallback(_)) => {
+            log::info!("Inference cancelled");
+
+            _ = response_sender.send(InferenceResult::Error {
+                code: ErrorCode::ShuttingDown,
+                message: lifecycle::SHUTDOWN_MESSAGE.to_string(),
+            });
+            None
         }
     }
-    Ok(InferenceSnapshot::load_from_disk(path).map_err(|err| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Could not load prompt snapshot: {err}"),
-        )
-    })?)
 }
 
-#[derive(Default, Clone, Debug, PartialEq)]
-pub struct CustomTokenBias(Rc<RefCell<String>>);
+/// Checks the command parts of an answer once they are complete: how risky
+/// they are and, unless disabled, whether they work on this machine.
+struct CommandChecks<'a> {
+    cwd: Option<&'a Path>,
+    validator: Option<&'a CommandValidator>,
+    /// How many parts were looked at.
+    checked: usize,
+    /// Feedback on the commands that failed validation.
+    problems: Vec<String>,
+}
 
-impl CustomTokenBias {
-    pub fn new(bias: Rc<RefCell<String>>) -> Self {
-        Self(bias)
+impl<'a> CommandChecks<'a> {
+    fn new(cwd: Option<&'a Path>, validator: Option<&'a CommandValidator>) -> Self {
+        Self {
+            cwd,
+            validator,
+            checked: 0,
+            problems: vec![],
+        }
     }
-}
 
-impl TokenBias for CustomTokenBias {
-    fn get(&self, tid: u32) -> Option<f32> {
-        if tid != EOD_TOKEN_ID {
-            None
-        } else {
-            // If less than 2 newlines, prevent eod token
-            let text = self.0.borrow();
-            let code_end_index = text.find("```\n");
-
-            // Need at least another token after the code block
-            if code_end_index.is_none() || code_end_index.unwrap() + 4 >= text.len() {
-                Some(-1.0)
-            } else {
-                None
+    /// Checks the commands among the complete `parts` that were not checked
+    /// yet.
+    fn check(&mut self, parts: &[ResponsePart], response_sender: &flume::Sender<InferenceResult>) {
+        for (index, part) in parts.iter().enumerate().skip(self.checked) {
+            if part.kind != PartKind::Command {
+                continue;
+            }
+            let risk = wiz_rs::assess_risk(&part.text, self.cwd);
+            if risk.level() >= RiskLevel::Medium {
+                log::warn!("Answer has {} risk: {}", risk.level(), part.text);
+            }
+            _ = response_sender.send(InferenceResult::Risk { index, risk });
+
+            if let Some(validator) = self.validator {
+                let validation = validator.validate(&part.text, self.cwd);
+                if !validation.is_valid() {
+                    log::info!("Answer failed validation: {}", part.text);
+                    self.problems
+                        .push(wiz_rs::validation_feedback(&part.text, &validation));
+                }
+                _ = response_sender.send(InferenceResult::Validation { index, validation });
             }
         }
+        self.checked = self.checked.max(parts.len());
     }
 }
 
-fn inference_worker(
-    rx: flume::Receiver<InferenceRequest>,
-    mut model: wiz_rs::Model,
-    vocab: Tokenizer,
-    snapshot: InferenceSnapshot,
+fn answer_query(
+    loaded: &mut LoadedModel,
+    query: String,
+    template: Box<PromptTemplate>,
+    extras: PromptExtras,
+    response_sender: flume::Sender<InferenceResult>,
+    cancel: &AtomicBool,
 ) {
-    while let Ok(req) = rx.recv() {
-        let text: Rc<RefCell<String>> = Rc::new(RefCell::new("".to_string()));
-        let inference_params = InferenceParameters {
-            n_threads: 4 as i32,
-            n_batch: 8,
-            top_k: 1,
-            top_p: 1.0,
-            repeat_penalty: 0.00001,
-            temp: 1.0,
-            bias_tokens: Box::new(CustomTokenBias::new(text.clone())),
+    let Ok((system_len, prompt_tokens)) =
+        tokenize_query(&loaded.model, &loaded.vocab, &template, &query, &extras)
+    else {
+        tokenization_failed(&response_sender);
+        return;
+    };
+    let (mut session, cached) = start_prompt_session(loaded, &template, &prompt_tokens, system_len);
+
+    log::info!("Starting inference with query: {}", &query);
+
+    let Some(answer) = stream_answer(
+        loaded,
+        &mut session,
+        &prompt_tokens[cached..],
+        &template.response_prefix,
+        extras.cwd.as_deref(),
+        &response_sender,
+        cancel,
+    ) else {
+        return;
+    };
+
+    // Keep the state after the answer, repeated queries can start from it
+    loaded
+        .prefix_cache
+        .insert(&[prompt_tokens, answer.tokens].concat(), &session);
+
+    // Point out the problems of invalid commands and let the model try again,
+    // as a new candidate continuing the session
+    let mut problems = answer.problems;
+    for candidate in 1..=CLI_ARGS.validation_retries {
+        if problems.is_empty() {
+            break;
+        }
+        let feedback = template.follow_up(&problems.join("\n"));
+        let Ok(feedback_tokens) = loaded.model.tokenize(&loaded.vocab, &feedback, false) else {
+            tokenization_failed(&response_sender);
+            return;
         };
 
-        let mut rng = ThreadRng::default();
+        log::info!("Regenerating answer, attempt {candidate}");
+        _ = response_sender.send(InferenceResult::Candidate(candidate));
+        let Some(answer) = stream_answer(
+            loaded,
+            &mut session,
+            &feedback_tokens,
+            &template.response_prefix,
+            extras.cwd.as_deref(),
+            &response_sender,
+            cancel,
+        ) else {
+            return;
+        };
+        problems = answer.problems;
+    }
+}
 
-        let mut session = {
-            match model.session_from_snapshot(snapshot.clone()) {
-                Ok(session) => {
-                    log::info!("Restored cached memory from snapshot");
-                    session
+fn load_prompt_snapshot(
+    model: &wiz_rs::Model,
+    vocab: &Tokenizer,
+    prompt: &str,
+) -> Result<InferenceSnapshot, Box<dyn Error>> {
+    let cache = SnapshotCache::new(
+        get_wiz_home_dir()?.join("snapshots"),
+        CLI_ARGS.snapshot_cache_size * 1024 * 1024,
+        CLI_ARGS.snapshot_compression,
+    );
+    cache.get_or_create(prompt, model, vocab, session_params())
+}
+
+/// Everything the worker needs to answer requests. Dropped when the server
+/// unloads the model after being idle.
+struct LoadedModel {
+    model: wiz_rs::Model,
+    vocab: Tokenizer,
+    /// Snapshots after feeding the system prompts of the templates in use,
+    /// by system prompt.
+    snapshots: HashMap<String, InferenceSnapshot>,
+    prefix_cache: PrefixCache,
+    /// Checks generated commands, `None` with `--no-validation`.
+    validator: Option<CommandValidator>,
+}
+
+impl LoadedModel {
+    /// Makes sure `snapshots` contains the state after feeding `system`,
+    /// loading it from the snapshot cache or creating it on first use. Returns
+    /// false if there is no system prompt or the snapshot could not be
+    /// prepared.
+    fn prepare_system_snapshot(&mut self, system: &str) -> bool {
+        if system.is_empty() {
+            return false;
+        }
+        if !self.snapshots.contains_key(system) {
+            match load_prompt_snapshot(&self.model, &self.vocab, system) {
+                Ok(snapshot) => {
+                    self.snapshots.insert(system.to_string(), snapshot);
                 }
                 Err(err) => {
-                    log::error!("{err}");
-                    std::process::exit(1);
+                    log::error!("Could not prepare prompt snapshot: {err}");
+                    return false;
                 }
             }
-        };
-
-        let prompt = generate_prompt(&req.query);
-
-        log::info!("Starting inference with query: {}", &req.query);
-
-        let res = session.inference_with_prompt::<Infallible>(
-            &model,
-            &vocab,
-            &inference_params,
-            &prompt,
-            None,
-            &mut rng,
-            |t| {
-                {
-                    let mut text = text.borrow_mut();
-                    *text += &format!("{t}");
-                }
+        }
+        true
+    }
 
-                match t {
-                    OutputToken::Token(_, false) => {
-                        return Ok(());
-                    }
-                    _ => {}
-                }
+    /// Loads the model, reporting the progress in the server status. On
+    /// failure the status is `Failed` and the error is returned, so the
+    /// request that needed the model can be answered with it.
+    fn load(state: &Mutex<AppState>) -> Result<Self, String> {
+        state.lock().unwrap().status = ServerStatus::LoadingModel;
+        let (model, vocab) = match load_model() {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                let message = format!("Could not load model: {err}");
+                log::error!("{message}");
+                state.lock().unwrap().status = ServerStatus::Failed;
+                return Err(message);
+            }
+        };
+        {
+            let mut state = state.lock().unwrap();
+            state.model_info = Some(ModelInfo::new(&model));
+            state.status = ServerStatus::PreparingSnapshot;
+        }
+        let system = state.lock().unwrap().default_template().system.clone();
+        let mut loaded = Self {
+            model,
+            vocab,
+            snapshots: HashMap::new(),
+            prefix_cache: PrefixCache::new(CLI_ARGS.prefix_cache_size * 1024 * 1024),
+            validator: (!CLI_ARGS.no_validation)
+                .then(|| CommandValidator::new().run_help_of(&CLI_ARGS.validate_help_of)),
+        };
+        // Prepare the snapshot of the default template up front. Without it
+        // prompts are fed from the start, see `start_prompt_session`
+        loaded.prepare_system_snapshot(&system);
+        state.lock().unwrap().status = ServerStatus::Ready;
+        Ok(loaded)
+    }
+}
 
-                _ = req
-                    .response_sender
-                    .send(InferenceResult::Token(t.to_string()));
+fn inference_worker(rx: flume::Receiver<InferenceRequest>, state: Arc<Mutex<AppState>>) {
+    let mut loaded = if CLI_ARGS.lazy {
+        state.lock().unwrap().status = ServerStatus::Unloaded;
+        None
+    } else {
+        LoadedModel::load(&state).ok()
+    };
 
-                Ok(())
-            },
-        );
+    let cancel = state.lock().unwrap().cancel.clone();
 
-        match res {
-            Ok(_) => {
-                log::info!("Inference completed successfully");
+    while let Ok(req) = rx.recv() {
+        match req {
+            InferenceRequest::Unload => {
+                if loaded.take().is_some() {
+                    log::info!("Model unloaded");
+                }
+                state.lock().unwrap().status = ServerStatus::Unloaded;
+                continue;
             }
-            Err(InferenceError::ContextFull) => {
-                log::warn!("Context is not large enough to fit the prompt.");
-
-                req.response_sender
-                    .send(InferenceResult::Error(
-                        "Context is not large enough to fit the prompt.".to_string(),
-                    ))
-                    .unwrap();
+            InferenceRequest::Shutdown => break,
+            req if cancel.load(Ordering::Relaxed) => {
+                req.reject(ErrorCode::ShuttingDown, lifecycle::SHUTDOWN_MESSAGE);
+                continue;
             }
-            Err(wiz_rs::InferenceError::TokenizationFailed) => {
-                log::error!("Failed to tokenize initial prompt.");
-
-                req.response_sender
-                    .send(InferenceResult::Error(
-                        "Failed to tokenize initial prompt.".to_string(),
-                    ))
-                    .unwrap();
+            _ => {}
+        }
+
+        if loaded.is_none() {
+            match LoadedModel::load(&state) {
+                Ok(model) => loaded = Some(model),
+                Err(message) => {
+                    req.reject(ErrorCode::Unavailable, &message);
+                    continue;
+                }
             }
+        }
+        state.lock().unwrap().busy = true;
+        let loaded = loaded.as_mut().expect("loaded above");
 
-            Err(wiz_rs::InferenceError::UserCallback(_)) => unreachable!("cannot fail"),
+        match req {
+            InferenceRequest::Query {
+                query,
+                template,
+                extras,
+                response_sender,
+            } => answer_query(loaded, query, template, extras, response_sender, &cancel),
+            InferenceRequest::Completion(job) => openai::run_completion(
+                &loaded.model,
+                &loaded.vocab,
+                &mut loaded.prefix_cache,
+                job,
+                &cancel,
+            ),
+            InferenceRequest::Complete(job) => complete::run_complete(
+                &loaded.model,
+                &loaded.vocab,
+                &mut loaded.prefix_cache,
+                job,
+                &cancel,
+            ),
+            InferenceRequest::Turn {
+                conversation,
+                query,
+                extras,
+                response_sender,
+            } => conversations::answer_turn(
+                loaded,
+                &state,
+                conversation,
+                query,
+                *extras,
+                response_sender,
+                &cancel,
+            ),
+            InferenceRequest::Unload | InferenceRequest::Shutdown => {
+                unreachable!("handled above")
+            }
         }
+
+        let mut state = state.lock().unwrap();
+        state.busy = false;
+        state.last_activity = Instant::now();
     }
+
+    log::info!("Inference worker stopped");
+}
+
+/// Loads the prompt templates and picks the default one: the one given with
+/// `--template`, or the one named like the model file, or `wiz`.
+fn load_templates() -> Result<(PromptTemplates, String), Box<dyn Error>> {
+    let mut templates = PromptTemplates::default();
+    let dir = match &CLI_ARGS.template_dir {
+        Some(dir) => dir.clone(),
+        None => get_wiz_home_dir()?.join("templates"),
+    };
+    templates.load_dir(&dir)?;
+
+    let default_template = match &CLI_ARGS.template {
+        Some(name) => name.clone(),
+        None => templates
+            .name_for_model(&get_model_path()?, "wiz")
+            .to_string(),
+    };
+    templates.get(&default_template)?;
+    log::info!("Using prompt template `{default_template}` by default");
+
+    Ok((templates, default_template))
+}
+
+/// Indexes the examples for few-shot prompts, leaving out the ones kept for
+/// evaluation.
+fn load_example_index() -> Option<ExampleIndex> {
+    if CLI_ARGS.num_examples == 0 {
+        return None;
+    }
+    let path = match &CLI_ARGS.examples {
+        Some(path) => path.clone(),
+        None => get_wiz_home_dir().ok()?.join("examples.txt"),
+    };
+    let examples = match wiz_rs::load_examples(&path) {
+        Ok(examples) => examples,
+        Err(err) => {
+            // Only complain if the file was asked for explicitly
+            if CLI_ARGS.examples.is_some() {
+                log::error!("Could not read examples from {}: {err}", path.display());
+                std::process::exit(1);
+            }
+            return None;
+        }
+    };
+
+    let index = ExampleIndex::new(
+        examples
+            .into_iter()
+            .filter(|example| example.section.as_deref() != Some("eval"))
+            .collect(),
+    );
+    log::info!(
+        "Loaded {} few-shot examples from {}",
+        index.len(),
+        path.display()
+    );
+    (!index.is_empty()).then_some(index)
 }
 
 #[tokio::main]
@@ -314,32 +871,136 @@ async fn main() {
         .parse_default_env()
         .init();
 
-    let (req_tx, req_rx) = flume::unbounded::<InferenceRequest>();
-    let (model, vocab) = load_model().unwrap();
-    let snapshot = load_prompt_snapshot(PROMPT_PREFIX, &model, &vocab).unwrap();
-    let _inference_join_handle = spawn_blocking(move || {
-        inference_worker(req_rx, model, vocab, snapshot);
-    });
+    // Everything that can fail on startup is done before taking the pidfile,
+    // exiting skips its cleanup
+    let (templates, default_template) = match load_templates() {
+        Ok(templates) => templates,
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(1);
+        }
+    };
+    let examples = load_example_index();
+
+    let pid_file = match lifecycle::PidFile::acquire() {
+        Ok(pid_file) => pid_file,
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(1);
+        }
+    };
 
+    let (req_tx, req_rx) = flume::unbounded::<InferenceRequest>();
     let shared_state = Arc::new(Mutex::new(AppState {
         inference_tx: req_tx,
+        status: ServerStatus::LoadingModel,
+        started_at: Instant::now(),
+        model_info: None,
+        last_activity: Instant::now(),
+        busy: false,
+        cancel: Arc::new(AtomicBool::new(false)),
+        conversations: ConversationStore::new(CLI_ARGS.max_conversations),
+        templates,
+        default_template,
+        context: (!CLI_ARGS.no_context).then(|| ShellContext {
+            // The server's directory says nothing about where the user is
+            cwd: None,
+            ..ShellContext::gather()
+        }),
+        examples,
+        journal: journal::open(),
     }));
-    let addr = SocketAddr::from(([127, 0, 0, 1], 8085));
+
+    // Load the model in the background so that health checks are answered
+    // right away. Requests are queued until the worker is ready.
+    let worker_state = shared_state.clone();
+    let inference_join_handle = spawn_blocking(move || {
+        inference_worker(req_rx, worker_state);
+    });
+
+    let (shutdown_tx, mut shutdown_rx) = tokio::sync::watch::channel(false);
+    let shutdown_tx = Arc::new(shutdown_tx);
+    tokio::spawn(lifecycle::idle_watchdog(
+        shared_state.clone(),
+        shutdown_tx.clone(),
+    ));
+    tokio::spawn(lifecycle::signal_handler(shutdown_tx));
+    if let Some(journal) = shared_state.lock().unwrap().journal.clone() {
+        tokio::spawn(journal::enforce_retention(journal));
+    }
+    tokio::spawn(lifecycle::cancel_after_grace_period(
+        shared_state.clone(),
+        shutdown_rx.clone(),
+    ));
 
     let app = Router::new()
         .route("/api/completions", post(sse_handler))
-        .layer(Extension(shared_state));
+        .route("/api/v1/completions", post(stream_handler))
+        .route("/api/complete", post(complete::complete_handler))
+        .route("/api/v1/fix", post(fix_handler))
+        .route("/api/v1/explain", post(explain::explain_handler))
+        .route("/api/v1/outcome", post(journal::outcome_handler))
+        .route(
+            "/api/v1/conversations",
+            get(conversations::list_handler).post(conversations::create_handler),
+        )
+        .route(
+            "/api/v1/conversations/:id",
+            get(conversations::get_handler).delete(conversations::delete_handler),
+        )
+        .route(
+            "/api/v1/conversations/:id/turns",
+            post(conversations::turn_handler),
+        )
+        .route("/health", get(health::health_handler))
+        .route("/ready", get(health::ready_handler))
+        .route("/info", get(health::info_handler))
+        .route("/v1/models", get(openai::models_handler))
+        .route("/v1/completions", post(openai::completions_handler))
+        .route(
+            "/v1/chat/completions",
+            post(openai::chat_completions_handler),
+        )
+        .layer(Extension(shared_state.clone()));
 
-    log::info!("Listening on http://{}", addr);
+    let server = match lifecycle::inherited_listener() {
+        Some(listener) => {
+            log::info!("Listening on socket passed by the service manager");
+            axum::Server::from_tcp(listener).unwrap()
+        }
+        None => {
+            let addr = SocketAddr::from(([127, 0, 0, 1], CLI_ARGS.port));
+            log::info!("Listening on http://{}", addr);
+            axum::Server::bind(&addr)
+        }
+    };
 
     // run our application with hyper
-    axum::Server::bind(&addr)
+    server
         .serve(app.into_make_service())
+        .with_graceful_shutdown(async move {
+            _ = shutdown_rx.wait_for(|shutdown| *shutdown).await;
+        })
         .await
         .unwrap();
+
+    // No new requests are accepted at this point. Let the worker handle what
+    // is still queued (rejecting it if the grace period is over), then stop.
+    log::info!("Server stopped, waiting for the inference worker");
+    _ = shared_state
+        .lock()
+        .unwrap()
+        .inference_tx
+        .send(InferenceRequest::Shutdown);
+    _ = inference_join_handle.await;
+
+    drop(pid_file);
+    log::info!("Shutdown complete");
 }
 
 // serde
+/// Payload of the original `/api/completions` stream, kept for older
+/// clients. New clients should use `/api/v1/completions`, see `wiz_protocol`.
 #[derive(Serialize)]
 struct SSECompletionMessage {
     text: String,
@@ -349,20 +1010,44 @@ struct SSECompletionMessage {
 #[derive(Deserialize)]
 struct CompletionRequest {
     query: String,
+    /// Name of the prompt template to use instead of the default one.
+    #[serde(default)]
+    template: Option<String>,
+    /// The client's environment, e.g. its working directory. Fields that are
+    /// not set are taken from the server's own environment.
+    #[serde(default)]
+    context: Option<ShellContext>,
+}
+
+/// Resolves the template named in a request, answering with 400 if there is no
+/// such template.
+fn request_template(
+    state: &Mutex<AppState>,
+    name: Option<&str>,
+) -> Result<PromptTemplate, (StatusCode, String)> {
+    state
+        .lock()
+        .unwrap()
+        .template(name)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))
 }
 
 async fn sse_handler(
     Extension(state): Extension<Arc<Mutex<AppState>>>,
     Json(payload): Json<CompletionRequest>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let template = request_template(&state, payload.template.as_deref())?;
     let query = payload.query;
-
-    let mut saw_triple_backtick = false;
+    let extras = state.lock().unwrap().prompt_extras(&query, payload.context);
+    let mut recorder = QueryRecorder::new(&state, "query", &query, payload.template.as_deref());
 
     let stream = async_stream::stream! {
+        recorder.set_id(&format!("wiz-{:016x}", rand::thread_rng().gen::<u64>()));
         let (tx, rx) = flume::unbounded::<InferenceResult>();
-        match state.lock().unwrap().inference_tx.send(InferenceRequest {
+        match state.lock().unwrap().submit(InferenceRequest::Query {
             query: query.to_string(),
+            template: Box::new(template),
+            extras,
             response_sender: tx,
         }) {
             Ok(_) => {
@@ -374,38 +1059,57 @@ async fn sse_handler(
             }
         }
 
+        // Older clients only know one command followed by its explanation
+        let mut kinds = vec![];
         loop {
-            let res = rx.recv();
+            let res = rx.recv_async().await;
+            if let Ok(res) = &res {
+                recorder.observe(res);
+            }
 
             match res {
-                Ok(InferenceResult::Token(t)) => {
-                    if t == "```" {
-                        if saw_triple_backtick {
-                            break;
-                        } else {
-                            saw_triple_backtick = true;
-                        }
-                        continue;
+                Ok(InferenceResult::Part(ResponseEvent::PartStart { kind, .. })) => {
+                    let explained = kinds.iter().any(|k| *k != PartKind::Command);
+                    if kind == PartKind::Command && explained && kinds.contains(&PartKind::Command) {
+                        break;
                     }
-
+                    if explained {
+                        let msg = SSECompletionMessage {
+                            text: "\n\n".to_string(),
+                            r#type: "explanation".to_string(),
+                        };
+                        yield Ok(Event::default().data(serde_json::to_string(&msg).unwrap()));
+                    }
+                    kinds.push(kind);
+                }
+                Ok(InferenceResult::Part(ResponseEvent::Text { index, text })) => {
                     let msg = SSECompletionMessage {
-                        text: t,
-                        r#type: if saw_triple_backtick {
-                            "explanation".to_string()
-                        } else {
+                        text,
+                        r#type: if kinds[index] == PartKind::Command {
                             "command".to_string()
+                        } else {
+                            "explanation".to_string()
                         },
                     };
                     yield Ok(Event::default().data(serde_json::to_string(&msg).unwrap()));
                 }
-                Ok(InferenceResult::Error(e)) => {
+                Ok(InferenceResult::Error { message, .. }) => {
                     let msg = SSECompletionMessage {
-                        text: e,
+                        text: message,
                         r#type: "error".to_string(),
                     };
                     yield Ok(Event::default().data(serde_json::to_string(&msg).unwrap()));
                 }
-                Err(_) => {
+                Ok(
+                    InferenceResult::Token { .. }
+                    | InferenceResult::Risk { .. }
+                    | InferenceResult::Validation { .. }
+                    | InferenceResult::Usage(_)
+                    | InferenceResult::Truncated,
+                ) => {}
+                // Older clients cannot take an answer back, they keep the
+                // first one
+                Ok(InferenceResult::Candidate(_)) | Err(_) => {
                     break;
                 }
             }
@@ -413,5 +1117,199 @@ async fn sse_handler(
         }
     };
 
+    Ok(Sse::new(stream))
+}
+
+fn stream_event(event: StreamEvent) -> Result<Event, Infallible> {
+    Ok(Event::default()
+        .event(event.name())
+        .data(serde_json::to_string(&event).unwrap()))
+}
+
+/// Streams the answer to a query as versioned `wiz_protocol::StreamEvent`s.
+async fn stream_handler(
+    Extension(state): Extension<Arc<Mutex<AppState>>>,
+    Json(payload): Json<CompletionRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let template = request_template(&state, payload.template.as_deref())?;
+    let extras = state
+        .lock()
+        .unwrap()
+        .prompt_extras(&payload.query, payload.context);
+    let recorder = QueryRecorder::new(&state, "query", &payload.query, payload.template.as_deref());
+    Ok(protocol_stream(state, recorder, move |response_sender| {
+        InferenceRequest::Query {
+            query: payload.query,
+            template: Box::new(template),
+            extras,
+            response_sender,
+        }
+    }))
+}
+
+#[derive(Deserialize)]
+struct FixRequest {
+    #[serde(flatten)]
+    failed: FailedCommand,
+    /// Name of the prompt template to use instead of the default one.
+    #[serde(default)]
+    template: Option<String>,
+    /// The client's environment, see `CompletionRequest`.
+    #[serde(default)]
+    context: Option<ShellContext>,
+}
+
+/// Streams a corrected version of a failed command, followed by an
+/// explanation of what was wrong, as `wiz_protocol::StreamEvent`s.
+async fn fix_handler(
+    Extension(state): Extension<Arc<Mutex<AppState>>>,
+    Json(payload): Json<FixRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    if payload.failed.command.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "`command` is empty".to_string()));
+    }
+    let template = request_template(&state, payload.template.as_deref())?;
+    // Examples of similar commands rather than of similar queries
+    let extras = state
+        .lock()
+        .unwrap()
+        .prompt_extras(&payload.failed.command, payload.context);
+    let recorder = QueryRecorder::new(
+        &state,
+        "fix",
+        &payload.failed.command,
+        payload.template.as_deref(),
+    );
+    Ok(protocol_stream(state, recorder, move |response_sender| {
+        InferenceRequest::Query {
+            query: payload.failed.correction_query(),
+            template: Box::new(template),
+            extras,
+            response_sender,
+        }
+    }))
+}
+
+fn protocol_risk_level(level: RiskLevel) -> wiz_protocol::RiskLevel {
+    match level {
+        RiskLevel::None => wiz_protocol::RiskLevel::None,
+        RiskLevel::Low => wiz_protocol::RiskLevel::Low,
+        RiskLevel::Medium => wiz_protocol::RiskLevel::Medium,
+        RiskLevel::High => wiz_protocol::RiskLevel::High,
+        RiskLevel::Critical => wiz_protocol::RiskLevel::Critical,
+    }
+}
+
+/// Submits the request built by `request` and streams its results as
+/// `wiz_protocol::StreamEvent`s.
+fn protocol_stream(
+    state: Arc<Mutex<AppState>>,
+    mut recorder: QueryRecorder,
+    request: impl FnOnce(flume::Sender<InferenceResult>) -> InferenceRequest + Send + 'static,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = async_stream::stream! {
+        let id = format!("wiz-{:016x}", rand::thread_rng().gen::<u64>());
+        recorder.set_id(&id);
+        yield stream_event(StreamEvent::Start {
+            version: PROTOCOL_VERSION,
+            id,
+            model: openai::MODEL_ID.to_string(),
+        });
+
+        let (tx, rx) = flume::unbounded::<InferenceResult>();
+        if state.lock().unwrap().submit(request(tx)).is_err()
+        {
+            log::error!("Could not send inference request");
+            yield stream_event(StreamEvent::Error {
+                code: ErrorCode::Unavailable,
+                message: "Could not send inference request".to_string(),
+            });
+            yield stream_event(StreamEvent::Done { finish_reason: FinishReason::Error });
+            return;
+        }
+
+        yield stream_event(StreamEvent::Candidate { index: 0 });
+
+        let mut finish_reason = FinishReason::Stop;
+        while let Ok(res) = rx.recv_async().await {
+            recorder.observe(&res);
+            match res {
+                InferenceResult::Token { text, token_id, phase } => {
+                    yield stream_event(StreamEvent::Token {
+                        text,
+                        phase,
+                        token_id: Some(token_id),
+                    });
+                }
+                InferenceResult::Part(ResponseEvent::PartStart { index, kind, language }) => {
+                    yield stream_event(StreamEvent::PartStart {
+                        index,
+                        kind: match kind {
+                            PartKind::Command => wiz_protocol::PartKind::Command,
+                            PartKind::Explanation => wiz_protocol::PartKind::Explanation,
+                            PartKind::Warning => wiz_protocol::PartKind::Warning,
+                        },
+                        language,
+                    });
+                }
+                InferenceResult::Part(ResponseEvent::Text { index, text }) => {
+                    yield stream_event(StreamEvent::PartText { index, text });
+                }
+                InferenceResult::Risk { index, risk } => {
+                    yield stream_event(StreamEvent::Risk {
+                        index,
+                        level: protocol_risk_level(risk.level()),
+                        reasons: risk
+                            .reasons
+                            .into_iter()
+                            .map(|reason| wiz_protocol::RiskReason {
+                                level: protocol_risk_level(reason.level),
+                                message: reason.message,
+                                start: reason.span.start,
+                                end: reason.span.end,
+                            })
+                            .collect(),
+                    });
+                }
+                InferenceResult::Validation { index, validation } => {
+                    yield stream_event(StreamEvent::Validation {
+                        index,
+                        valid: validation.is_valid(),
+                        issues: validation
+                            .issues
+                            .into_iter()
+                            .map(|issue| wiz_protocol::ValidationIssue {
+                                kind: match issue.kind {
+                                    IssueKind::Syntax => wiz_protocol::IssueKind::Syntax,
+                                    IssueKind::MissingExecutable => {
+                                        wiz_protocol::IssueKind::MissingExecutable
+                                    }
+                                    IssueKind::UnknownFlag => wiz_protocol::IssueKind::UnknownFlag,
+                                },
+                                message: issue.message,
+                                start: issue.span.map(|span| span.start),
+                                end: issue.span.map(|span| span.end),
+                            })
+                            .collect(),
+                    });
+                }
+                InferenceResult::Candidate(index) => {
+                    yield stream_event(StreamEvent::Candidate { index });
+                }
+                InferenceResult::Usage(usage) => {
+                    yield stream_event(StreamEvent::Usage(usage));
+                }
+                InferenceResult::Truncated => finish_reason = FinishReason::Length,
+                InferenceResult::Error { code, message } => {
+                    finish_reason = FinishReason::Error;
+                    yield stream_event(StreamEvent::Error { code, message });
+                }
+            }
+        }
+
+        drop(recorder);
+        yield stream_event(StreamEvent::Done { finish_reason });
+    };
+
     Sse::new(stream)
 }
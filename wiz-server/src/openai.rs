@@ -0,0 +1,977 @@
+use axum::{
+    http::StatusCode,
+    response::{sse::Event, IntoResponse, Response, Sse},
+    Extension, Json,
+};
+use rand::{rngs::ThreadRng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokenizers::Tokenizer;
+use wiz_rs::{
+    ConstantTokenBias, InferenceError, InferenceParameters, InferenceSessionParameters,
+    OutputToken, PrefixCache, PromptTemplate, TokenId,
+};
+
+use crate::{request_template, AppState, InferenceRequest};
+
+/// The id under which the loaded model is advertised on `/v1/models`.
+pub const MODEL_ID: &str = "wiz";
+
+/// OpenAI allows at most this many alternatives per token.
+const MAX_LOGPROBS: usize = 5;
+
+/// At most this many choices are generated per prompt, and this many prompts
+/// per request. Every choice is a full generation on the single worker.
+const MAX_N: usize = 8;
+const MAX_PROMPTS: usize = 8;
+
+// ========
+// Requests
+// ========
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum StringOrArray {
+    String(String),
+    Array(Vec<String>),
+}
+
+impl StringOrArray {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            StringOrArray::String(s) => vec![s],
+            StringOrArray::Array(v) => v,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CompletionRequest {
+    #[serde(default)]
+    model: Option<String>,
+    prompt: StringOrArray,
+    #[serde(default)]
+    max_tokens: Option<usize>,
+    #[serde(default)]
+    temperature: Option<f32>,
+    #[serde(default)]
+    top_p: Option<f32>,
+    #[serde(default)]
+    n: Option<usize>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(default)]
+    stop: Option<StringOrArray>,
+    #[serde(default)]
+    logprobs: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChatCompletionRequest {
+    #[serde(default)]
+    model: Option<String>,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    max_tokens: Option<usize>,
+    #[serde(default)]
+    temperature: Option<f32>,
+    #[serde(default)]
+    top_p: Option<f32>,
+    #[serde(default)]
+    n: Option<usize>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(default)]
+    stop: Option<StringOrArray>,
+    #[serde(default)]
+    logprobs: bool,
+    #[serde(default)]
+    top_logprobs: Option<usize>,
+    /// Name of the prompt template the messages are rendered with, instead of
+    /// the server's default. Not part of the OpenAI API.
+    #[serde(default)]
+    template: Option<String>,
+}
+
+// =========
+// Responses
+// =========
+
+#[derive(Serialize)]
+struct ModelObject {
+    id: &'static str,
+    object: &'static str,
+    created: u64,
+    owned_by: &'static str,
+}
+
+#[derive(Serialize)]
+struct ModelList {
+    object: &'static str,
+    data: Vec<ModelObject>,
+}
+
+#[derive(Serialize, Default, Clone, Copy)]
+struct Usage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+}
+
+#[derive(Serialize, Default)]
+struct CompletionLogprobs {
+    tokens: Vec<String>,
+    token_logprobs: Vec<f32>,
+    top_logprobs: Vec<BTreeMap<String, f32>>,
+    text_offset: Vec<usize>,
+}
+
+#[derive(Serialize)]
+struct CompletionChoice {
+    index: usize,
+    text: String,
+    logprobs: Option<CompletionLogprobs>,
+    finish_reason: Option<FinishReason>,
+}
+
+#[derive(Serialize)]
+struct CompletionResponse {
+    id: String,
+    object: &'static str,
+    created: u64,
+    model: String,
+    choices: Vec<CompletionChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+}
+
+#[derive(Serialize)]
+struct ChatTopLogprob {
+    token: String,
+    logprob: f32,
+    bytes: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct ChatTokenLogprob {
+    token: String,
+    logprob: f32,
+    bytes: Vec<u8>,
+    top_logprobs: Vec<ChatTopLogprob>,
+}
+
+#[derive(Serialize, Default)]
+struct ChatLogprobs {
+    content: Vec<ChatTokenLogprob>,
+}
+
+#[derive(Serialize, Default)]
+struct ChatDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ChatChoice {
+    index: usize,
+    message: ChatMessage,
+    logprobs: Option<ChatLogprobs>,
+    finish_reason: Option<FinishReason>,
+}
+
+#[derive(Serialize)]
+struct ChatChunkChoice {
+    index: usize,
+    delta: ChatDelta,
+    logprobs: Option<ChatLogprobs>,
+    finish_reason: Option<FinishReason>,
+}
+
+#[derive(Serialize)]
+struct ChatCompletionResponse<C> {
+    id: String,
+    object: &'static str,
+    created: u64,
+    model: String,
+    choices: Vec<C>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    message: String,
+    r#type: &'static str,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+fn error_type(status: StatusCode) -> &'static str {
+    if status.is_client_error() {
+        "invalid_request_error"
+    } else {
+        "server_error"
+    }
+}
+
+fn error_response(status: StatusCode, message: impl Into<String>) -> Response {
+    (
+        status,
+        Json(ErrorResponse {
+            error: ErrorBody {
+                message: message.into(),
+                r#type: error_type(status),
+            },
+        }),
+    )
+        .into_response()
+}
+
+// ======================
+// Worker side generation
+// ======================
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Stop,
+    Length,
+}
+
+/// Log-probability of a generated token, with the most likely alternatives at
+/// that position.
+#[derive(Debug, Clone)]
+pub struct TokenLogprob {
+    token: String,
+    logprob: f32,
+    top: Vec<(String, f32)>,
+}
+
+pub struct GenerationSettings {
+    /// `None` generates until the end of text or until the context is full.
+    pub max_tokens: Option<usize>,
+    pub temperature: f32,
+    pub top_p: f32,
+    pub stop: Vec<String>,
+    /// Number of alternatives to report per token, if log-probabilities were
+    /// requested.
+    pub logprobs: Option<usize>,
+}
+
+impl GenerationSettings {
+    fn inference_parameters(&self) -> InferenceParameters {
+        // A temperature of zero means greedy decoding
+        let greedy = self.temperature <= 0.0;
+        InferenceParameters {
+            n_threads: 4,
+            n_batch: 8,
+            top_k: if greedy { 1 } else { usize::MAX },
+            top_p: self.top_p,
+            repeat_penalty: 1.0,
+            temp: if greedy { 1.0 } else { self.temperature },
+            bias_tokens: Box::new(ConstantTokenBias::default()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CompletionEvent {
+    Token {
+        index: usize,
+        text: String,
+        logprob: Option<TokenLogprob>,
+    },
+    Finished {
+        index: usize,
+        reason: FinishReason,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+    },
+    /// Generation failed, `status` tells whether the request or the server
+    /// is to blame.
+    Error { status: StatusCode, message: String },
+}
+
+/// A request for the inference worker to run `n` generations for each prompt.
+/// Choice `i` of prompt `p` is reported with index `p * n + i`.
+pub struct CompletionJob {
+    pub prompts: Vec<String>,
+    pub n: usize,
+    pub settings: GenerationSettings,
+    pub response_sender: flume::Sender<CompletionEvent>,
+}
+
+/// Holds back generated text that could be the beginning of a stop sequence
+/// until it is clear whether the sequence completes.
+struct StopMatcher<'a> {
+    stop: &'a [String],
+    pending: Vec<(String, Option<TokenLogprob>)>,
+}
+
+impl<'a> StopMatcher<'a> {
+    fn new(stop: &'a [String]) -> Self {
+        Self {
+            stop,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Adds a token and returns the tokens that are safe to emit, along with
+    /// whether a stop sequence was hit.
+    fn push(
+        &mut self,
+        text: String,
+        logprob: Option<TokenLogprob>,
+    ) -> (Vec<(String, Option<TokenLogprob>)>, bool) {
+        self.pending.push((text, logprob));
+        let pending_text: String = self.pending.iter().map(|(t, _)| t.as_str()).collect();
+
+        let stop_at = self
+            .stop
+            .iter()
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| pending_text.find(s.as_str()))
+            .min();
+
+        if let Some(stop_at) = stop_at {
+            let mut ready = Vec::new();
+            let mut offset = 0;
+            for (text, logprob) in self.pending.drain(..) {
+                if offset >= stop_at {
+                    break;
+                }
+                let end = (offset + text.len()).min(stop_at);
+                ready.push((text[..end - offset].to_string(), logprob));
+                offset += text.len();
+            }
+            return (ready, true);
+        }
+
+        let partial = self.stop.iter().any(|s| {
+            (1..s.len())
+                .filter(|&len| s.is_char_boundary(len))
+                .any(|len| pending_text.ends_with(&s[..len]))
+        });
+
+        if partial {
+            (Vec::new(), false)
+        } else {
+            (self.pending.drain(..).collect(), false)
+        }
+    }
+
+    fn flush(&mut self) -> Vec<(String, Option<TokenLogprob>)> {
+        self.pending.drain(..).collect()
+    }
+}
+
+fn token_logprob(
+    vocab: &Tokenizer,
+    logprobs: &[f32],
+    token_id: TokenId,
+    text: &str,
+    n_top: usize,
+) -> TokenLogprob {
+    let mut top: Vec<(usize, f32)> = logprobs.iter().copied().enumerate().collect();
+    let n_top = n_top.min(top.len());
+    top.select_nth_unstable_by(n_top.saturating_sub(1), |a, b| b.1.total_cmp(&a.1));
+    top.truncate(n_top);
+    top.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    TokenLogprob {
+        token: text.to_string(),
+        logprob: logprobs[token_id as usize],
+        top: top
+            .into_iter()
+            .map(|(tid, lp)| (vocab.decode(vec![tid as u32], true).unwrap_or_default(), lp))
+            .collect(),
+    }
+}
+
+/// Runs a `CompletionJob` on the inference worker. Generation stops early if
+/// the receiving side hangs up or the server is shutting down.
+pub fn run_completion(
+    model: &wiz_rs::Model,
+    vocab: &Tokenizer,
+    prefix_cache: &mut PrefixCache,
+    job: CompletionJob,
+    cancel: &AtomicBool,
+) {
+    let CompletionJob {
+        prompts,
+        n,
+        settings,
+        response_sender,
+    } = job;
+    let params = settings.inference_parameters();
+    let mut rng = ThreadRng::default();
+
+    for (p, prompt) in prompts.iter().enumerate() {
+        for i in 0..n {
+            let index = p * n + i;
+            let Ok(tokens) = model.tokenize(vocab, prompt, true) else {
+                _ = response_sender.send(CompletionEvent::Error {
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                    message: InferenceError::TokenizationFailed.to_string(),
+                });
+                return;
+            };
+            let (mut session, cached) = match prefix_cache.start_session(model, &tokens) {
+                Ok(Some(cached)) => cached,
+                result => {
+                    if let Err(err) = result {
+                        log::warn!("Could not restore a cached prefix: {err}");
+                    }
+                    let session = model.start_session(InferenceSessionParameters {
+                        memory_k_type: wiz_rs::ModelKVMemoryType::Float16,
+                        memory_v_type: wiz_rs::ModelKVMemoryType::Float16,
+                        ..Default::default()
+                    });
+                    (session, 0)
+                }
+            };
+
+            if let Err(err) =
+                session
+                    .feed_tokens::<Infallible>(model, vocab, &params, &tokens[cached..], |_| Ok(()))
+            {
+                let (status, message) = match err {
+                    InferenceError::ContextFull => (
+                        StatusCode::BAD_REQUEST,
+                        "Context is not large enough to fit the prompt.".to_string(),
+                    ),
+                    err => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+                };
+                log::warn!("{message}");
+                _ = response_sender.send(CompletionEvent::Error { status, message });
+                return;
+            }
+            let prompt_tokens = session.n_past();
+            prefix_cache.insert(&tokens, &session);
+
+            let mut matcher = StopMatcher::new(&settings.stop);
+            let mut completion_tokens = 0;
+            let reason = loop {
+                if cancel.load(Ordering::Relaxed) {
+                    _ = response_sender.send(CompletionEvent::Error {
+                        status: StatusCode::SERVICE_UNAVAILABLE,
+                        message: crate::lifecycle::SHUTDOWN_MESSAGE.to_string(),
+                    });
+                    return;
+                }
+                if settings
+                    .max_tokens
+                    .map(|max| completion_tokens >= max)
+                    .unwrap_or(false)
+                {
+                    break FinishReason::Length;
+                }
+
+                let logprobs = settings
+                    .logprobs
+                    .map(|n_top| (n_top, session.next_token_logprobs()));
+
+                let token = match session.infer_next_token(model, vocab, &params, &mut rng) {
+                    Ok(token) => token,
+                    Err(InferenceError::ContextFull) => break FinishReason::Length,
+                    Err(err) => {
+                        _ = response_sender.send(CompletionEvent::Error {
+                            status: StatusCode::INTERNAL_SERVER_ERROR,
+                            message: err.to_string(),
+                        });
+                        return;
+                    }
+                };
+                let (text, token_id) = match token {
+                    OutputToken::Token(text, _, token_id) => (text, token_id),
+                    OutputToken::EndOfText => break FinishReason::Stop,
+                };
+                completion_tokens += 1;
+
+                let logprob = logprobs.map(|(n_top, logprobs)| {
+                    token_logprob(vocab, &logprobs, token_id, &text, n_top)
+                });
+
+                let (ready, stopped) = matcher.push(text, logprob);
+                for (text, logprob) in ready {
+                    let event = CompletionEvent::Token {
+                        index,
+                        text,
+                        logprob,
+                    };
+                    if response_sender.send(event).is_err() {
+                        log::info!("Client disconnected, stopping generation");
+                        return;
+                    }
+                }
+                if stopped {
+                    break FinishReason::Stop;
+                }
+            };
+
+            for (text, logprob) in matcher.flush() {
+                _ = response_sender.send(CompletionEvent::Token {
+                    index,
+                    text,
+                    logprob,
+                });
+            }
+
+            let finished = CompletionEvent::Finished {
+                index,
+                reason,
+                prompt_tokens,
+                completion_tokens,
+            };
+            if response_sender.send(finished).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// ========
+// Handlers
+// ========
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn response_id(prefix: &str) -> String {
+    format!("{prefix}-{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+/// Renders chat messages with `template`. System messages replace the
+/// instructions of the template, the other messages become its turns. The
+/// last message has to be from the user.
+fn chat_prompt(
+    mut template: PromptTemplate,
+    messages: &[ChatMessage],
+) -> Result<String, (StatusCode, String)> {
+    let system: Vec<&str> = messages
+        .iter()
+        .filter(|message| message.role == "system")
+        .map(|message| message.content.as_str())
+        .collect();
+    if !system.is_empty() {
+        template.system = system.join("\n") + "\n\n";
+    }
+
+    // Consecutive messages of the same role are joined into one turn
+    let mut turns: Vec<(String, Option<String>)> = vec![];
+    for message in messages.iter().filter(|message| message.role != "system") {
+        match (message.role.as_str(), turns.last_mut()) {
+            ("assistant", Some((_, Some(answer)))) => *answer += &format!("\n{}", message.content),
+            ("assistant", Some((_, answer))) => *answer = Some(message.content.clone()),
+            ("assistant", None) => turns.push((String::new(), Some(message.content.clone()))),
+            (_, Some((input, None))) => *input += &format!("\n{}", message.content),
+            _ => turns.push((message.content.clone(), None)),
+        }
+    }
+    let Some((input, None)) = turns.pop() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "the last message must be from the user".to_string(),
+        ));
+    };
+    let history: Vec<(&str, &str)> = turns
+        .iter()
+        .map(|(input, answer)| (input.as_str(), answer.as_deref().unwrap_or_default()))
+        .collect();
+
+    Ok(template.render(&history, &input, None, &[]))
+}
+
+fn submit(
+    state: &Arc<Mutex<AppState>>,
+    prompts: Vec<String>,
+    n: usize,
+    settings: GenerationSettings,
+) -> Result<flume::Receiver<CompletionEvent>, (StatusCode, String)> {
+    if n == 0 || n > MAX_N {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("`n` must be between 1 and {MAX_N}"),
+        ));
+    }
+    if prompts.is_empty() || prompts.len() > MAX_PROMPTS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("between 1 and {MAX_PROMPTS} prompts are required"),
+        ));
+    }
+    if settings.temperature < 0.0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "`temperature` must not be negative".to_string(),
+        ));
+    }
+    if settings.top_p <= 0.0 || settings.top_p > 1.0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "`top_p` must be greater than 0 and at most 1".to_string(),
+        ));
+    }
+
+    let (tx, rx) = flume::unbounded();
+    let job = CompletionJob {
+        prompts,
+        n,
+        settings,
+        response_sender: tx,
+    };
+    match state
+        .lock()
+        .unwrap()
+        .submit(InferenceRequest::Completion(job))
+    {
+        Ok(_) => Ok(rx),
+        Err(_) => {
+            log::error!("Could not send inference request");
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "inference worker is not running".to_string(),
+            ))
+        }
+    }
+}
+
+fn json_event(value: &impl Serialize) -> Result<Event, Infallible> {
+    Ok(Event::default().data(serde_json::to_string(value).unwrap()))
+}
+
+pub async fn models_handler() -> Json<impl Serialize> {
+    Json(ModelList {
+        object: "list",
+        data: vec![ModelObject {
+            id: MODEL_ID,
+            object: "model",
+            created: 0,
+            owned_by: "wiz",
+        }],
+    })
+}
+
+pub async fn completions_handler(
+    Extension(state): Extension<Arc<Mutex<AppState>>>,
+    Json(payload): Json<CompletionRequest>,
+) -> Response {
+    let n = payload.n.unwrap_or(1);
+    let settings = GenerationSettings {
+        max_tokens: Some(payload.max_tokens.unwrap_or(16)),
+        temperature: payload.temperature.unwrap_or(1.0),
+        top_p: payload.top_p.unwrap_or(1.0),
+        stop: payload
+            .stop
+            .map(StringOrArray::into_vec)
+            .unwrap_or_default(),
+        logprobs: payload.logprobs.map(|n| n.min(MAX_LOGPROBS)),
+    };
+    let with_logprobs = settings.logprobs.is_some();
+    let prompts = payload.prompt.into_vec();
+    let n_prompts = prompts.len();
+    let rx = match submit(&state, prompts, n, settings) {
+        Ok(rx) => rx,
+        Err((status, message)) => return error_response(status, message),
+    };
+    let n_choices = n_prompts * n;
+
+    let id = response_id("cmpl");
+    let created = unix_time();
+    let model = payload.model.unwrap_or_else(|| MODEL_ID.to_string());
+
+    if payload.stream {
+        let stream = async_stream::stream! {
+            let chunk = |choice: CompletionChoice| CompletionResponse {
+                id: id.clone(),
+                object: "text_completion",
+                created,
+                model: model.clone(),
+                choices: vec![choice],
+                usage: None,
+            };
+
+            while let Ok(event) = rx.recv_async().await {
+                match event {
+                    CompletionEvent::Token { index, text, logprob } => {
+                        let logprobs = logprob.map(|lp| {
+                            let mut logprobs = CompletionLogprobs::default();
+                            logprobs.push(lp, 0);
+                            logprobs
+                        });
+                        yield json_event(&chunk(CompletionChoice {
+                            index,
+                            text,
+                            logprobs,
+                            finish_reason: None,
+                        }));
+                    }
+                    CompletionEvent::Finished { index, reason, .. } => {
+                        yield json_event(&chunk(CompletionChoice {
+                            index,
+                            text: String::new(),
+                            logprobs: None,
+                            finish_reason: Some(reason),
+                        }));
+                    }
+                    CompletionEvent::Error { status, message } => {
+                        yield json_event(&ErrorResponse {
+                            error: ErrorBody { message, r#type: error_type(status) },
+                        });
+                        break;
+                    }
+                }
+            }
+            yield Ok(Event::default().data("[DONE]"));
+        };
+        return Sse::new(stream).into_response();
+    }
+
+    let mut choices: Vec<CompletionChoice> = (0..n_choices)
+        .map(|index| CompletionChoice {
+            index,
+            text: String::new(),
+            logprobs: with_logprobs.then(CompletionLogprobs::default),
+            finish_reason: None,
+        })
+        .collect();
+    let mut usage = Usage::default();
+
+    while let Ok(event) = rx.recv_async().await {
+        match event {
+            CompletionEvent::Token {
+                index,
+                text,
+                logprob,
+            } => {
+                let choice = &mut choices[index];
+                if let (Some(logprobs), Some(logprob)) = (&mut choice.logprobs, logprob) {
+                    logprobs.push(logprob, choice.text.len());
+                }
+                choice.text += &text;
+            }
+            CompletionEvent::Finished {
+                index,
+                reason,
+                prompt_tokens,
+                completion_tokens,
+            } => {
+                choices[index].finish_reason = Some(reason);
+                // The prompt is only counted once, no matter how many choices
+                if index % n == 0 {
+                    usage.prompt_tokens += prompt_tokens;
+                }
+                usage.completion_tokens += completion_tokens;
+            }
+            CompletionEvent::Error { status, message } => {
+                return error_response(status, message);
+            }
+        }
+    }
+    usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+
+    Json(CompletionResponse {
+        id,
+        object: "text_completion",
+        created,
+        model,
+        choices,
+        usage: Some(usage),
+    })
+    .into_response()
+}
+
+impl CompletionLogprobs {
+    fn push(&mut self, logprob: TokenLogprob, offset: usize) {
+        self.tokens.push(logprob.token);
+        self.token_logprobs.push(logprob.logprob);
+        self.top_logprobs.push(logprob.top.into_iter().collect());
+        self.text_offset.push(offset);
+    }
+}
+
+impl From<TokenLogprob> for ChatTokenLogprob {
+    fn from(logprob: TokenLogprob) -> Self {
+        ChatTokenLogprob {
+            bytes: logprob.token.as_bytes().to_vec(),
+            token: logprob.token,
+            logprob: logprob.logprob,
+            top_logprobs: logprob
+                .top
+                .into_iter()
+                .map(|(token, logprob)| ChatTopLogprob {
+                    bytes: token.as_bytes().to_vec(),
+                    token,
+                    logprob,
+                })
+                .collect(),
+        }
+    }
+}
+
+pub async fn chat_completions_handler(
+    Extension(state): Extension<Arc<Mutex<AppState>>>,
+    Json(payload): Json<ChatCompletionRequest>,
+) -> Response {
+    let n = payload.n.unwrap_or(1);
+    let template = match request_template(&state, payload.template.as_deref()) {
+        Ok(template) => template,
+        Err((status, message)) => return error_response(status, message),
+    };
+    let mut stop: Vec<String> = payload
+        .stop
+        .map(StringOrArray::into_vec)
+        .unwrap_or_default();
+    // Models trained on a template tend to go on with the next turn of the
+    // user, the answer is complete when they do
+    stop.extend(template.turn_start().map(str::to_string));
+    let settings = GenerationSettings {
+        max_tokens: payload.max_tokens,
+        temperature: payload.temperature.unwrap_or(1.0),
+        top_p: payload.top_p.unwrap_or(1.0),
+        stop,
+        logprobs: payload
+            .logprobs
+            .then(|| payload.top_logprobs.unwrap_or(0).min(MAX_LOGPROBS)),
+    };
+    let with_logprobs = settings.logprobs.is_some();
+    let prompt = match chat_prompt(template, &payload.messages) {
+        Ok(prompt) => prompt,
+        Err((status, message)) => return error_response(status, message),
+    };
+    let rx = match submit(&state, vec![prompt], n, settings) {
+        Ok(rx) => rx,
+        Err((status, message)) => return error_response(status, message),
+    };
+
+    let id = response_id("chatcmpl");
+    let created = unix_time();
+    let model = payload.model.unwrap_or_else(|| MODEL_ID.to_string());
+
+    if payload.stream {
+        let stream = async_stream::stream! {
+            let chunk = |choice: ChatChunkChoice| ChatCompletionResponse {
+                id: id.clone(),
+                object: "chat.completion.chunk",
+                created,
+                model: model.clone(),
+                choices: vec![choice],
+                usage: None,
+            };
+
+            let mut started = vec![false; n];
+            while let Ok(event) = rx.recv_async().await {
+                match event {
+                    CompletionEvent::Token { index, text, logprob } => {
+                        let role = if started[index] {
+                            None
+                        } else {
+                            started[index] = true;
+                            Some("assistant")
+                        };
+                        yield json_event(&chunk(ChatChunkChoice {
+                            index,
+                            delta: ChatDelta { role, content: Some(text) },
+                            logprobs: logprob.map(|lp| ChatLogprobs { content: vec![lp.into()] }),
+                            finish_reason: None,
+                        }));
+                    }
+                    CompletionEvent::Finished { index, reason, .. } => {
+                        yield json_event(&chunk(ChatChunkChoice {
+                            index,
+                            delta: ChatDelta::default(),
+                            logprobs: None,
+                            finish_reason: Some(reason),
+                        }));
+                    }
+                    CompletionEvent::Error { status, message } => {
+                        yield json_event(&ErrorResponse {
+                            error: ErrorBody { message, r#type: error_type(status) },
+                        });
+                        break;
+                    }
+                }
+            }
+            yield Ok(Event::default().data("[DONE]"));
+        };
+        return Sse::new(stream).into_response();
+    }
+
+    let mut choices: Vec<ChatChoice> = (0..n)
+        .map(|index| ChatChoice {
+            index,
+            message: ChatMessage {
+                role: "assistant".to_string(),
+                content: String::new(),
+            },
+            logprobs: with_logprobs.then(ChatLogprobs::default),
+            finish_reason: None,
+        })
+        .collect();
+    let mut usage = Usage::default();
+
+    while let Ok(event) = rx.recv_async().await {
+        match event {
+            CompletionEvent::Token {
+                index,
+                text,
+                logprob,
+            } => {
+                let choice = &mut choices[index];
+                if let (Some(logprobs), Some(logprob)) = (&mut choice.logprobs, logprob) {
+                    logprobs.content.push(logprob.into());
+                }
+                choice.message.content += &text;
+            }
+            CompletionEvent::Finished {
+                index,
+                reason,
+                prompt_tokens,
+                completion_tokens,
+            } => {
+                choices[index].finish_reason = Some(reason);
+                if index == 0 {
+                    usage.prompt_tokens = prompt_tokens;
+                }
+                usage.completion_tokens += completion_tokens;
+            }
+            CompletionEvent::Error { status, message } => {
+                return error_response(status, message);
+            }
+        }
+    }
+    usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+
+    Json(ChatCompletionResponse {
+        id,
+        object: "chat.completion",
+        created,
+        model,
+        choices,
+        usage: Some(usage),
+    })
+    .into_response()
+}
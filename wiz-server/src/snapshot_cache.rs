@@ -0,0 +1,162 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    convert::Infallible,
+    error::Error,
+    fs::File,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokenizers::Tokenizer;
+use wiz_rs::{
+    InferenceParameters, InferenceSessionParameters, InferenceSnapshot, SnapshotCompression,
+};
+
+/// Snapshot files are named after the first `KEY_LENGTH` hex digits of the
+/// hash of their `CacheKey`.
+const KEY_LENGTH: usize = 16;
+const SNAPSHOT_EXTENSION: &str = "bin";
+
+/// Whether the file at `path` is a snapshot written by the cache, and not
+/// some other file in the directory.
+fn is_snapshot(path: &Path) -> bool {
+    let stem = path.file_stem().and_then(|stem| stem.to_str());
+    path.extension().and_then(|ext| ext.to_str()) == Some(SNAPSHOT_EXTENSION)
+        && stem.is_some_and(|stem| {
+            stem.len() == KEY_LENGTH && stem.bytes().all(|b| b.is_ascii_hexdigit())
+        })
+}
+
+/// Everything that has to match for a snapshot to be reusable.
+#[derive(Serialize)]
+struct CacheKey<'a> {
+    prompt: &'a str,
+    /// `Model::fingerprint`, the same the snapshots themselves are checked
+    /// against when restored.
+    model: [u8; 32],
+    session_params: &'a InferenceSessionParameters,
+}
+
+/// Prompt snapshots stored in `~/.wiz/snapshots`. Least recently used
+/// snapshots are removed once the directory grows beyond `max_bytes`.
+pub struct SnapshotCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    compression: SnapshotCompression,
+}
+
+impl SnapshotCache {
+    pub fn new(dir: PathBuf, max_bytes: u64, compression: SnapshotCompression) -> Self {
+        Self {
+            dir,
+            max_bytes,
+            compression,
+        }
+    }
+
+    fn path(
+        &self,
+        prompt: &str,
+        model: [u8; 32],
+        session_params: &InferenceSessionParameters,
+    ) -> PathBuf {
+        let key = CacheKey {
+            prompt,
+            model,
+            session_params,
+        };
+        let hash = Sha256::digest(serde_json::to_vec(&key).unwrap());
+        self.dir.join(format!(
+            "{}.{SNAPSHOT_EXTENSION}",
+            &format!("{:x}", hash)[..KEY_LENGTH]
+        ))
+    }
+
+    /// Returns the snapshot of the model state after feeding `prompt`, from
+    /// disk if possible. Otherwise the prompt is evaluated and the result
+    /// stored for next time.
+    pub fn get_or_create(
+        &self,
+        prompt: &str,
+        model: &wiz_rs::Model,
+        vocab: &Tokenizer,
+        session_params: InferenceSessionParameters,
+    ) -> Result<InferenceSnapshot, Box<dyn Error>> {
+        let path = self.path(prompt, model.fingerprint(), &session_params);
+
+        if path.exists() {
+            match InferenceSnapshot::load_from_disk(&path) {
+                Ok(snapshot) => {
+                    log::info!("Loaded prompt snapshot {}", path.to_string_lossy());
+                    // Mark as recently used for eviction
+                    _ = File::options()
+                        .write(true)
+                        .open(&path)
+                        .and_then(|file| file.set_modified(SystemTime::now()));
+                    return Ok(snapshot);
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Discarding unreadable prompt snapshot {}: {err}",
+                        path.to_string_lossy()
+                    );
+                    _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+
+        // If not, generate it
+        let mut session = model.start_session(session_params);
+        session.feed_prompt::<Infallible>(
+            model,
+            vocab,
+            &InferenceParameters {
+                ..Default::default()
+            },
+            prompt,
+            |_| Ok(()),
+        )?;
+
+        // Create parent directories if they don't exist
+        std::fs::create_dir_all(&self.dir)?;
+
+        session.write_snapshot_to_disk(&path, self.compression)?;
+        log::info!(
+            "Successfully written prompt cache to {}",
+            path.to_string_lossy()
+        );
+
+        if let Err(err) = self.evict() {
+            log::warn!("Could not clean up the snapshot cache: {err}");
+        }
+
+        Ok(session.snapshot())
+    }
+
+    /// Removes the least recently used snapshots until the cache fits into
+    /// `max_bytes`. The most recent snapshot is always kept, and files not
+    /// written by the cache are left alone.
+    fn evict(&self) -> std::io::Result<()> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() && is_snapshot(&entry.path()) {
+                entries.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+        // Newest first
+        entries.sort_by_key(|(modified, ..)| Reverse(*modified));
+
+        let mut total = 0;
+        for (i, (_, size, path)) in entries.into_iter().enumerate() {
+            total += size;
+            if i > 0 && total > self.max_bytes {
+                log::info!("Evicting prompt snapshot {}", path.to_string_lossy());
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
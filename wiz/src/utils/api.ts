@@ -85,5 +85,20 @@ export const generatePromptingStream = async (
 export const generateCompletion = async (
 	input: string,
 ): Promise<CompletionResult | undefined> => {
-	return undefined;
+	try {
+		// Without a cursor the server completes the end of the input
+		const res = await fetch('http://localhost:8085/api/complete', {
+			headers: {'Content-Type': 'application/json'},
+			method: 'POST',
+			body: JSON.stringify({input, n: 1}),
+		});
+		if (!res.ok) {
+			return undefined;
+		}
+		const json = await res.json();
+		const completion: string | undefined = json.completions[0]?.text;
+		return completion === undefined ? undefined : {completion};
+	} catch (e) {
+		return undefined;
+	}
 };